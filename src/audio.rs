@@ -1,20 +1,25 @@
 use std::{
     collections::HashMap,
     fs,
-    fs::OpenOptions,
-    io::{BufReader, Write, Cursor},
+    io::{BufReader, Cursor},
 };
 
-use actix_web::HttpResponse;
+use actix_web::{body::MessageBody, http::header, web, HttpResponse};
 use hound;
-use rand::prelude::*;
+use rand::{distributions::WeightedIndex, prelude::*};
 use rodio::{
     source::Buffered,
     Decoder,
+    OutputStream,
+    Sample,
+    Sink,
     Source,
 };
 
-use crate::structs::{ResponseMessage, RandomAudioQueueOptions};
+use crate::structs::{ResponseMessage, RandomAudioQueueOptions, Tone, Event, EventKind, EventPosition, RequestInfo};
+use crate::logging::{log_event, csv_field};
+use crate::clock::{monotonic_ns, wall_clock_ns};
+use crate::randomization::{resolve_seed, seeded_rng, sorted_audio_file_names, SessionRng};
 
 
 // Preload audio files to RAM for faster playback
@@ -60,12 +65,83 @@ pub fn preload_audio_files(audio_folder_path: &str) -> HashMap<String, Buffered<
 
 #[derive(Clone)]
 pub enum PlaylistTypes {
-    AudioFiles(String),
+    AudioFiles(String, f32), // audio file name and gain in dB, written as "{name}" or "{name} gain_{gain}dB" in the playlist file
     Pause(u32),
+    Tone(Tone), // written as "tone_{freq}Hz_{duration}ms_{amplitude}dB_@{sample_rate}Hz" in the playlist file
+    Block(String, Vec<PlaylistTypes>), // "[block {name}]" line, followed by the trials of that block
+    Include(String, u32, Vec<PlaylistTypes>), // included playlist file name, repeat count and its items, written as "include_{name}" or "include_{name} repeat_{count}"
+    Random(Vec<(f64, Vec<PlaylistTypes>)>), // "[random]" group of weighted options ("[option {weight}]" lines) until "[end random]", one option is played
+    Label(String), // "label_{name}", target of the jumps
+    Jump(String, Option<JumpCondition>), // "jump_{label}", or "jump_{label} if_{variable}={value}" (or !=) to jump on a playlist variable
+    Branch(String), // random option taken, only in the expanded steps (see expand_playlist), eg. "random_option_2/3"
+    ResponseWindow(u32, u32), // "respond_{start}-{end}ms" (or "respond_{end}ms") after a stimulus: response window in ms after its onset, see responses.rs
+}
+
+// Condition of a jump, on a playlist variable set over HTTP (/variables/set). An unset variable is ""
+#[derive(Clone)]
+pub struct JumpCondition {
+    pub variable: String,
+    pub value: String,
+    pub negate: bool, // "!=" instead of "="
+}
+
+impl JumpCondition {
+    pub fn is_met(&self, variables: &HashMap<String, String>) -> bool {
+        let value = variables.get(&self.variable).map_or("", |value| value.as_str());
+        (value == self.value) != self.negate
+    }
+}
+
+#[derive(Clone)]
+pub struct Playlist {
+    pub header: Vec<(String, String)>, // "# key: value" lines, in order (eg. seed, iti, block_rest, shuffle)
+    pub items: Vec<PlaylistTypes>,
+}
+
+impl Playlist {
+    pub fn header_value(&self, key: &str) -> Option<&str> {
+        self.header.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+// How the blocks and trials of a playlist are randomized at playback
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlockShuffle {
+    None,
+    Blocks, // shuffle the block order
+    Trials, // shuffle the trials within each block
+    Both,
+}
+
+impl BlockShuffle {
+    pub fn parse(shuffle: &str) -> Option<BlockShuffle> {
+        match shuffle {
+            "" | "none" => Some(BlockShuffle::None),
+            "blocks" => Some(BlockShuffle::Blocks),
+            "trials" => Some(BlockShuffle::Trials),
+            "both" => Some(BlockShuffle::Both),
+            _ => None,
+        }
+    }
+}
+
+// Header keys that change how a playlist is played, checked when the playlist is loaded
+const PLAYLIST_SETTINGS: [&str; 3] = ["iti", "block_rest", "shuffle"];
+
+// Limits of the includes: repeat count of one include_ line, and items of a playlist once its includes are expanded (nested repeats multiply)
+const MAX_INCLUDE_REPEAT: u32 = 1000;
+const MAX_EXPANDED_ITEMS: u64 = 1_000_000;
+
+fn validate_playlist_setting(key: &str, value: &str) -> bool {
+    match key {
+        "iti" | "block_rest" => value.parse::<u32>().is_ok(),
+        "shuffle" => BlockShuffle::parse(value).is_some(),
+        _ => true,
+    }
 }
 
 // Load the .txt files in the playlists folder and validate the audio file names (make sure they exist in the audio folder)
-pub fn load_and_validate_playlists(playlists_folder_path: &str, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> HashMap<String, Vec<PlaylistTypes>> {
+pub fn load_and_validate_playlists(playlists_folder_path: &str, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> HashMap<String, Playlist> {
     // if no /playlists folder is found, return an empty HashMap
     if !fs::metadata(playlists_folder_path).is_ok() {
         return HashMap::new();
@@ -73,8 +149,9 @@ pub fn load_and_validate_playlists(playlists_folder_path: &str, audio_files: &Ha
 
     println!("Loading playlists...");
 
-    // Playlist: Key is the name of the file.txt, Value is a Vec of audio file names (and breaks if applicable)
-    let mut playlists: HashMap<String, Vec<PlaylistTypes>> = HashMap::new();
+    // Playlist: Key is the name of the file.txt, Value is the header and a Vec of audio file names (and breaks if applicable)
+    // The includes are resolved once all the playlists are read
+    let mut parsed_playlists: HashMap<String, Playlist> = HashMap::new();
 
     let paths = fs::read_dir(playlists_folder_path).unwrap();
 
@@ -94,32 +171,202 @@ pub fn load_and_validate_playlists(playlists_folder_path: &str, audio_files: &Ha
         let mut contents = String::new();
         std::io::Read::read_to_string(&mut reader, &mut contents).unwrap();
 
-        // split the file contents by line
-        let lines = contents.split("\n");
+        // if the playlist is not empty, then add it to the playlists HashMap
+        if let Some(playlist) = parse_playlist(&file_name, &contents, audio_files) {
+            parsed_playlists.insert(file_name, playlist);
+        }
+    }
+
+    // Resolve the includes: if an included playlist is missing (or was ignored), or if playlists include each other, then ignore the playlist
+    let mut file_names: Vec<&String> = parsed_playlists.keys().collect();
+    file_names.sort();
+    let mut playlists: HashMap<String, Playlist> = HashMap::new();
+    for file_name in file_names {
+        let playlist = parsed_playlists.get(file_name).unwrap();
+        let mut include_chain = vec![file_name.clone()];
+        match resolve_includes(&playlist.items, &parsed_playlists, &mut include_chain).and_then(validate_expanded_item_count).and_then(validate_jumps) {
+            Ok(items) => {
+                playlists.insert(file_name.clone(), Playlist { header: playlist.header.clone(), items });
+            },
+            Err(e) => {
+                println!("\x1b[2m    \x1b[31mError: {}\x1b[0m", e);
+                println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+            }
+        }
+    }
+
+    println!("Loaded {} playlists\n", playlists.len());
+
+    playlists
+}
+
 
-        // validate the audio file names: if any of the audio file names are not found in the audio folder, or not start with "pause_", then ignore the playlist
-        let mut playlist: Vec<PlaylistTypes> = Vec::new();
-        let mut error_occurred = false; // Add this flag
+// Parse the lines of one playlist file and validate them (the audio files must exist in the audio folder)
+// Returns None (and prints why) if the playlist is empty or invalid. The includes are resolved by load_and_validate_playlists
+pub fn parse_playlist(file_name: &str, contents: &str, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Option<Playlist> {
+    // split the file contents by line
+    let lines = contents.split("\n");
 
-        for line in lines {
-            let line = line.trim();
+    // validate the audio file names: if any of the audio file names are not found in the audio folder, or not start with "pause_", then ignore the playlist
+    let mut header: Vec<(String, String)> = Vec::new();
+    let mut playlist: Vec<PlaylistTypes> = Vec::new();
+    let mut current_block: Option<(String, Vec<PlaylistTypes>)> = None; // the lines after "[block {name}]" go to this block
+    let mut current_random: Option<Vec<(f64, Vec<PlaylistTypes>)>> = None; // the lines between "[random]" and "[end random]" go to its last option
+    let mut error_occurred = false; // Add this flag
 
-            // ignore empty lines
-            if line == "" {
-                continue;
+    for line in lines {
+        let line = line.trim();
+
+        // ignore empty lines
+        if line == "" {
+            continue;
+        }
+
+        // header lines are "# key: value", any other line starting with "#" is a comment
+        if let Some(comment) = line.strip_prefix("#") {
+            if let Some((key, value)) = comment.split_once(':') {
+                let (key, value) = (key.trim(), value.trim());
+                if PLAYLIST_SETTINGS.contains(&key) && !validate_playlist_setting(key, value) {
+                    println!("\x1b[2m    \x1b[31mError: Invalid value \"{}\" for \"{}\"\x1b[0m", value, key);
+                    println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                    error_occurred = true;
+                    break;
+                }
+                header.push((key.to_string(), value.to_string()));
             }
+            continue;
+        }
+
+        // "[random]", "[option {weight}]" and "[end random]" delimit a group of weighted options, one of which is played
+        if line == "[random]" || line.starts_with("[option") || line == "[end random]" || (line.starts_with("[block") && current_random.is_some()) {
+            let error = match (line, current_random.as_mut()) {
+                ("[random]", None) => {
+                    current_random = Some(Vec::new());
+                    None
+                },
+                ("[random]", Some(_)) => Some("Nested [random] groups are not supported".to_string()),
+                ("[end random]", Some(options)) if options.is_empty() => Some("[random] group without [option] lines".to_string()),
+                ("[end random]", Some(_)) => {
+                    let item = PlaylistTypes::Random(current_random.take().unwrap());
+                    match current_block.as_mut() {
+                        Some((_, trials)) => trials.push(item),
+                        None => playlist.push(item),
+                    }
+                    None
+                },
+                (_, None) => Some(format!("\"{}\" outside of a [random] group", line)),
+                (_, Some(_)) if line.starts_with("[block") => Some("[block] line inside a [random] group".to_string()),
+                (_, Some(options)) => match parse_option_line(line) {
+                    Ok(weight) => {
+                        options.push((weight, Vec::new()));
+                        None
+                    },
+                    Err(e) => Some(e),
+                },
+            };
+            if let Some(e) = error {
+                println!("\x1b[2m    \x1b[31mError: {}\x1b[0m", e);
+                println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                error_occurred = true;
+                break;
+            }
+            continue;
+        }
+
+        // "[block {name}]" starts a new block, the previous block (if any) ends here
+        if line.starts_with("[block") {
+            let block_name = match line.strip_prefix("[block ").and_then(|rest| rest.strip_suffix("]")).map(|name| name.trim()).filter(|name| !name.is_empty()) {
+                Some(block_name) => block_name,
+                None => {
+                    println!("\x1b[2m    \x1b[31mError: Invalid block \"{}\" (expected [block {{name}}])\x1b[0m", line);
+                    println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                    error_occurred = true;
+                    break;
+                }
+            };
+            if let Some((name, trials)) = current_block.take() {
+                playlist.push(PlaylistTypes::Block(name, trials));
+            }
+            current_block = Some((block_name.to_string(), Vec::new()));
+            continue;
+        }
+
+        let item;
+
+        // an audio file named like one of the line types below (eg. tone_1000Hz.wav or label_start.wav) is played as an audio file, as in resolve_stimulus
+        let is_audio_file = matches!(parse_audio_file_line(line), Ok((audio_file_name, _)) if audio_files.contains_key(&audio_file_name));
 
-            // if the line starts with "pause_", then it's a break
-            if line.starts_with("pause_") {
-                let break_duration = line.replace("pause_", "").replace("ms", "").parse::<u32>().unwrap();
-                playlist.push(PlaylistTypes::Pause(break_duration));
-                continue;
+        // if the line starts with "pause_", then it's a break
+        if line.starts_with("pause_") {
+            let break_duration = line.replace("pause_", "").replace("ms", "").parse::<u32>().unwrap();
+            item = PlaylistTypes::Pause(break_duration);
+        }
+        // if the line starts with "tone_", then it's a pure sine tone generated on the fly
+        else if line.starts_with("tone_") && !is_audio_file {
+            match parse_tone_spec(line) {
+                Some(tone) => item = PlaylistTypes::Tone(tone),
+                None => {
+                    println!("\x1b[2m    \x1b[31mError: Invalid tone \"{}\" (expected tone_{{freq}}Hz_{{duration}}ms_{{amplitude}}dB_@{{sample_rate}}Hz)\x1b[0m", line);
+                    println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                    error_occurred = true;
+                    break;
+                }
             }
+        }
+        // if the line starts with "include_", then it's another playlist of the playlists folder, resolved below
+        else if line.starts_with("include_") && !is_audio_file {
+            match parse_include_line(line) {
+                Ok((included_file_name, repeat)) => item = PlaylistTypes::Include(included_file_name, repeat, Vec::new()),
+                Err(e) => {
+                    println!("\x1b[2m    \x1b[31mError: {}\x1b[0m", e);
+                    println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                    error_occurred = true;
+                    break;
+                }
+            }
+        }
+        // if the line starts with "respond_", then it's the response window of the stimulus before it
+        else if line.starts_with("respond_") && !is_audio_file {
+            match parse_response_window(line) {
+                Some((start, end)) => item = PlaylistTypes::ResponseWindow(start, end),
+                None => {
+                    println!("\x1b[2m    \x1b[31mError: Invalid response window \"{}\" (expected respond_{{start}}-{{end}}ms or respond_{{end}}ms)\x1b[0m", line);
+                    println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                    error_occurred = true;
+                    break;
+                }
+            }
+        }
+        // "label_{name}" marks a position of the playlist, "jump_{label}" goes to that position (if its condition is met)
+        else if let Some(label) = line.strip_prefix("label_").filter(|_| !is_audio_file) {
+            item = PlaylistTypes::Label(label.to_string());
+        }
+        else if line.starts_with("jump_") && !is_audio_file {
+            match parse_jump_line(line) {
+                Ok((label, condition)) => item = PlaylistTypes::Jump(label, condition),
+                Err(e) => {
+                    println!("\x1b[2m    \x1b[31mError: {}\x1b[0m", e);
+                    println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                    error_occurred = true;
+                    break;
+                }
+            }
+        }
+        // if the line is not a break, then it's an audio file name (with an optional gain)
+        else {
+            let (audio_file_name, gain) = match parse_audio_file_line(line) {
+                Ok(audio_file) => audio_file,
+                Err(e) => {
+                    println!("\x1b[2m    \x1b[31mError: {}\x1b[0m", e);
+                    println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                    error_occurred = true;
+                    break;
+                }
+            };
 
-            // if the line is not a break, then it's an audio file name
             // check if the audio file name exists in the audio folder
-            if !audio_files.contains_key(line) {
-                println!("\x1b[2m    \x1b[31mError: Audio file \"{}\" not found\x1b[0m", line);
+            if !audio_files.contains_key(&audio_file_name) {
+                println!("\x1b[2m    \x1b[31mError: Audio file \"{}\" not found\x1b[0m", audio_file_name);
                 println!("\x1b[2m    Please make sure the audio file exists in the \"audio\" folder and try again.\x1b[0m");
                 println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
                 error_occurred = true; // Set the flag to true
@@ -127,26 +374,493 @@ pub fn load_and_validate_playlists(playlists_folder_path: &str, audio_files: &Ha
             }
 
             // if the audio file name exists in the audio folder, then add it to the playlist
-            playlist.push(PlaylistTypes::AudioFiles(line.to_string()));
+            item = PlaylistTypes::AudioFiles(audio_file_name, gain);
+        }
+
+        match (current_random.as_mut().map(|options| options.last_mut()), current_block.as_mut()) {
+            (Some(Some((_, option_items))), _) => option_items.push(item),
+            (Some(None), _) => {
+                println!("\x1b[2m    \x1b[31mError: \"{}\" before the first [option] of a [random] group\x1b[0m", line);
+                println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+                error_occurred = true;
+                break;
+            },
+            (None, Some((_, trials))) => trials.push(item),
+            (None, None) => playlist.push(item),
+        }
+    }
+
+    if current_random.is_some() && !error_occurred {
+        println!("\x1b[2m    \x1b[31mError: [random] group without [end random]\x1b[0m");
+        println!("\x1b[2m    Ignoring playlist \"{}\"...\n\x1b[0m", file_name);
+        error_occurred = true;
+    }
+
+    if let Some((name, trials)) = current_block.take() {
+        playlist.push(PlaylistTypes::Block(name, trials));
+    }
+
+    // if the playlist is empty or an error occurred, then ignore it
+    if playlist.len() == 0 || error_occurred {
+        return None;
+    }
+
+    Some(Playlist { header, items: playlist })
+}
+
+
+// Replace the include items with the items of the included playlists, recursively
+// include_chain is the chain of playlists being resolved (starting with the playlist loaded), to detect the cycles
+fn resolve_includes(items: &[PlaylistTypes], parsed_playlists: &HashMap<String, Playlist>, include_chain: &mut Vec<String>) -> Result<Vec<PlaylistTypes>, String> {
+    let mut resolved: Vec<PlaylistTypes> = Vec::new();
+    for item in items {
+        match item {
+            PlaylistTypes::Include(included_file_name, repeat, _) => {
+                if include_chain.contains(included_file_name) {
+                    return Err(format!("Include cycle {} > {}", include_chain.join(" > "), included_file_name));
+                }
+                let included = match parsed_playlists.get(included_file_name) {
+                    Some(included) => included,
+                    None => return Err(format!("Included playlist \"{}\" not found (or invalid)", included_file_name)),
+                };
+                include_chain.push(included_file_name.clone());
+                let included_items = resolve_includes(&included.items, parsed_playlists, include_chain)?;
+                include_chain.pop();
+                resolved.push(PlaylistTypes::Include(included_file_name.clone(), *repeat, included_items));
+            },
+            PlaylistTypes::Block(name, trials) => resolved.push(PlaylistTypes::Block(name.clone(), resolve_includes(trials, parsed_playlists, include_chain)?)),
+            PlaylistTypes::Random(options) => {
+                let mut resolved_options = Vec::new();
+                for (weight, option_items) in options {
+                    resolved_options.push((*weight, resolve_includes(option_items, parsed_playlists, include_chain)?));
+                }
+                resolved.push(PlaylistTypes::Random(resolved_options));
+            },
+            _ => resolved.push(item.clone()),
+        }
+    }
+    Ok(resolved)
+}
+
+// The includes (and their repeats) must not expand the playlist to more than MAX_EXPANDED_ITEMS items
+fn validate_expanded_item_count(items: Vec<PlaylistTypes>) -> Result<Vec<PlaylistTypes>, String> {
+    let item_count = expanded_item_count(&items);
+    if item_count > MAX_EXPANDED_ITEMS {
+        return Err(format!("The includes expand the playlist to {} items (at most {})", item_count, MAX_EXPANDED_ITEMS));
+    }
+    Ok(items)
+}
+
+// Number of items once the includes are expanded, counting the longest option of the random groups
+fn expanded_item_count(items: &[PlaylistTypes]) -> u64 {
+    items.iter().fold(0u64, |count, item| {
+        let item_count = match item {
+            PlaylistTypes::Block(_, trials) => expanded_item_count(trials),
+            PlaylistTypes::Include(_, repeat, included_items) => expanded_item_count(included_items).saturating_mul(*repeat as u64),
+            PlaylistTypes::Random(options) => options.iter().map(|(_, option_items)| expanded_item_count(option_items)).max().unwrap_or(0),
+            _ => 1,
+        };
+        count.saturating_add(item_count)
+    })
+}
+
+// Every jump must go to a label of the playlist (included playlists and random options included)
+fn validate_jumps(items: Vec<PlaylistTypes>) -> Result<Vec<PlaylistTypes>, String> {
+    fn collect(items: &[PlaylistTypes], labels: &mut Vec<String>, jumps: &mut Vec<String>) {
+        for item in items {
+            match item {
+                PlaylistTypes::Label(label) => labels.push(label.clone()),
+                PlaylistTypes::Jump(label, _) => jumps.push(label.clone()),
+                PlaylistTypes::Block(_, trials) | PlaylistTypes::Include(_, _, trials) => collect(trials, labels, jumps),
+                PlaylistTypes::Random(options) => options.iter().for_each(|(_, option_items)| collect(option_items, labels, jumps)),
+                _ => {}
+            }
+        }
+    }
+    let (mut labels, mut jumps) = (Vec::new(), Vec::new());
+    collect(&items, &mut labels, &mut jumps);
+    match jumps.iter().find(|label| !labels.contains(label)) {
+        Some(label) => Err(format!("No label_{} for jump_{}", label, label)),
+        None => Ok(items),
+    }
+}
+
+// "respond_{start}-{end}ms" or "respond_{end}ms" (from the onset) line, eg. respond_100-1500ms
+pub fn parse_response_window(line: &str) -> Option<(u32, u32)> {
+    let window = line.strip_prefix("respond_")?.strip_suffix("ms")?;
+    let (start, end) = match window.split_once('-') {
+        Some((start, end)) => (start.parse::<u32>().ok()?, end.parse::<u32>().ok()?),
+        None => (0, window.parse::<u32>().ok()?),
+    };
+    if start < end { Some((start, end)) } else { None }
+}
+
+// "[option]" or "[option {weight}]" line of a [random] group, the weight defaults to 1
+fn parse_option_line(line: &str) -> Result<f64, String> {
+    let weight = line.strip_prefix("[option").and_then(|rest| rest.strip_suffix("]")).map(|weight| weight.trim());
+    match weight {
+        Some("") => Ok(1.0),
+        Some(weight) => weight.parse::<f64>().ok().filter(|weight| weight.is_finite() && *weight > 0.0).ok_or(format!("Invalid option weight \"{}\" (expected a positive number)", weight)),
+        None => Err(format!("Invalid option \"{}\" (expected [option {{weight}}])", line)),
+    }
+}
+
+// Jump line of a playlist: "jump_{label}", or "jump_{label} if_{variable}={value}" / "jump_{label} if_{variable}!={value}"
+pub fn parse_jump_line(line: &str) -> Result<(String, Option<JumpCondition>), String> {
+    let jump = line.strip_prefix("jump_").unwrap_or(line);
+    let (label, condition) = match jump.split_once(' ') {
+        Some((label, condition)) => (label.trim(), Some(condition.trim())),
+        None => (jump, None),
+    };
+    if label.is_empty() {
+        return Err(format!("Invalid jump \"{}\" (expected jump_{{label}})", line));
+    }
+    let condition = match condition {
+        None => None,
+        Some(condition) => {
+            let parsed = condition.strip_prefix("if_").and_then(|condition| {
+                match condition.split_once("!=") {
+                    Some((variable, value)) => Some((variable, value, true)),
+                    None => condition.split_once('=').map(|(variable, value)| (variable, value, false)),
+                }
+            });
+            match parsed {
+                Some((variable, value, negate)) if !variable.is_empty() => Some(JumpCondition { variable: variable.to_string(), value: value.to_string(), negate }),
+                _ => return Err(format!("Invalid jump condition \"{}\" (expected if_{{variable}}={{value}} or if_{{variable}}!={{value}})", condition)),
+            }
+        },
+    };
+    Ok((label.to_string(), condition))
+}
+
+pub fn jump_line(label: &str, condition: &Option<JumpCondition>) -> String {
+    match condition {
+        Some(condition) => format!("jump_{} if_{}{}{}", label, condition.variable, if condition.negate { "!=" } else { "=" }, condition.value),
+        None => format!("jump_{}", label),
+    }
+}
+
+// Include line of a playlist: "include_{name}", or "include_{name} repeat_{count}" (eg. "include_practice.txt repeat_2")
+// The ".txt" extension of the included playlist is optional
+pub fn parse_include_line(line: &str) -> Result<(String, u32), String> {
+    let include = line.strip_prefix("include_").unwrap_or(line);
+    let (included_file_name, repeat) = match include.rsplit_once(' ') {
+        Some((included_file_name, suffix)) if suffix.starts_with("repeat_") => {
+            match suffix["repeat_".len()..].parse::<u32>().ok().filter(|repeat| *repeat > 0 && *repeat <= MAX_INCLUDE_REPEAT) {
+                Some(repeat) => (included_file_name.trim(), repeat),
+                None => return Err(format!("Invalid repeat count \"{}\" (expected repeat_{{count}}, from 1 to {}, eg. repeat_3)", suffix, MAX_INCLUDE_REPEAT)),
+            }
+        },
+        _ => (include, 1),
+    };
+    if included_file_name.is_empty() || included_file_name.contains('/') || included_file_name.contains('\\') {
+        return Err(format!("Invalid included playlist \"{}\"", included_file_name));
+    }
+    let included_file_name = if included_file_name.ends_with(".txt") { included_file_name.to_string() } else { format!("{}.txt", included_file_name) };
+    Ok((included_file_name, repeat))
+}
+
+pub fn include_line(included_file_name: &str, repeat: u32) -> String {
+    if repeat == 1 {
+        format!("include_{}", included_file_name)
+    } else {
+        format!("include_{} repeat_{}", included_file_name, repeat)
+    }
+}
+
+// Audio file line of a playlist: "{name}", or "{name} gain_{gain}dB" (eg. "1000.wav gain_-6dB") to play the file louder or softer
+pub fn parse_audio_file_line(line: &str) -> Result<(String, f32), String> {
+    if let Some((audio_file_name, suffix)) = line.rsplit_once(' ') {
+        if let Some(gain) = suffix.strip_prefix("gain_") {
+            return match gain.strip_suffix("dB").and_then(|gain| gain.parse::<f32>().ok()).filter(|gain| gain.is_finite()) {
+                Some(gain) => Ok((audio_file_name.trim().to_string(), gain)),
+                None => Err(format!("Invalid gain \"{}\" (expected gain_{{gain}}dB, eg. gain_-6dB)", suffix)),
+            };
+        }
+    }
+    Ok((line.to_string(), 0.0))
+}
+
+pub fn audio_file_line(audio_file_name: &str, gain: f32) -> String {
+    if gain == 0.0 {
+        audio_file_name.to_string()
+    } else {
+        format!("{} gain_{}dB", audio_file_name, gain)
+    }
+}
+
+// Convert a gain in dB to a linear amplitude factor (for Source::amplify)
+pub fn db_to_linear(gain: f32) -> f32 {
+    10.0_f32.powf(gain / 20.0)
+}
+
+
+// Write playlist items back to playlist file lines (the inverse of load_and_validate_playlists, without the header)
+pub fn playlist_item_lines(items: &[PlaylistTypes]) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for item in items.iter() {
+        match item {
+            PlaylistTypes::AudioFiles(audio_file_name, gain) => lines.push(audio_file_line(audio_file_name, *gain)),
+            PlaylistTypes::Pause(pause_duration) => lines.push(format!("pause_{}ms", pause_duration)),
+            PlaylistTypes::Tone(tone) => lines.push(tone_spec(tone)),
+            PlaylistTypes::Block(name, trials) => {
+                lines.push(format!("[block {}]", name));
+                lines.extend(playlist_item_lines(trials));
+            },
+            PlaylistTypes::Include(included_file_name, repeat, _) => lines.push(include_line(included_file_name, *repeat)),
+            PlaylistTypes::Random(options) => {
+                lines.push("[random]".to_string());
+                for (weight, option_items) in options {
+                    lines.push(format!("[option {}]", weight));
+                    lines.extend(playlist_item_lines(option_items));
+                }
+                lines.push("[end random]".to_string());
+            },
+            PlaylistTypes::Label(label) => lines.push(format!("label_{}", label)),
+            PlaylistTypes::Jump(label, condition) => lines.push(jump_line(label, condition)),
+            PlaylistTypes::Branch(branch) => lines.push(branch.clone()),
+            PlaylistTypes::ResponseWindow(start, end) => lines.push(format!("respond_{}-{}ms", start, end)),
+        }
+    }
+    lines
+}
+
+
+// One step of a playlist playback, after the blocks have been expanded (and shuffled)
+pub struct PlaylistStep {
+    pub item: PlaylistTypes, // never a Block
+    pub block: Option<(usize, String)>, // block number (in playback order, starting at 1) and name
+    pub trial: Option<usize>, // trial number within the block (in playback order, starting at 1)
+    pub source: Option<String>, // included playlist the item comes from, eg. "session.txt>practice.txt" for nested includes
+}
+
+impl PlaylistStep {
+    // block, block_name, trial and source_playlist columns of the playlist logs (empty outside of blocks and includes)
+    pub fn log_columns(&self) -> String {
+        format!(
+            "{},{},{},{}",
+            self.block.as_ref().map_or(String::new(), |(number, _)| number.to_string()),
+            csv_field(self.block.as_ref().map_or("", |(_, name)| name.as_str())),
+            self.trial.map_or(String::new(), |trial| trial.to_string()),
+            csv_field(self.source.as_deref().unwrap_or(""))
+        )
+    }
+
+    // the same position for the JSON Lines event log, with the item number (index + 1)
+    pub fn position(&self, index: usize) -> EventPosition {
+        EventPosition {
+            item: index + 1,
+            block: self.block.as_ref().map(|(number, _)| *number),
+            block_name: self.block.as_ref().map(|(_, name)| name.clone()),
+            trial: self.trial,
+            source_playlist: self.source.clone(),
+        }
+    }
+}
+
+// A playlist ready to be played (or rendered): its settings, after the query parameters and the header, and its expanded steps
+pub struct PreparedPlaylist {
+    pub playlist: Playlist,
+    pub iti: u32,
+    pub block_rest: u32,
+    pub shuffle_name: String,
+    pub seed: u64,
+    pub steps: Vec<PlaylistStep>,
+}
+
+// A playlist item once the includes are inlined and the random options chosen, with the included playlist it comes from (None for the items of the playlist itself)
+type SourcedItem = (PlaylistTypes, Option<String>);
+
+enum InlinedItem {
+    Item(SourcedItem),
+    Block(String, Vec<SourcedItem>),
+}
+
+fn include_source(source: &Option<String>, included_file_name: &str) -> Option<String> {
+    Some(source.as_ref().map_or(included_file_name.to_string(), |source| format!("{}>{}", source, included_file_name)))
+}
+
+// Choose one option of a [random] group, with probabilities proportional to the weights
+// Returns the Branch item logged for the choice, and the items of the option
+fn choose_random_option<'a>(options: &'a [(f64, Vec<PlaylistTypes>)], rng: &mut SessionRng) -> (PlaylistTypes, &'a [PlaylistTypes]) {
+    let weights = WeightedIndex::new(options.iter().map(|(weight, _)| *weight)).unwrap(); // the weights were validated when loading the playlist
+    let option = weights.sample(rng);
+    (PlaylistTypes::Branch(format!("random_option_{}/{}", option + 1, options.len())), &options[option].1)
+}
+
+// Inline the includes (repeat times) and the random options of the top level of a playlist: the blocks of an included playlist are blocks of the playlist
+fn inline_includes(items: &[PlaylistTypes], source: &Option<String>, rng: &mut SessionRng, inlined: &mut Vec<InlinedItem>) {
+    for item in items {
+        match item {
+            PlaylistTypes::Include(included_file_name, repeat, included_items) => {
+                let included_source = include_source(source, included_file_name);
+                for _ in 0..*repeat {
+                    inline_includes(included_items, &included_source, rng, inlined);
+                }
+            },
+            PlaylistTypes::Block(name, trials) => {
+                let mut block_items: Vec<SourcedItem> = Vec::new();
+                inline_block_includes(trials, source, rng, &mut block_items);
+                inlined.push(InlinedItem::Block(name.clone(), block_items));
+            },
+            PlaylistTypes::Random(options) => {
+                let (branch, option_items) = choose_random_option(options, rng);
+                inlined.push(InlinedItem::Item((branch, source.clone())));
+                inline_includes(option_items, source, rng, inlined);
+            },
+            _ => inlined.push(InlinedItem::Item((item.clone(), source.clone()))),
+        }
+    }
+}
+
+// Inline the includes (repeat times) and the random options of a block: the blocks of an included playlist are trials of the enclosing block
+fn inline_block_includes(items: &[PlaylistTypes], source: &Option<String>, rng: &mut SessionRng, inlined: &mut Vec<SourcedItem>) {
+    for item in items {
+        match item {
+            PlaylistTypes::Include(included_file_name, repeat, included_items) => {
+                let included_source = include_source(source, included_file_name);
+                for _ in 0..*repeat {
+                    inline_block_includes(included_items, &included_source, rng, inlined);
+                }
+            },
+            PlaylistTypes::Block(_, trials) => inline_block_includes(trials, source, rng, inlined),
+            PlaylistTypes::Random(options) => {
+                let (branch, option_items) = choose_random_option(options, rng);
+                inlined.push((branch, source.clone()));
+                inline_block_includes(option_items, source, rng, inlined);
+            },
+            _ => inlined.push((item.clone(), source.clone())),
         }
+    }
+}
+
+// Expand a playlist into the list of steps to play:
+// - the includes are replaced by the items of the included playlists (repeat times)
+// - one option of each [random] group is chosen, and logged as a Branch step before its items
+// - the blocks are played in the file order, or shuffled (shuffle = blocks / both)
+// - the trials within a block are played in the file order, or shuffled (shuffle = trials / both)
+//   a trial is a stimulus line together with the pause, response window, label and jump lines that follow it (and the Branch step before it, if any)
+// - iti (ms) is inserted between two trials of the same block, block_rest (ms) between two blocks
+// Items outside of any block are played as they are, and keep their position
+pub fn expand_playlist(playlist: &Playlist, iti: u32, block_rest: u32, shuffle: BlockShuffle, rng: &mut SessionRng) -> Vec<PlaylistStep> {
+    let mut inlined: Vec<InlinedItem> = Vec::new();
+    inline_includes(&playlist.items, &None, rng, &mut inlined);
 
-        // if the playlist is empty or an error occurred, then ignore it
-        if playlist.len() == 0 || error_occurred {
+    // shuffle the blocks among the block positions, so that the items outside of blocks don't move
+    let mut blocks: Vec<(&String, &Vec<SourcedItem>)> = inlined.iter()
+        .filter_map(|item| match item {
+            InlinedItem::Block(name, trials) => Some((name, trials)),
+            _ => None,
+        })
+        .collect();
+    if shuffle == BlockShuffle::Blocks || shuffle == BlockShuffle::Both {
+        blocks.shuffle(rng);
+    }
+    let mut blocks = blocks.into_iter();
+
+    let mut steps: Vec<PlaylistStep> = Vec::new();
+    let mut block_number = 0;
+
+    for item in inlined.iter() {
+        if let InlinedItem::Item((item, source)) = item {
+            steps.push(PlaylistStep { item: item.clone(), block: None, trial: None, source: source.clone() });
             continue;
         }
 
-        // if the playlist is not empty, then add it to the playlists HashMap
-        playlists.insert(file_name, playlist);
+        let (block_name, block_items) = blocks.next().unwrap();
+        block_number += 1;
+        if block_number > 1 && block_rest > 0 {
+            steps.push(PlaylistStep { item: PlaylistTypes::Pause(block_rest), block: None, trial: None, source: None });
+        }
+
+        // group the pauses with the stimulus before them (pauses at the start of the block stay at the start)
+        // a taken random option starts a trial, and its first stimulus is part of that trial
+        let mut leading_pauses: Vec<SourcedItem> = Vec::new();
+        let mut trials: Vec<Vec<SourcedItem>> = Vec::new();
+        let mut branch_pending = false;
+        for block_item in block_items.iter() {
+            match (&block_item.0, trials.last_mut()) {
+                (PlaylistTypes::Branch(_), _) => {
+                    trials.push(vec![block_item.clone()]);
+                    branch_pending = true;
+                },
+                (PlaylistTypes::AudioFiles(_, _) | PlaylistTypes::Tone(_), Some(trial)) if branch_pending => {
+                    trial.push(block_item.clone());
+                    branch_pending = false;
+                },
+                (PlaylistTypes::AudioFiles(_, _) | PlaylistTypes::Tone(_), _) => trials.push(vec![block_item.clone()]),
+                (_, Some(trial)) => trial.push(block_item.clone()),
+                (_, None) => leading_pauses.push(block_item.clone()),
+            }
+        }
+        if shuffle == BlockShuffle::Trials || shuffle == BlockShuffle::Both {
+            trials.shuffle(rng);
+        }
+
+        let block = Some((block_number, block_name.clone()));
+        for (pause, source) in leading_pauses {
+            steps.push(PlaylistStep { item: pause, block: block.clone(), trial: None, source });
+        }
+        for (t, trial) in trials.into_iter().enumerate() {
+            if t > 0 && iti > 0 {
+                steps.push(PlaylistStep { item: PlaylistTypes::Pause(iti), block: block.clone(), trial: None, source: None });
+            }
+            // only the stimulus itself is numbered (or the Branch step of an empty option), its pauses are logged as part of the block
+            let numbered = trial.iter().position(|(trial_item, _)| matches!(trial_item, PlaylistTypes::AudioFiles(_, _) | PlaylistTypes::Tone(_))).unwrap_or(0);
+            for (k, (trial_item, source)) in trial.into_iter().enumerate() {
+                let trial_number = if k == numbered { Some(t + 1) } else { None };
+                steps.push(PlaylistStep { item: trial_item, block: block.clone(), trial: trial_number, source });
+            }
+        }
     }
 
-    println!("Loaded {} playlists\n", playlists.len());
+    steps
+}
 
-    playlists
+
+// Response window of the stimulus at steps[index]: the first "respond_" line after it, before the next stimulus
+pub fn response_window(steps: &[PlaylistStep], index: usize) -> Option<(u32, u32)> {
+    for step in steps.iter().skip(index + 1) {
+        match step.item {
+            PlaylistTypes::ResponseWindow(start, end) => return Some((start, end)),
+            PlaylistTypes::AudioFiles(_, _) | PlaylistTypes::Tone(_) => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+
+// Resolve a stimulus name used by the playlist generators: either an audio file from the audio folder, or a tone (see tone_spec)
+// Returns the playlist item and its duration in milliseconds, or None if the stimulus doesn't exist
+pub fn resolve_stimulus(name: &str, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Option<(PlaylistTypes, u128)> {
+    if let Some(source) = audio_files.get(name) {
+        let duration = source.total_duration().map_or(0, |duration| duration.as_millis());
+        return Some((PlaylistTypes::AudioFiles(name.to_string(), 0.0), duration));
+    }
+
+    let tone = parse_tone_spec(name)?;
+    let duration = tone.duration as u128;
+    Some((PlaylistTypes::Tone(tone), duration))
+}
+
+
+// Nominal duration of a playlist item in milliseconds (a block is the sum of its trials, an include the sum of its items times its repeat count, a [random] group its longest option)
+pub fn playlist_item_duration(item: &PlaylistTypes, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> u128 {
+    match item {
+        PlaylistTypes::AudioFiles(audio_file_name, _) => audio_files.get(audio_file_name).and_then(|source| source.total_duration()).map_or(0, |duration| duration.as_millis()),
+        PlaylistTypes::Pause(pause_duration) => *pause_duration as u128,
+        PlaylistTypes::Tone(tone) => tone.duration as u128,
+        PlaylistTypes::Block(_, trials) => trials.iter().map(|trial| playlist_item_duration(trial, audio_files)).sum(),
+        PlaylistTypes::Include(_, repeat, items) => *repeat as u128 * items.iter().map(|item| playlist_item_duration(item, audio_files)).sum::<u128>(),
+        PlaylistTypes::Random(options) => options.iter().map(|(_, items)| items.iter().map(|item| playlist_item_duration(item, audio_files)).sum::<u128>()).max().unwrap_or(0),
+        PlaylistTypes::Label(_) | PlaylistTypes::Jump(_, _) | PlaylistTypes::Branch(_) | PlaylistTypes::ResponseWindow(_, _) => 0,
+    }
 }
 
 
 // Correctly print and log the error when no audio output device is available
-pub fn handle_audio_error(audio_file_name: &str, request_time: &str, e: &str, global_log_file_name: &std::sync::Mutex<String>) -> HttpResponse {
+pub fn handle_audio_error(audio_file_name: &str, request_time: &str, e: &str, global_log_file_name: &std::sync::Mutex<String>, request: &RequestInfo) -> HttpResponse {
     println!("\x1b[2m    \x1b[31m{}\x1b[0m", e);
     println!("\x1b[2m    \x1b[31mError: Could not create OutputStream. Is there any audio output device available?\x1b[0m");
 
@@ -154,21 +868,9 @@ pub fn handle_audio_error(audio_file_name: &str, request_time: &str, e: &str, gl
 
     // update the log file with the error
     let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
-    let log_file_name = global_log_file_name.lock().unwrap();
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("{}.csv", *log_file_name))
-        .unwrap();
-    if let Err(e) = writeln!(file, "{},{},{},{}", time_start_nano, audio_file_name, "error", request_time) {
-        eprintln!("Couldn't write to file: {}", e);
-    } else {
-        println!("\x1b[2m    \x1b[31mAppended to log file (error): {}\x1b[0m", *log_file_name);
-    }
-
-    drop(log_file_name); // release the lock
-    drop(file); // release the lock
+    let log_file_name = global_log_file_name.lock().unwrap().clone();
+    let kind = EventKind::Error { stimulus: audio_file_name.to_string(), message: e.to_string() };
+    log_event(&log_file_name, &format!("{},{},{},{}", time_start_nano, csv_field(audio_file_name), "error", csv_field(request_time)), &Event::new(time_start_nano, kind, "error", request_time).for_request(request));
 
     HttpResponse::InternalServerError().json(ResponseMessage { message })
 }
@@ -196,6 +898,31 @@ pub fn generate_sine_tone(freq: &f32, duration: &u32, amplitude: &f32, sample_ra
     sine_tone
 }
 
+// Name of a tone, as used in the log files and in the playlist files
+// eg. tone_1000Hz_500ms_40dB_@96000Hz
+pub fn tone_spec(tone: &Tone) -> String {
+    format!("tone_{}Hz_{}ms_{}dB_@{}Hz", tone.freq, tone.duration, tone.amplitude, tone.sample_rate)
+}
+
+// Parse a tone name (see tone_spec) back to a Tone, returns None if the name is not a valid tone
+// The frequency and the duration must be positive, and the sample rate at least twice the frequency (Nyquist)
+pub fn parse_tone_spec(spec: &str) -> Option<Tone> {
+    let parts: Vec<&str> = spec.strip_prefix("tone_")?.split('_').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+
+    let freq = parts[0].strip_suffix("Hz")?.parse::<f32>().ok()?;
+    let duration = parts[1].strip_suffix("ms")?.parse::<u32>().ok()?;
+    let amplitude = parts[2].strip_suffix("dB")?.parse::<f32>().ok()?;
+    let sample_rate = parts[3].strip_prefix('@')?.strip_suffix("Hz")?.parse::<u32>().ok()?;
+    if !freq.is_finite() || freq <= 0.0 || duration == 0 || !amplitude.is_finite() || (sample_rate as f32) < 2.0 * freq {
+        return None;
+    }
+
+    Some(Tone { freq, duration, amplitude, sample_rate })
+}
+
 pub fn tone_to_source(freq: &f32, duration: &u32, amplitude: &f32, sample_rate: &u32) -> Buffered<Decoder<BufReader<Cursor<Vec<u8>>>>> {
     let sine_tone = generate_sine_tone(freq, duration, amplitude, sample_rate);
 
@@ -222,6 +949,28 @@ pub fn tone_to_source(freq: &f32, duration: &u32, amplitude: &f32, sample_rate:
     source
 }
 
+// Same as tone_to_source, but in stereo with the tone on one channel only (left: true = left ear, false = right ear), eg. for audiometry
+pub fn tone_to_ear_source(freq: &f32, duration: &u32, amplitude: &f32, sample_rate: &u32, left: bool) -> rodio::buffer::SamplesBuffer<f32> {
+    let sine_tone = generate_sine_tone(freq, duration, amplitude, sample_rate);
+
+    let mut samples = Vec::with_capacity(sine_tone.len() * 2);
+    for sample in sine_tone {
+        if left {
+            samples.extend([sample, 0.0]);
+        } else {
+            samples.extend([0.0, sample]);
+        }
+    }
+
+    rodio::buffer::SamplesBuffer::new(2, *sample_rate, samples)
+}
+
+// A click: a rectangular pulse of duration_us microseconds (at least one sample), at half of full scale, eg. for the timing self-test
+pub fn click_source(duration_us: u32, sample_rate: u32) -> rodio::buffer::SamplesBuffer<f32> {
+    let sample_count = ((duration_us as u64 * sample_rate as u64 / 1_000_000) as usize).max(1);
+    rodio::buffer::SamplesBuffer::new(1, sample_rate, vec![0.5; sample_count])
+}
+
 pub fn tone_to_wav_file(freq: &f32, duration: &u32, amplitude: &f32, sample_rate: &u32) -> Vec<u8> {
     let sine_tone = generate_sine_tone(freq, duration, amplitude, sample_rate);
 
@@ -290,14 +1039,14 @@ pub fn generate_random_audio_queue(audio_files: &HashMap<String, Buffered<Decode
         return Vec::new();
     }
 
-    let mut rng = rand::thread_rng();
+    let mut rng = seeded_rng(resolve_seed(options.seed));
     let mut queue: Vec<QueueSource> = Vec::new();
-    let files = audio_files.keys().collect::<Vec<&String>>();
+    let files = sorted_audio_file_names(audio_files);
 
     // randomly choose an audio file name from the list of audio files
     for _ in 0..file_limit { // generate the queue up to the file limit to avoid memory issues
         let random_audio_file_name = files.choose(&mut rng).unwrap();
-        let source = audio_files.get(*random_audio_file_name).unwrap().clone();
+        let source = audio_files.get(random_audio_file_name).unwrap().clone();
         queue.push(QueueSource::AudioFile(source));
     }
 
@@ -312,9 +1061,262 @@ pub fn generate_random_audio_queue(audio_files: &HashMap<String, Buffered<Decode
     queue
 }
 
+// How far ahead start_at can be: the playback holds a thread until then
+pub static MAX_START_AT_AHEAD_NS: u128 = 24 * 60 * 60 * 1_000_000_000;
+
+// Parse the ?start_at= query parameter to UNIX nanoseconds
+// Accepts UNIX nanoseconds, or ISO-8601 / RFC 3339 date times (without a timezone, the time is taken as UTC)
+// Returns None if start_at is empty (start right away), and an error if it is more than MAX_START_AT_AHEAD_NS ahead
+pub fn parse_start_at(start_at: &str) -> Result<Option<u128>, String> {
+    let start_at = start_at.trim();
+    if start_at.is_empty() {
+        return Ok(None);
+    }
+
+    let start_at_nano = match start_at.parse::<u128>() {
+        Ok(start_at_nano) => start_at_nano,
+        Err(_) => {
+            let date_time = match chrono::DateTime::parse_from_rfc3339(start_at) {
+                Ok(date_time) => date_time.with_timezone(&chrono::Utc),
+                Err(_) => match chrono::NaiveDateTime::parse_from_str(start_at, "%Y-%m-%dT%H:%M:%S%.f") {
+                    Ok(naive_date_time) => naive_date_time.and_utc(),
+                    Err(_) => return Err(format!("Invalid start_at \"{}\". Use UNIX nanoseconds or an ISO-8601 date time (eg. 2024-01-31T14:00:00.000Z)", start_at)),
+                },
+            };
+            match date_time.timestamp_nanos_opt() {
+                Some(start_at_nano) if start_at_nano >= 0 => start_at_nano as u128,
+                _ => return Err(format!("start_at \"{}\" is out of range", start_at)),
+            }
+        },
+    };
+
+    if start_at_nano > wall_clock_ns() + MAX_START_AT_AHEAD_NS {
+        return Err(format!("start_at \"{}\" is more than {} hours ahead", start_at, MAX_START_AT_AHEAD_NS / 3_600_000_000_000));
+    }
+    Ok(Some(start_at_nano))
+}
+
+// Run a playback with a start_at in a blocking thread (web::block) rather than on the actix worker, which would be held until start_at
+// The response is rebuilt from its status, content type and body. Without start_at, the playback runs on the worker like the other routes
+pub async fn play_off_worker(start_at: &str, playback: impl FnOnce() -> HttpResponse + Send + 'static) -> HttpResponse {
+    if start_at.trim().is_empty() {
+        return playback();
+    }
+
+    let result = web::block(move || {
+        let response = playback();
+        let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+        (response.status(), content_type, response.into_body().try_into_bytes().unwrap_or_default())
+    }).await;
+
+    match result {
+        Ok((status, content_type, body)) => {
+            let mut response = HttpResponse::build(status);
+            if let Some(content_type) = content_type {
+                response.insert_header((header::CONTENT_TYPE, content_type));
+            }
+            response.body(body)
+        },
+        Err(e) => {
+            let message = format!("The playback thread failed: {}", e);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            HttpResponse::InternalServerError().json(ResponseMessage { message })
+        }
+    }
+}
+
+// Block until start_at_nano (UNIX nanoseconds), and return the actual time.
+// Sleeps until shortly before the target, then spins for the last couple of milliseconds, as thread::sleep alone can oversleep by a few ms.
+// Returns right away if start_at_nano is in the past.
+pub fn wait_until(start_at_nano: u128) -> u128 {
+    const SPIN_NANO: u128 = 2_000_000;
+
+    let now_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    if start_at_nano > now_nano + SPIN_NANO {
+        std::thread::sleep(std::time::Duration::from_nanos((start_at_nano - now_nano - SPIN_NANO).min(u64::MAX as u128) as u64));
+    }
+
+    loop {
+        let now_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        if now_nano >= start_at_nano {
+            return now_nano;
+        }
+        std::hint::spin_loop();
+    }
+}
+
+// Log line text for a scheduled start: requested and actual start times, and the error (positive = late) in nanoseconds
+pub fn scheduled_start_text(requested_nano: u128, actual_nano: u128) -> String {
+    format!("Scheduled start (requested: {} | actual: {} | error: {} ns)", requested_nano, actual_nano, actual_nano as i128 - requested_nano as i128)
+}
+
+// Play a source right away on the default output device, and block until it has finished playing
+// on_start is called with the onset time (UNIX nanoseconds, read once the sink is started) and the monotonic time read with it
+// (see clock::monotonic_ns), eg. to record them for the responses
+// Returns the onset time and its monotonic time, or the output device error
+pub fn play_source_blocking<S>(source: S, on_start: impl FnOnce(u128, u128)) -> Result<(u128, u128), String>
+where
+    S: Source + Send + 'static,
+    S::Item: Sample + Send,
+{
+    // Linux with ALSA will panic here if there is no audio output device available, Windows returns an error
+    let (_stream, stream_handle) = match std::panic::catch_unwind(OutputStream::try_default) {
+        Err(_) => return Err("OutputStream NoDevice".to_string()),
+        Ok(Err(e)) => return Err(e.to_string()),
+        Ok(Ok(output_stream)) => output_stream,
+    };
+
+    let sink = Sink::try_new(&stream_handle).unwrap();
+    sink.pause(); // buffer the source in the paused sink, then start it
+    sink.append(source);
+
+    sink.play();
+    let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    let time_start_monotonic = monotonic_ns();
+    on_start(time_start_nano, time_start_monotonic);
+    sink.sleep_until_end(); // this thread is blocked until the source has finished playing
+    Ok((time_start_nano, time_start_monotonic))
+}
+
+// Milliseconds elapsed since start_nano (UNIX nanoseconds)
+pub fn elapsed_ms(start_nano: u128) -> u128 {
+    let now_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    now_nano.saturating_sub(start_nano) / 1_000_000
+}
+
 pub fn pause_sink_duration(sink: &rodio::Sink, duration: &u32) {
     // pause, sleep, then play
     sink.pause();
     std::thread::sleep(std::time::Duration::from_millis(*duration as u64));
     sink.play();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tone_spec_round_trip() {
+        let tone = parse_tone_spec("tone_1000Hz_500ms_-6.5dB_@48000Hz").unwrap();
+        assert_eq!((tone.freq, tone.duration, tone.amplitude, tone.sample_rate), (1000.0, 500, -6.5, 48000));
+        assert_eq!(tone_spec(&tone), "tone_1000Hz_500ms_-6.5dB_@48000Hz");
+    }
+
+    #[test]
+    fn invalid_tone_specs_are_rejected() {
+        for spec in [
+            "1000Hz_500ms_0dB_@48000Hz", // no tone_ prefix
+            "tone_1000Hz_500ms_0dB", // no sample rate
+            "tone_1000Hz_500ms_0dB_@48000Hz_extra",
+            "tone_1000_500ms_0dB_@48000Hz", // no unit
+            "tone_NaNHz_500ms_0dB_@48000Hz",
+            "tone_infHz_500ms_0dB_@48000Hz",
+            "tone_0Hz_500ms_0dB_@48000Hz",
+            "tone_-440Hz_500ms_0dB_@48000Hz",
+            "tone_1000Hz_0ms_0dB_@48000Hz",
+            "tone_1000Hz_-5ms_0dB_@48000Hz",
+            "tone_1000Hz_500ms_NaNdB_@48000Hz",
+            "tone_1000Hz_500ms_0dB_@0Hz",
+            "tone_30000Hz_500ms_0dB_@48000Hz", // above Nyquist
+        ] {
+            assert!(parse_tone_spec(spec).is_none(), "{}", spec);
+        }
+        assert!(parse_tone_spec("tone_24000Hz_500ms_0dB_@48000Hz").is_some()); // at Nyquist
+    }
+
+    #[test]
+    fn start_at_formats() {
+        assert_eq!(parse_start_at("").unwrap(), None);
+        assert_eq!(parse_start_at(" 1706709600000000000 ").unwrap(), Some(1706709600000000000));
+        assert_eq!(parse_start_at("2024-01-31T14:00:00Z").unwrap(), Some(1706709600000000000));
+        assert_eq!(parse_start_at("2024-01-31T15:00:00.5+01:00").unwrap(), Some(1706709600500000000));
+        assert_eq!(parse_start_at("2024-01-31T14:00:00.250").unwrap(), Some(1706709600250000000)); // no timezone: UTC
+    }
+
+    #[test]
+    fn invalid_start_at_is_rejected() {
+        assert!(parse_start_at("tomorrow").is_err());
+        assert!(parse_start_at("2024-01-31 14:00").is_err());
+        assert!(parse_start_at("1960-01-01T00:00:00Z").is_err()); // before the UNIX epoch
+        assert!(parse_start_at("2999-01-01T00:00:00Z").is_err()); // out of range
+        assert!(parse_start_at("2100-01-01T00:00:00Z").unwrap_err().contains("24 hours ahead"));
+        assert!(parse_start_at(&u128::MAX.to_string()).is_err());
+        assert!(parse_start_at(&(wall_clock_ns() + MAX_START_AT_AHEAD_NS - 60_000_000_000).to_string()).is_ok());
+    }
+
+    #[test]
+    fn include_lines() {
+        assert_eq!(parse_include_line("include_practice").unwrap(), ("practice.txt".to_string(), 1));
+        assert_eq!(parse_include_line("include_stimuli.txt repeat_3").unwrap(), ("stimuli.txt".to_string(), 3));
+        assert_eq!(parse_include_line("include_my list repeat_1000").unwrap(), ("my list.txt".to_string(), 1000));
+        assert_eq!(include_line("stimuli.txt", 3), "include_stimuli.txt repeat_3");
+
+        assert!(parse_include_line("include_stimuli.txt repeat_1001").unwrap_err().contains("from 1 to 1000"));
+        assert!(parse_include_line("include_stimuli.txt repeat_0").is_err());
+        assert!(parse_include_line("include_stimuli.txt repeat_x").is_err());
+        assert!(parse_include_line("include_../secret.txt").is_err());
+        assert!(parse_include_line("include_").is_err());
+    }
+
+    #[test]
+    fn expanded_item_counts() {
+        let stimuli = vec![PlaylistTypes::Pause(100); 10];
+        let include = |repeat: u32, items: Vec<PlaylistTypes>| PlaylistTypes::Include("stimuli.txt".to_string(), repeat, items);
+        let random = PlaylistTypes::Random(vec![(1.0, vec![PlaylistTypes::Pause(100)]), (1.0, stimuli.clone())]);
+        assert_eq!(expanded_item_count(&[PlaylistTypes::Pause(100), include(3, stimuli.clone()), PlaylistTypes::Block("main".to_string(), vec![random])]), 41);
+
+        // nested repeats multiply
+        let nested = vec![include(1000, vec![include(1000, stimuli.clone())])];
+        assert_eq!(expanded_item_count(&nested), 10_000_000);
+        assert!(validate_expanded_item_count(nested).is_err());
+        assert!(validate_expanded_item_count(vec![include(1000, vec![include(100, stimuli)])]).is_ok());
+    }
+
+    #[test]
+    fn jump_lines() {
+        let (label, condition) = parse_jump_line("jump_retry if_score!=pass").unwrap();
+        let condition = condition.unwrap();
+        assert_eq!((label.as_str(), condition.variable.as_str(), condition.value.as_str(), condition.negate), ("retry", "score", "pass", true));
+        assert_eq!(jump_line(&label, &Some(condition)), "jump_retry if_score!=pass");
+
+        let (label, condition) = parse_jump_line("jump_end if_done=").unwrap();
+        assert_eq!((label.as_str(), condition.as_ref().map(|condition| (condition.value.as_str(), condition.negate))), ("end", Some(("", false))));
+        assert!(parse_jump_line("jump_end").unwrap().1.is_none());
+
+        assert!(parse_jump_line("jump_").is_err());
+        assert!(parse_jump_line("jump_end when_done=1").is_err());
+        assert!(parse_jump_line("jump_end if_=1").is_err());
+        assert!(parse_jump_line("jump_end if_done").is_err());
+    }
+
+    #[test]
+    fn audio_files_come_before_the_line_types() {
+        let mut audio_files = HashMap::new();
+        for file_name in ["label_start.wav", "jump_start.wav", "include_start.wav", "respond_start.wav", "tone_start.wav"] {
+            let path = std::env::temp_dir().join(format!("rust_audio_server_{}_{}", std::process::id(), file_name));
+            fs::write(&path, tone_to_wav_file(&1000.0, &10, &0.5, &44100)).unwrap();
+            let source = Decoder::new(BufReader::new(std::fs::File::open(&path).unwrap())).unwrap().buffered();
+            fs::remove_file(&path).unwrap();
+            audio_files.insert(file_name.to_string(), source);
+        }
+
+        let playlist = parse_playlist("test.txt", "label_start.wav\njump_start.wav\ninclude_start.wav\nrespond_start.wav\ntone_start.wav\nlabel_start\njump_start", &audio_files).unwrap();
+        let file_count = playlist.items.iter().filter(|item| matches!(item, PlaylistTypes::AudioFiles(_, _))).count();
+        assert_eq!(file_count, 5);
+        assert!(matches!(&playlist.items[5], PlaylistTypes::Label(label) if label == "start"));
+        assert!(matches!(&playlist.items[6], PlaylistTypes::Jump(label, None) if label == "start"));
+    }
+
+    #[test]
+    fn response_windows() {
+        assert_eq!(parse_response_window("respond_100-1500ms"), Some((100, 1500)));
+        assert_eq!(parse_response_window("respond_1500ms"), Some((0, 1500)));
+
+        assert_eq!(parse_response_window("respond_1500-100ms"), None);
+        assert_eq!(parse_response_window("respond_0ms"), None);
+        assert_eq!(parse_response_window("respond_100-1500"), None);
+        assert_eq!(parse_response_window("respond_-1500ms"), None);
+        assert_eq!(parse_response_window("respond_1.5sms"), None);
+        assert_eq!(parse_response_window("reply_1500ms"), None);
+    }
+}
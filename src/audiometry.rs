@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    fs,
+};
+
+use crate::structs::{AudiometryOptions, AudiometrySummary, AudiogramThreshold, Tone, RequestInfo};
+use crate::audio::tone_spec;
+use crate::logging::{start_csv_log, log_row, csv_field, write_log_file};
+use crate::clock::monotonic_ns;
+use crate::session::register_log_file;
+
+
+// Pure-tone audiometry with the modified Hughson-Westlake procedure, for each ear and frequency:
+// start at start_level, go down 10 dB after each tone heard and up 5 dB after each tone not heard.
+// A presentation is ascending if the previous tone was not heard. The threshold is the lowest level heard on at least
+// 2 ascending presentations, and on at least half of them (2 of 2, 2 of 3, 3 of 4, ...).
+
+// Calibration gains are read from this file, one per line: frequency (Hz) | ear (left, right or both) | gain (dB)
+// The gain is the amplitude (dB re full scale) of the tone at 0 dB HL, measured with the headphones of the setup
+pub static CALIBRATION_FILE: &str = "./calibration.txt";
+
+// Gain used without calibration file: 0 dB HL is played at -100 dB re full scale (and 100 dB HL at full scale)
+const UNCALIBRATED_GAIN: f32 = -100.0;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Ear {
+    Left,
+    Right,
+}
+
+impl Ear {
+    pub fn parse(ear: &str) -> Option<Ear> {
+        match ear.trim().to_lowercase().as_str() {
+            "left" | "l" => Some(Ear::Left),
+            "right" | "r" => Some(Ear::Right),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Ear::Left => "left",
+            Ear::Right => "right",
+        }
+    }
+}
+
+// Calibration gain of each frequency, for both ears (None) or for one ear
+pub struct Calibration {
+    pub gains: Vec<(f32, Option<Ear>, f32)>,
+}
+
+impl Calibration {
+    // Gain of a frequency and ear. A gain for this ear takes precedence over a gain for both ears
+    pub fn gain(&self, frequency: f32, ear: Ear) -> Option<f32> {
+        let for_ear = self.gains.iter().find(|(freq, gain_ear, _)| *freq == frequency && *gain_ear == Some(ear));
+        let for_both = self.gains.iter().find(|(freq, gain_ear, _)| *freq == frequency && gain_ear.is_none());
+        for_ear.or(for_both).map(|(_, _, gain)| *gain)
+    }
+}
+
+// Load the calibration file, returns None if there is no calibration file
+pub fn load_calibration(path: &str) -> Result<Option<Calibration>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return Ok(None),
+    };
+
+    let mut gains = Vec::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('|').map(|field| field.trim()).collect();
+        let invalid = || format!("Invalid line {} in {}: \"{}\" (expected frequency | left, right or both | gain)", line_number + 1, path, line);
+        if fields.len() != 3 {
+            return Err(invalid());
+        }
+        let frequency = fields[0].trim_end_matches("Hz").parse::<f32>().map_err(|_| invalid())?;
+        let ear = match fields[1] {
+            "both" => None,
+            ear => Some(Ear::parse(ear).ok_or_else(invalid)?),
+        };
+        let gain = fields[2].trim_end_matches("dB").parse::<f32>().map_err(|_| invalid())?;
+        if !frequency.is_finite() || frequency <= 0.0 || !gain.is_finite() {
+            return Err(invalid());
+        }
+        gains.push((frequency, ear, gain));
+    }
+
+    Ok(Some(Calibration { gains }))
+}
+
+
+pub struct Audiometry {
+    pub name: String,
+    pub tests: Vec<(Ear, f32, f32)>, // ear, frequency and calibration gain, in test order
+    pub calibrated: bool,
+    pub duration: u32,
+    pub sample_rate: u32,
+    pub start_level: i32,
+    pub min_level: i32,
+    pub max_level: i32,
+    pub max_presentations: u32,
+    pub log_file: String,
+    pub results_file: String,
+
+    pub test: usize, // index of the current test in tests
+    pub level: i32, // level of the next presentation, in dB HL
+    pub presentations: u32, // for the current test
+    pub total_presentations: u32,
+    pub last_heard: Option<bool>, // answer of the previous presentation of the current test
+    pub ascending: HashMap<i32, (u32, u32)>, // level --> (ascending presentations, heard) for the current test
+    pub awaiting_answer: bool,
+    pub thresholds: Vec<AudiogramThreshold>,
+}
+
+// Result of an answer
+pub struct AudiometryStep {
+    pub ear: Ear,
+    pub frequency: f32,
+    pub level: i32, // level of the answered presentation
+    pub ascending: bool,
+    pub next_level: Option<i32>, // None if the test of this ear and frequency is finished
+    pub threshold: Option<AudiogramThreshold>, // result of the test, once finished
+}
+
+impl Audiometry {
+    pub fn from_options(options: &AudiometryOptions, calibration: Option<&Calibration>) -> Result<Audiometry, String> {
+        let name = options.name.trim().to_string();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("Invalid audiogram name (use letters, digits, - and _)".to_string());
+        }
+
+        let frequencies = if options.frequencies.is_empty() { "1000,2000,4000,8000,500,250" } else { &options.frequencies };
+        let frequencies = match frequencies.split(',').map(|freq| freq.trim().trim_end_matches("Hz").parse::<f32>()).collect::<Result<Vec<f32>, _>>() {
+            Ok(frequencies) if frequencies.iter().all(|freq| freq.is_finite() && *freq > 0.0 && *freq < options.sample_rate as f32 / 2.0) => frequencies,
+            _ => return Err(format!("Invalid frequencies \"{}\" (expected comma-separated frequencies in Hz, below half the sample rate)", frequencies)),
+        };
+
+        let ears = if options.ears.is_empty() { "right,left" } else { &options.ears };
+        let ears = match ears.split(',').map(Ear::parse).collect::<Option<Vec<Ear>>>() {
+            Some(ears) => ears,
+            None => return Err(format!("Invalid ears \"{}\" (expected right, left or right,left)", ears)),
+        };
+
+        if options.min_level % 5 != 0 || options.max_level % 5 != 0 || options.start_level % 5 != 0 {
+            return Err("start_level, min_level and max_level must be multiples of 5 dB".to_string());
+        }
+        if options.min_level >= options.max_level || !(options.min_level..=options.max_level).contains(&options.start_level) {
+            return Err(format!("start_level ({} dB HL) must be between min_level ({} dB HL) and max_level ({} dB HL)", options.start_level, options.min_level, options.max_level));
+        }
+        if options.duration == 0 || options.sample_rate == 0 || options.max_presentations == 0 {
+            return Err("duration, sample_rate and max_presentations must be greater than 0".to_string());
+        }
+
+        let mut tests = Vec::new();
+        for ear in &ears {
+            for frequency in &frequencies {
+                let gain = match calibration {
+                    Some(calibration) => match calibration.gain(*frequency, *ear) {
+                        Some(gain) => gain,
+                        None => return Err(format!("No calibration gain for {} Hz ({} ear) in {}", frequency, ear.as_str(), CALIBRATION_FILE)),
+                    },
+                    None => UNCALIBRATED_GAIN,
+                };
+                tests.push((*ear, *frequency, gain));
+            }
+        }
+
+        let mut audiometry = Audiometry {
+            name,
+            tests,
+            calibrated: calibration.is_some(),
+            duration: options.duration,
+            sample_rate: options.sample_rate,
+            start_level: options.start_level,
+            min_level: options.min_level,
+            max_level: options.max_level,
+            max_presentations: options.max_presentations,
+            log_file: String::new(),
+            results_file: String::new(),
+            test: 0,
+            level: options.start_level,
+            presentations: 0,
+            total_presentations: 0,
+            last_heard: None,
+            ascending: HashMap::new(),
+            awaiting_answer: false,
+            thresholds: Vec::new(),
+        };
+        audiometry.level = audiometry.level.min(audiometry.test_max_level());
+        Ok(audiometry)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.test >= self.tests.len()
+    }
+
+    // Max level of the current test: max_level, or lower if the tone would clip with the calibration gain
+    fn test_max_level(&self) -> i32 {
+        let (_, _, gain) = self.tests[self.test];
+        let max_without_clipping = (-gain / 5.0).floor() as i32 * 5;
+        self.max_level.min(max_without_clipping).max(self.min_level)
+    }
+
+    // Tone of the next presentation, with the calibration gain applied to its level
+    pub fn next_tone(&self) -> Option<(Ear, Tone)> {
+        let (ear, frequency, gain) = *self.tests.get(self.test)?;
+        Some((ear, Tone { freq: frequency, duration: self.duration, amplitude: self.level as f32 + gain, sample_rate: self.sample_rate }))
+    }
+
+    // Name of the next presentation, as in the logs (eg. tone_1000Hz_1000ms_-70dB_@48000Hz right)
+    pub fn next_tone_name(&self) -> String {
+        match self.next_tone() {
+            Some((ear, tone)) => format!("{} {}", tone_spec(&tone), ear.as_str()),
+            None => String::new(),
+        }
+    }
+
+    // Is the next presentation ascending? (previous tone not heard, or heard again at the min level)
+    pub fn next_is_ascending(&self) -> bool {
+        match self.last_heard {
+            Some(false) => true,
+            Some(true) => self.level == self.min_level,
+            None => false,
+        }
+    }
+
+    // Apply the answer of the last presentation, and move on to the next frequency (or ear) once the threshold is found
+    pub fn answer(&mut self, heard: bool) -> AudiometryStep {
+        let (ear, frequency, gain) = self.tests[self.test];
+        let level = self.level;
+        let ascending = self.next_is_ascending();
+        let max_level = self.test_max_level();
+        self.awaiting_answer = false;
+
+        let mut status = None;
+        if ascending {
+            let (presented, heard_count) = self.ascending.entry(level).or_insert((0, 0));
+            *presented += 1;
+            if heard {
+                *heard_count += 1;
+            }
+            if *heard_count >= 2 && *heard_count * 2 >= *presented {
+                status = Some("threshold");
+            }
+        }
+        if status.is_none() {
+            if !heard && level >= max_level {
+                status = Some("no_response");
+            } else if self.presentations >= self.max_presentations {
+                status = Some("not_established");
+            }
+        }
+
+        let threshold = status.map(|status| AudiogramThreshold {
+            ear: ear.as_str().to_string(),
+            frequency,
+            threshold: if status == "threshold" { Some(level) } else { None },
+            status: status.to_string(),
+            presentations: self.presentations,
+            calibration_gain: gain,
+        });
+
+        let next_level = match &threshold {
+            Some(threshold) => {
+                self.thresholds.push(threshold.clone());
+                // next frequency (or ear), from the start level
+                self.test += 1;
+                self.level = self.start_level;
+                self.presentations = 0;
+                self.last_heard = None;
+                self.ascending.clear();
+                None
+            },
+            None => {
+                self.level = if heard { (level - 10).max(self.min_level) } else { (level + 5).min(max_level) };
+                self.last_heard = Some(heard);
+                Some(self.level)
+            },
+        };
+        if !self.finished() {
+            self.level = self.level.min(self.test_max_level());
+        }
+
+        AudiometryStep { ear, frequency, level, ascending, next_level, threshold }
+    }
+
+    pub fn summary(&self) -> AudiometrySummary {
+        let next = self.tests.get(self.test);
+        AudiometrySummary {
+            name: self.name.clone(),
+            calibrated: self.calibrated,
+            finished: self.finished(),
+            ear: next.map(|(ear, _, _)| ear.as_str().to_string()),
+            frequency: next.map(|(_, frequency, _)| *frequency),
+            level: next.map(|_| self.level),
+            presentations: self.total_presentations,
+            thresholds: self.thresholds.clone(),
+            log_file: self.log_file.clone(),
+            results_file: self.results_file.clone(),
+        }
+    }
+}
+
+
+// Thresholds as CSV, one row per ear and frequency
+pub fn audiogram_csv(audiometry: &Audiometry) -> String {
+    let mut csv = "ear,frequency_hz,threshold_db_hl,status,presentations,calibration_gain_db\n".to_string();
+    for threshold in &audiometry.thresholds {
+        let threshold_text = threshold.threshold.map_or(String::new(), |threshold| threshold.to_string());
+        csv.push_str(&format!("{},{},{},{},{},{}\n", threshold.ear, threshold.frequency, threshold_text, threshold.status, threshold.presentations, threshold.calibration_gain));
+    }
+    csv
+}
+
+// Audiometry log: one row per presentation and per answer
+pub fn create_audiometry_log(audiometry: &mut Audiometry, time_ns: u128, client_time: &str, request: &RequestInfo) {
+    audiometry.log_file = chrono::Utc::now().format(&format!("logs/log_audiometry_{}_%Y%m%d-%H%M%S.csv", audiometry.name)).to_string();
+
+    let frequencies: Vec<String> = audiometry.tests.iter().map(|(ear, frequency, _)| format!("{}:{}", ear.as_str(), frequency)).collect();
+    start_csv_log(&audiometry.log_file, "timestamp_audio,audio_filename,status,timestamp_client,ear,frequency_hz,level_db_hl,ascending,answer,next_level_db_hl");
+    let request_text = format!(
+        "Received /audiometry/start (name: {} | tests: {} | start_level: {} dB HL | min_level: {} dB HL | max_level: {} dB HL | duration: {} ms | sample_rate: {} Hz | calibration: {})",
+        audiometry.name, frequencies.join(" "), audiometry.start_level, audiometry.min_level, audiometry.max_level, audiometry.duration, audiometry.sample_rate,
+        if audiometry.calibrated { CALIBRATION_FILE.to_string() } else { format!("none, {} dB", UNCALIBRATED_GAIN) }
+    );
+    log_row(&audiometry.log_file, &format!("{},{},success,{},,,,,,", time_ns, csv_field(&request_text), csv_field(client_time)), monotonic_ns(), Some(request));
+    println!("\x1b[1m    Started new log file: ./{}\x1b[0m", audiometry.log_file);
+    register_log_file(&audiometry.log_file); // session metadata sidecar
+}
+
+// Write the thresholds to logs/audiogram_{name}_%Y%m%d-%H%M%S.csv, once the audiogram is finished
+pub fn save_audiogram(audiometry: &mut Audiometry) {
+    audiometry.results_file = chrono::Utc::now().format(&format!("logs/audiogram_{}_%Y%m%d-%H%M%S.csv", audiometry.name)).to_string();
+    write_log_file(&audiometry.results_file, audiogram_csv(audiometry));
+    println!("\x1b[1m    Saved audiogram: ./{}\x1b[0m", audiometry.results_file);
+    register_log_file(&audiometry.results_file); // session metadata sidecar
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_audiometry(options: serde_json::Value) -> Audiometry {
+        Audiometry::from_options(&serde_json::from_value(options).unwrap(), None).unwrap()
+    }
+
+    // Present tones to a listener who hears the levels at or above hearing_level, as /audiometry/play and /audiometry/answer do,
+    // until the current test is finished. Returns the levels presented and the result
+    fn run_test(audiometry: &mut Audiometry, hearing_level: i32) -> (Vec<i32>, AudiogramThreshold) {
+        let mut levels = Vec::new();
+        loop {
+            audiometry.presentations += 1;
+            audiometry.total_presentations += 1;
+            levels.push(audiometry.level);
+            let step = audiometry.answer(audiometry.level >= hearing_level);
+            if let Some(threshold) = step.threshold {
+                return (levels, threshold);
+            }
+        }
+    }
+
+    #[test]
+    fn hughson_westlake_threshold() {
+        let mut audiometry = new_audiometry(serde_json::json!({ "name": "test", "frequencies": "1000,2000", "ears": "right" }));
+
+        // down 10 dB when heard, up 5 dB when not, until 2 of 2 ascending presentations are heard at the same level
+        let (levels, threshold) = run_test(&mut audiometry, 20);
+        assert_eq!(levels, vec![30, 20, 10, 15, 20, 10, 15, 20]);
+        assert_eq!((threshold.threshold, threshold.status.as_str(), threshold.presentations), (Some(20), "threshold", 8));
+        assert_eq!((threshold.ear.as_str(), threshold.frequency), ("right", 1000.0));
+
+        // next frequency, from the start level
+        assert_eq!(audiometry.level, 30);
+        assert!(!audiometry.finished());
+        let (_, threshold) = run_test(&mut audiometry, -10);
+        assert_eq!((threshold.threshold, threshold.frequency), (Some(-10), 2000.0)); // heard twice at the min level
+        assert!(audiometry.finished());
+        assert_eq!(audiometry.thresholds.len(), 2);
+        assert_eq!(audiometry.total_presentations, 8 + audiometry.thresholds[1].presentations);
+    }
+
+    #[test]
+    fn ascending_presentations_heard_at_least_half_the_time() {
+        let mut audiometry = new_audiometry(serde_json::json!({ "name": "test", "frequencies": "1000", "ears": "left" }));
+        // ascending at 25 dB: heard, not heard, heard (2 of 3)
+        let answers = [true, false, true, false, false, false, true, false, true];
+        let mut levels = Vec::new();
+        let mut step = None;
+        for heard in answers {
+            audiometry.presentations += 1;
+            levels.push(audiometry.level);
+            step = Some(audiometry.answer(heard));
+        }
+        assert_eq!(levels, vec![30, 20, 25, 15, 20, 25, 30, 20, 25]);
+        assert_eq!(step.unwrap().threshold.unwrap().threshold, Some(25));
+    }
+
+    #[test]
+    fn no_response_and_not_established() {
+        let mut audiometry = new_audiometry(serde_json::json!({ "name": "test", "frequencies": "1000", "ears": "right", "max_level": 40 }));
+        let (levels, threshold) = run_test(&mut audiometry, 100);
+        assert_eq!(levels, vec![30, 35, 40]);
+        assert_eq!((threshold.threshold, threshold.status.as_str()), (None, "no_response"));
+
+        let mut audiometry = new_audiometry(serde_json::json!({ "name": "test", "frequencies": "1000", "ears": "right", "max_presentations": 4 }));
+        let (levels, threshold) = run_test(&mut audiometry, 20);
+        assert_eq!(levels, vec![30, 20, 10, 15]);
+        assert_eq!((threshold.threshold, threshold.status.as_str()), (None, "not_established"));
+    }
+}
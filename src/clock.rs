@@ -0,0 +1,55 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::structs::{Event, EventKind};
+use crate::logging::log_event;
+use crate::{CLIENT_CLOCKS, LOG_FILE_NAME, SERVER_START};
+
+
+// Clocks: the timestamps of the logs are wall-clock UNIX nanoseconds (SystemTime), which jump when NTP adjusts the clock.
+// Every log row also has a monotonic timestamp (nanoseconds since the server started, from Instant / CLOCK_MONOTONIC),
+// so that the intervals between rows can be computed safely. A "Clock sync" row in the main log maps one clock to the other,
+// when each main log starts and then every CLOCK_SYNC_INTERVAL
+//
+// Client clocks: a client estimates the offset of its clock with NTP-style exchanges (/clock/sync), and registers it (/clock/offset).
+// Until /session/end, the client timestamps (time parameter, in UNIX nanoseconds) of its requests are then also logged corrected to server time.
+// The offsets are kept by client IP: the requests of another client (or of the server itself) are not corrected
+
+static CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+// Nanoseconds since the server started (never goes back, and isn't adjusted by NTP)
+pub fn monotonic_ns() -> u128 {
+    SERVER_START.elapsed().as_nanos()
+}
+
+// Wall-clock UNIX nanoseconds
+pub fn wall_clock_ns() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
+}
+
+// Client timestamp corrected to server time with the clock offset registered by the client at client_ip
+// None without an offset for this client, or if the client timestamp isn't in nanoseconds (eg. N/A, or schedule:{name})
+pub fn corrected_client_time(client_time: &str, client_ip: &str) -> Option<u128> {
+    let offset_ns = CLIENT_CLOCKS.lock().unwrap().get(client_ip)?.offset_ns;
+    let client_ns = client_time.trim().parse::<i128>().ok()?;
+    u128::try_from(client_ns + offset_ns as i128).ok()
+}
+
+// Append a "Clock sync" row to a log: both clocks read back to back, and the offset between them (wall clock - monotonic)
+// A change of the offset between two rows is an adjustment of the wall clock
+pub fn log_clock_sync(log_file: &str) {
+    let monotonic = monotonic_ns();
+    let wall = wall_clock_ns();
+    let offset_ns = wall as i128 - monotonic as i128;
+
+    let event = Event::new(wall, EventKind::ClockSync { offset_ns }, "success", "N/A").monotonic(monotonic);
+    log_event(log_file, &format!("{},Clock sync (monotonic: {} ns | offset: {} ns),success,N/A", wall, monotonic, offset_ns), &event);
+}
+
+// Log a clock sync row to the current main log every CLOCK_SYNC_INTERVAL
+pub fn start_clock_sync() {
+    std::thread::spawn(|| loop {
+        std::thread::sleep(CLOCK_SYNC_INTERVAL);
+        let log_file_name = LOG_FILE_NAME.lock().unwrap().clone();
+        log_clock_sync(&log_file_name);
+    });
+}
@@ -0,0 +1,34 @@
+use std::{collections::HashMap, fs};
+
+
+// Server configuration, read at startup: ./config.txt, one "key: value" per line (lines starting with # are comments)
+// Every key is optional, eg.:
+//     # csv, jsonl or both
+//     log_format: both
+//     # on or off
+//     timing_diagnostics: on
+
+pub static CONFIG_FILE: &str = "./config.txt";
+
+// Read the configuration file (keys in lowercase). No file is an empty configuration
+pub fn load_config(config_file: &str) -> HashMap<String, String> {
+    let mut config = HashMap::new();
+    let content = match fs::read_to_string(config_file) {
+        Ok(content) => content,
+        Err(_) => return config,
+    };
+
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((key, value)) => {
+                config.insert(key.trim().to_lowercase(), value.trim().to_string());
+            },
+            None => println!("\x1b[33mIgnoring line {} of {}: expected \"key: value\"\x1b[0m", line_number + 1, config_file),
+        }
+    }
+    config
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+use crate::structs::{LatencyStats, TimingReport};
+use crate::TIMING_DIAGNOSTICS;
+
+
+// Timing diagnostics (timing_diagnostics: on in ./config.txt, or /diagnostics/timing/enable), for each playback:
+// - receipt to onset: from the receipt of the HTTP request to the start of the playback (the onset logged in timestamp_audio)
+// - planned to actual onset: from the planned onset (start_at, or the end of the previous item of /play/random and playlists) to the actual onset
+// The receipt time is stamped by a middleware (see main.rs), before the routing and the handler. The onset is read when sink.play()
+// returns, with the stimulus already buffered in the paused sink: the output buffer of the audio device adds a constant latency after it,
+// which can only be measured with a loopback recording. Rolling statistics over the last DIAGNOSTICS_WINDOW playbacks are on /diagnostics/timing
+
+pub static DIAGNOSTICS_WINDOW: usize = 1000;
+
+#[derive(Default)]
+pub struct TimingDiagnostics {
+    pub enabled: bool,
+    receipt_to_onset_ms: VecDeque<f64>,
+    planned_to_actual_ms: VecDeque<f64>,
+}
+
+impl TimingDiagnostics {
+    pub fn reset(&mut self) {
+        self.receipt_to_onset_ms.clear();
+        self.planned_to_actual_ms.clear();
+    }
+
+    pub fn report(&self) -> TimingReport {
+        TimingReport {
+            enabled: self.enabled,
+            window: DIAGNOSTICS_WINDOW,
+            receipt_to_onset: latency_stats(self.receipt_to_onset_ms.iter().copied().collect()),
+            planned_to_actual: latency_stats(self.planned_to_actual_ms.iter().copied().collect()),
+        }
+    }
+}
+
+fn push_sample(samples: &mut VecDeque<f64>, sample: f64) {
+    if samples.len() == DIAGNOSTICS_WINDOW {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+// Milliseconds from a reference time to an onset (UNIX nanoseconds), negative if the onset is early
+pub fn latency_ms(reference_ns: u128, onset_ns: u128) -> f64 {
+    (onset_ns as i128 - reference_ns as i128) as f64 / 1_000_000.0
+}
+
+// Record the latencies of an onset, if the diagnostics are on: from the receipt of its request (first item of a playback only),
+// and from its planned onset (if it had one)
+pub fn record_onset_timing(received_ns: Option<u128>, planned_ns: Option<u128>, onset_ns: u128) {
+    let mut diagnostics = TIMING_DIAGNOSTICS.lock().unwrap();
+    if !diagnostics.enabled {
+        return;
+    }
+    if let Some(received_ns) = received_ns {
+        let latency = latency_ms(received_ns, onset_ns);
+        push_sample(&mut diagnostics.receipt_to_onset_ms, latency);
+        println!("\x1b[2m    \x1b[38;5;8mReceipt to onset: {:.3} ms\x1b[0m", latency);
+    }
+    if let Some(planned_ns) = planned_ns {
+        let latency = latency_ms(planned_ns, onset_ns);
+        push_sample(&mut diagnostics.planned_to_actual_ms, latency);
+        println!("\x1b[2m    \x1b[38;5;8mPlanned to actual onset: {:.3} ms\x1b[0m", latency);
+    }
+}
+
+// Mean, standard deviation (sample), min, max and nearest-rank percentiles of latencies in milliseconds
+pub fn latency_stats(mut samples: Vec<f64>) -> LatencyStats {
+    samples.sort_by(|a, b| a.total_cmp(b));
+    let count = samples.len();
+    let percentile = |p: f64| -> Option<f64> {
+        if count == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * count as f64).ceil() as usize).clamp(1, count);
+        Some(samples[rank - 1])
+    };
+    let mean = (count > 0).then(|| samples.iter().sum::<f64>() / count as f64);
+    let sd = mean.filter(|_| count > 1).map(|mean| (samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f64>() / (count - 1) as f64).sqrt());
+
+    LatencyStats {
+        count,
+        mean_ms: mean,
+        sd_ms: sd,
+        min_ms: samples.first().copied(),
+        p50_ms: percentile(50.0),
+        p90_ms: percentile(90.0),
+        p95_ms: percentile(95.0),
+        p99_ms: percentile(99.0),
+        max_ms: samples.last().copied(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: Option<f64>, expected: f64) {
+        assert!((value.unwrap() - expected).abs() < 1e-9, "{:?} != {}", value, expected);
+    }
+
+    #[test]
+    fn percentiles_and_sd() {
+        // 1 to 100 ms, in reverse order
+        let stats = latency_stats((1..=100).rev().map(|latency| latency as f64).collect());
+        assert_eq!(stats.count, 100);
+        assert_eq!((stats.min_ms, stats.max_ms), (Some(1.0), Some(100.0)));
+        assert_eq!((stats.p50_ms, stats.p90_ms, stats.p95_ms, stats.p99_ms), (Some(50.0), Some(90.0), Some(95.0), Some(99.0)));
+        assert_close(stats.mean_ms, 50.5);
+        assert_close(stats.sd_ms, (100.0 * 101.0 / 12.0f64).sqrt()); // sample SD of 1..n: sqrt(n(n+1)/12)
+
+        let stats = latency_stats(vec![9.0, 2.0, 4.0, 5.0, 4.0, 7.0, 4.0, 5.0]);
+        assert_close(stats.mean_ms, 5.0);
+        assert_close(stats.sd_ms, (32.0 / 7.0f64).sqrt());
+        assert_eq!((stats.p50_ms, stats.p90_ms, stats.p99_ms), (Some(4.0), Some(9.0), Some(9.0))); // nearest rank
+    }
+
+    #[test]
+    fn few_samples() {
+        let stats = latency_stats(vec![-0.25]);
+        assert_eq!((stats.count, stats.mean_ms, stats.sd_ms), (1, Some(-0.25), None));
+        assert_eq!((stats.min_ms, stats.p50_ms, stats.p99_ms, stats.max_ms), (Some(-0.25), Some(-0.25), Some(-0.25), Some(-0.25)));
+
+        let stats = latency_stats(Vec::new());
+        assert_eq!((stats.count, stats.mean_ms, stats.sd_ms, stats.min_ms, stats.p50_ms, stats.max_ms), (0, None, None, None, None, None));
+    }
+}
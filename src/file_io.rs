@@ -1,9 +1,108 @@
 use std::io::Write;
 use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
 
-use actix_web::web;
+use actix_web::{http::StatusCode, web};
 
-use crate::{structs::AudioFiles, audio::PlaylistTypes, PORT};
+use crate::{structs::{AudioFiles, Playlists, PlaylistLog}, audio::{Playlist, load_and_validate_playlists}, PLAYLISTS, PORT};
+use crate::logging::parse_csv_row;
+
+
+// Save a playlist file to ./playlists/, then hot reload the playlists so that it can be played right away
+// The file is overwritten if it already exists (the same content always gives the same file name)
+pub fn save_playlist_and_reload(playlist_file_name: &str, content: &str, audio_files: &web::Data<AudioFiles>) {
+    fs::create_dir_all("./playlists").unwrap();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(format!("./playlists/{}", playlist_file_name))
+        .unwrap();
+
+    if let Err(e) = writeln!(file, "{}", content) {
+        eprintln!("Couldn't create new file: {}", e);
+    } else {
+        println!("\x1b[2m    \x1b[38;5;8mCreated new playlist file server-side: ./playlists/{}\x1b[0m", playlist_file_name);
+    }
+    drop(file);
+
+    // Reload the playlists
+    println!(" !! Hot Reloading Playlists !!");
+    let mut playlists = PLAYLISTS.lock().unwrap();
+    *playlists = Playlists { playlists: load_and_validate_playlists("./playlists", &audio_files.files) };
+    drop(playlists);
+}
+
+
+// Put a playlist file back as it was before save_playlist_and_reload (None: there was no such file), then hot reload the playlists
+pub fn restore_playlist_and_reload(playlist_file_name: &str, previous_content: Option<&str>, audio_files: &web::Data<AudioFiles>) {
+    let path = format!("./playlists/{}", playlist_file_name);
+    let result = match previous_content {
+        Some(previous_content) => fs::write(&path, previous_content),
+        None => fs::remove_file(&path),
+    };
+    if let Err(e) = result {
+        eprintln!("Couldn't restore playlist file: {} ({})", e, path);
+    } else {
+        println!("\x1b[2m    \x1b[38;5;8mRestored playlist file server-side: {}\x1b[0m", path);
+    }
+
+    println!(" !! Hot Reloading Playlists !!");
+    let mut playlists = PLAYLISTS.lock().unwrap();
+    *playlists = Playlists { playlists: load_and_validate_playlists("./playlists", &audio_files.files) };
+    drop(playlists);
+}
+
+
+// Read back a playlist log (./logs/log_playlist_*.csv): the playlist and its playback settings from the request row, and the items played
+// Each played item (audio file, tone or pause) has exactly one row, written once it has finished playing
+pub fn read_playlist_log(path: &str) -> Result<PlaylistLog, (StatusCode, String)> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err((StatusCode::NOT_FOUND, format!("Log file {} not found", path))),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Couldn't read log file {}: {}", path, e))),
+    };
+    let mut lines = content.lines().skip(1); // skip the header
+
+    // eg. 1706709600000000000,Received /playlist/blocks.txt (iti: 800 ms | block_rest: 0 ms | shuffle: trials | ... | seed: 42 | rng: ChaCha8Rng),success,N/A,,,,
+    let request_row = parse_csv_row(lines.next().unwrap_or(""));
+    let request = match request_row.get(1).and_then(|request| request.strip_prefix("Received /playlist/")) {
+        Some(request) => request,
+        None => return Err((StatusCode::BAD_REQUEST, format!("{} is not a playlist log (no request row)", path))),
+    };
+    let (request_path, settings_text) = match request.split_once(" (").and_then(|(request_path, rest)| rest.rfind(')').map(|end| (request_path, &rest[..end]))) {
+        Some((request_path, settings_text)) => (request_path, settings_text),
+        None => return Err((StatusCode::BAD_REQUEST, format!("{} has no playback settings in its request row", path))),
+    };
+
+    let mut settings: HashMap<String, String> = HashMap::new();
+    for setting in settings_text.split(" | ") {
+        if let Some((key, value)) = setting.split_once(": ") {
+            // "800 ms" --> "800"
+            settings.insert(key.trim().to_string(), value.trim().trim_end_matches(" ms").to_string());
+        }
+    }
+
+    // the continuation logs name the playlist in the settings, the others in the request path
+    let playlist = settings.get("playlist").cloned().unwrap_or(request_path.to_string());
+    let first_item = settings.get("first_item").and_then(|first_item| first_item.parse::<usize>().ok()).unwrap_or(1).max(1) - 1;
+
+    // The item rows are all the "success" rows, except the scheduled start, the end, the continuation and the response rows
+    let mut played_items: Vec<String> = Vec::new();
+    for line in lines {
+        let columns = parse_csv_row(line); // the file names may contain commas (quoted)
+        if columns.len() < 3 || columns[2] != "success" {
+            continue;
+        }
+        if columns[1].starts_with("Scheduled start") || columns[1].starts_with("End of") || columns[1].starts_with("Continued in") || columns[1].starts_with("Response ") || columns[1].starts_with("Miss (") {
+            continue;
+        }
+        played_items.push(columns[1].to_string());
+    }
+
+    Ok(PlaylistLog { playlist, settings, first_item, played_items })
+}
 
 
 // Create a batch file for Windows. Edit this template to change the batch file content
@@ -66,7 +165,7 @@ fn create_batch_file_playlist(playlist_name: &str, host_ip: &str, port: &str, wi
 }
 
 
-pub fn make_batch_zip_file(audio_files: &web::Data<AudioFiles>, playlists: &HashMap<String, Vec<PlaylistTypes>>, host_ip: &str, with_async: bool) -> Vec<u8> {
+pub fn make_batch_zip_file(audio_files: &web::Data<AudioFiles>, playlists: &HashMap<String, Playlist>, host_ip: &str, with_async: bool) -> Vec<u8> {
     // create a zip file containing all the batch files
     let mut zip_file = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
     let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
@@ -105,4 +204,58 @@ pub fn make_batch_zip_file(audio_files: &web::Data<AudioFiles>, playlists: &Hash
     // finish the zip file
     let zip_file = zip_file.finish().unwrap().into_inner();
     zip_file
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a log under the temp directory and read it back
+    fn read_log(name: &str, content: &str) -> Result<PlaylistLog, (StatusCode, String)> {
+        let path = std::env::temp_dir().join(format!("rust_audio_server_{}_{}.csv", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        let log = read_playlist_log(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        log
+    }
+
+    const HEADER: &str = "timestamp_audio,audio_filename,status,timestamp_client,block,block_name,trial,source_playlist,timestamp_monotonic,timestamp_client_corrected\n";
+
+    #[test]
+    fn playlist_log() {
+        let log = read_log("playlist_log", &format!("{}{}", HEADER, "\
+            1706709600000000000,Received /playlist/blocks.txt (iti: 800 ms | block_rest: 0 ms | shuffle: trials | max_duration: 0 ms | seed: 42 | rng: ChaCha8Rng),success,N/A,,,,,1,\n\
+            1706709601000000000,Scheduled start,success,N/A,,,,,2,\n\
+            1706709602000000000,440.wav,success,N/A,1,a,1,,3,\n\
+            1706709603000000000,\"tone_1000hz_200ms\",success,N/A,1,a,2,,4,\n\
+            1706709603500000000,Response (hit: 350 ms),success,N/A,,,,,5,\n\
+            1706709604000000000,\"a, b.wav\",success,N/A,1,a,3,,6,\n\
+            1706709605000000000,1000.wav,error,N/A,1,a,4,,7,\n\
+            1706709606000000000,End of playlist,success,N/A,,,,,8,\n"));
+        let log = log.unwrap();
+        assert_eq!(log.playlist, "blocks.txt");
+        assert_eq!(log.first_item, 0);
+        assert_eq!((log.settings["iti"].as_str(), log.settings["shuffle"].as_str(), log.settings["seed"].as_str()), ("800", "trials", "42"));
+        assert_eq!(log.played_items, vec!["440.wav", "tone_1000hz_200ms", "a, b.wav"]);
+    }
+
+    #[test]
+    fn continuation_log() {
+        let log = read_log("continuation_log", &format!("{}{}", HEADER, "\
+            1706709600000000000,Received /playlist/resume/log_playlist_20240131-140000.csv (iti: 0 ms | block_rest: 0 ms | shuffle: none | max_duration: 0 ms | seed: 7 | rng: ChaCha8Rng | playlist: session.txt | resumes: log_playlist_20240131-140000.csv | first_item: 5),success,N/A,,,,,1,\n\
+            1706709601000000000,440.wav,success,N/A,,,,,2,\n"));
+        let log = log.unwrap();
+        assert_eq!(log.playlist, "session.txt");
+        assert_eq!(log.first_item, 4);
+        assert_eq!(log.played_items, vec!["440.wav"]);
+    }
+
+    #[test]
+    fn invalid_playlist_logs() {
+        let status = |log: Result<PlaylistLog, (StatusCode, String)>| log.err().unwrap().0;
+        assert_eq!(status(read_playlist_log("./logs/log_playlist_00000000-000000.csv")), StatusCode::NOT_FOUND);
+        assert_eq!(status(read_log("empty_log", "")), StatusCode::BAD_REQUEST);
+        assert_eq!(status(read_log("file_log", &format!("{}1706709600000000000,Received /play/440.wav,success,N/A,,,,,1,\n", HEADER))), StatusCode::BAD_REQUEST);
+        assert_eq!(status(read_log("settings_log", &format!("{}1706709600000000000,Received /playlist/blocks.txt,success,N/A,,,,,1,\n", HEADER))), StatusCode::BAD_REQUEST);
+    }
+}
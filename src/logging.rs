@@ -0,0 +1,415 @@
+use std::{
+    collections::HashMap,
+    fs,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{HttpMessage, HttpRequest};
+
+use crate::structs::{Event, EventKind, EventPosition, RequestInfo, ReceivedAt};
+use crate::clock::{monotonic_ns, corrected_client_time};
+use crate::{LOG_FORMAT, LOG_WRITER};
+
+
+// Logging subsystem: every log file of the server (main, /play/random, playlist, staircase, audiometry and protocol logs, audiograms
+// and session sidecars) is written by a single background thread, fed by a channel. The routes only queue their rows, so a slow disk
+// never blocks the playback. The rows are buffered, and flushed every FLUSH_INTERVAL, on /session/end, before reading a log back, and on shutdown
+//
+// Main, /play/random and playlist logs: CSV rows (timestamp_audio,audio_filename,status,timestamp_client,...) and/or a JSON Lines event log,
+// with one JSON object per line and the typed fields of its event kind (see EventKind, and the schema in /info)
+// logs/log_playlist_20240131-140000.csv --> logs/log_playlist_20240131-140000.jsonl
+// The format is chosen with log_format in ./config.txt: csv (default), jsonl or both
+
+static FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Columns at the end of every row of the main, /play/random and playlist logs, see csv_request_columns
+static CSV_REQUEST_HEADER: &str = "timestamp_end,measured_duration_ms,nominal_duration_ms,source_sample_rate_hz,client_ip,request_path,request_id";
+
+pub enum LogMessage {
+    Create(String), // file, created empty if it doesn't exist
+    Append(String, String), // file, line
+    Write(String, String), // file, content (overwrites the file)
+    Flush(mpsc::Sender<()>), // answered once everything queued before is on disk
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Csv,
+    Jsonl,
+    Both,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format.to_lowercase().as_str() {
+            "csv" => Some(LogFormat::Csv),
+            "jsonl" => Some(LogFormat::Jsonl),
+            "both" => Some(LogFormat::Both),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Csv => "csv",
+            LogFormat::Jsonl => "jsonl",
+            LogFormat::Both => "both",
+        }
+    }
+
+    pub fn csv(&self) -> bool {
+        *self != LogFormat::Jsonl
+    }
+
+    pub fn jsonl(&self) -> bool {
+        *self != LogFormat::Csv
+    }
+}
+
+impl Event {
+    // An event at timestamp_ns. The client time is left out of the JSON if empty or N/A
+    // The monotonic time is read now: the events built after their timestamp (onsets, pauses) set the one read with it (see monotonic)
+    pub fn new(timestamp_ns: u128, kind: EventKind, status: &str, client_time: &str) -> Event {
+        let timestamp_client = match client_time {
+            "" | "N/A" => None,
+            client_time => Some(client_time.to_string()),
+        };
+        Event {
+            timestamp_ns,
+            timestamp_monotonic_ns: monotonic_ns(),
+            kind,
+            status: status.to_string(),
+            timestamp_client,
+            timestamp_client_corrected_ns: None, // see for_request
+            job_id: None,
+            position: None,
+            timestamp_end_ns: None,
+            measured_duration_ms: None,
+            nominal_duration_ms: None,
+            source_sample_rate_hz: None,
+            request: None,
+        }
+    }
+
+    // Monotonic time read together with timestamp_ns (see clock::monotonic_ns)
+    pub fn monotonic(mut self, monotonic_ns: u128) -> Event {
+        self.timestamp_monotonic_ns = monotonic_ns;
+        self
+    }
+
+    pub fn in_job(mut self, job_id: u128) -> Event {
+        self.job_id = Some(job_id);
+        self
+    }
+
+    pub fn at(mut self, position: Option<EventPosition>) -> Event {
+        self.position = position;
+        self
+    }
+
+    // A played item: from timestamp_ns to end_ns (measured), with the nominal duration and the sample rate of its source, if known
+    pub fn played(mut self, end_ns: u128, nominal_duration: Option<Duration>, sample_rate: Option<u32>) -> Event {
+        self.timestamp_end_ns = Some(end_ns);
+        self.measured_duration_ms = Some(end_ns.saturating_sub(self.timestamp_ns) as f64 / 1_000_000.0);
+        self.nominal_duration_ms = nominal_duration.map(|duration| duration.as_secs_f64() * 1000.0);
+        self.source_sample_rate_hz = sample_rate;
+        self
+    }
+
+    // The request of the event: its client timestamp is corrected with the clock offset of its client, if registered
+    pub fn for_request(mut self, request: &RequestInfo) -> Event {
+        self.timestamp_client_corrected_ns = self.timestamp_client.as_deref().and_then(|client_time| corrected_client_time(client_time, &request.client_ip));
+        self.request = Some(request.clone());
+        self
+    }
+}
+
+impl RequestInfo {
+    pub fn from_request(req: &HttpRequest) -> RequestInfo {
+        let request_id = req.headers().get("X-Request-ID")
+            .and_then(|request_id| request_id.to_str().ok())
+            .filter(|request_id| !request_id.is_empty())
+            .map_or_else(new_request_id, |request_id| request_id.to_string());
+        RequestInfo {
+            client_ip: req.peer_addr().map_or(String::new(), |address| address.ip().to_string()),
+            request_path: req.uri().to_string(),
+            request_id,
+            received_ns: req.extensions().get::<ReceivedAt>().map(|received_at| received_at.0), // stamped by the middleware of main.rs
+        }
+    }
+
+    // Requests of the server itself (schedules, protocols), without a client
+    pub fn internal(request_path: &str) -> RequestInfo {
+        RequestInfo { client_ip: String::new(), request_path: request_path.to_string(), request_id: new_request_id(), received_ns: None }
+    }
+}
+
+fn new_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+// A CSV field, quoted if it contains a comma, a quote or a line break (with the quotes doubled)
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Split a CSV row into its fields, unquoted (see csv_field)
+pub fn parse_csv_row(row: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            },
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (c, _) => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+// End time, durations, sample rate and request columns of a row (with a leading comma), empty for what the event doesn't have
+fn csv_request_columns(event: &Event) -> String {
+    let request = event.request.clone().unwrap_or_default();
+    format!(
+        ",{},{},{},{},{},{},{}",
+        event.timestamp_end_ns.map_or(String::new(), |end_ns| end_ns.to_string()),
+        event.measured_duration_ms.map_or(String::new(), |duration| format!("{:.3}", duration)),
+        event.nominal_duration_ms.map_or(String::new(), |duration| format!("{:.3}", duration)),
+        event.source_sample_rate_hz.map_or(String::new(), |sample_rate| sample_rate.to_string()),
+        request.client_ip,
+        csv_field(&request.request_path),
+        csv_field(&request.request_id)
+    )
+}
+
+// Column of an optional value, empty if None
+fn optional_column<T: ToString>(value: Option<T>) -> String {
+    value.map_or(String::new(), |value| value.to_string())
+}
+
+// Start the background writer. Until then (and if it stops), the logs are written synchronously
+pub fn start_log_writer() {
+    let (sender, receiver) = mpsc::channel::<LogMessage>();
+    *LOG_WRITER.lock().unwrap() = Some(sender);
+
+    std::thread::spawn(move || {
+        // the files are kept open until the next flush
+        let mut files: HashMap<String, BufWriter<File>> = HashMap::new();
+        let mut last_flush = Instant::now();
+        loop {
+            match receiver.recv_timeout(FLUSH_INTERVAL.saturating_sub(last_flush.elapsed())) {
+                Ok(LogMessage::Create(file_name)) => {
+                    if let Err(e) = open_log_file(&file_name) {
+                        eprintln!("Couldn't create new file: {} ({})", e, file_name);
+                    }
+                },
+                Ok(LogMessage::Append(file_name, line)) => {
+                    if !files.contains_key(&file_name) {
+                        match open_log_file(&file_name) {
+                            Ok(file) => {
+                                files.insert(file_name.clone(), BufWriter::new(file));
+                            },
+                            Err(e) => eprintln!("Couldn't write to file: {} ({})", e, file_name),
+                        }
+                    }
+                    if let Some(file) = files.get_mut(&file_name) {
+                        if let Err(e) = writeln!(file, "{}", line) {
+                            eprintln!("Couldn't write to file: {} ({})", e, file_name);
+                        }
+                    }
+                },
+                Ok(LogMessage::Write(file_name, content)) => {
+                    flush_log_files(&mut files); // in case the file was also appended to
+                    if let Err(e) = fs::write(&file_name, content) {
+                        eprintln!("Couldn't write to file: {} ({})", e, file_name);
+                    }
+                },
+                Ok(LogMessage::Flush(done)) => {
+                    flush_log_files(&mut files);
+                    let _ = done.send(());
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    flush_log_files(&mut files);
+                    break;
+                }
+            }
+
+            // every FLUSH_INTERVAL, even if the rows keep coming
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                flush_log_files(&mut files);
+                last_flush = Instant::now();
+            }
+        }
+    });
+}
+
+fn open_log_file(file_name: &str) -> Result<File, std::io::Error> {
+    if let Some(folder) = Path::new(file_name).parent() {
+        fs::create_dir_all(folder)?;
+    }
+    OpenOptions::new().append(true).create(true).open(file_name)
+}
+
+// Flush and close the open log files
+fn flush_log_files(files: &mut HashMap<String, BufWriter<File>>) {
+    for (file_name, mut file) in files.drain() {
+        if let Err(e) = file.flush() {
+            eprintln!("Couldn't write to file: {} ({})", e, file_name);
+        }
+    }
+}
+
+// Queue a message for the background writer, or handle it right away if the writer is not running
+fn send(message: LogMessage) {
+    let message = match LOG_WRITER.lock().unwrap().as_ref() {
+        Some(sender) => match sender.send(message) {
+            Ok(()) => return,
+            Err(mpsc::SendError(message)) => message,
+        },
+        None => message,
+    };
+    let result = match &message {
+        LogMessage::Create(file_name) => open_log_file(file_name).map(|_| ()),
+        LogMessage::Append(file_name, line) => open_log_file(file_name).and_then(|mut file| writeln!(file, "{}", line)),
+        LogMessage::Write(file_name, content) => fs::write(file_name, content),
+        LogMessage::Flush(done) => {
+            let _ = done.send(());
+            Ok(())
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("Couldn't write to file: {}", e);
+    }
+}
+
+// Block until all the rows queued so far are written to disk, eg. before reading a log back, on /session/end and on shutdown
+pub fn flush_logs() {
+    let (done, flushed) = mpsc::channel();
+    send(LogMessage::Flush(done));
+    let _ = flushed.recv();
+}
+
+// Log file name without its extension (the log files are passed with or without .csv)
+fn log_file_base(log_file: &str) -> &str {
+    log_file.strip_suffix(".csv").unwrap_or(log_file)
+}
+
+// JSON Lines event log of a log file (with or without the .csv extension)
+pub fn jsonl_file_name(log_file: &str) -> String {
+    format!("{}.jsonl", log_file_base(log_file))
+}
+
+// Files written for a main, /play/random or playlist log with the configured log format, for the console and the responses
+// eg. "./logs/log_20240131-140000.csv and ./logs/log_20240131-140000.jsonl"
+pub fn log_file_names(log_file: &str) -> String {
+    let log_format = *LOG_FORMAT.lock().unwrap();
+    let mut file_names = Vec::new();
+    if log_format.csv() {
+        file_names.push(format!("./{}.csv", log_file_base(log_file)));
+    }
+    if log_format.jsonl() {
+        file_names.push(format!("./{}", jsonl_file_name(log_file)));
+    }
+    file_names.join(" and ")
+}
+
+// Start a new main, /play/random or playlist log: the CSV header (followed by the request columns and timestamp_monotonic) if the CSV log is on,
+// and an empty JSON Lines file if the event log is on
+pub fn start_log(log_file: &str, csv_header: &str) {
+    let log_format = *LOG_FORMAT.lock().unwrap();
+    let log_file = log_file_base(log_file);
+
+    if log_format.csv() {
+        send(LogMessage::Append(format!("{}.csv", log_file), format!("{},{},timestamp_monotonic,timestamp_client_corrected", csv_header, CSV_REQUEST_HEADER)));
+    }
+    if log_format.jsonl() {
+        send(LogMessage::Create(jsonl_file_name(log_file)));
+    }
+}
+
+// Append an event to a main, /play/random or playlist log: its CSV row (followed by the request columns and the monotonic timestamp) if the CSV log is on,
+// and its JSON line if the event log is on. The text fields of csv_row must be quoted with csv_field
+pub fn log_event(log_file: &str, csv_row: &str, event: &Event) {
+    let log_format = *LOG_FORMAT.lock().unwrap();
+    let log_file = log_file_base(log_file);
+
+    if log_format.csv() {
+        send(LogMessage::Append(format!("{}.csv", log_file), format!("{}{},{},{}", csv_row, csv_request_columns(event), event.timestamp_monotonic_ns, optional_column(event.timestamp_client_corrected_ns))));
+    }
+    if log_format.jsonl() {
+        send(LogMessage::Append(jsonl_file_name(log_file), serde_json::to_string(event).unwrap()));
+    }
+    println!("\x1b[2m    \x1b[38;5;8mAppended to log file: {}\x1b[0m", log_file);
+}
+
+// Start a CSV-only log (staircase, audiometry and protocol logs, with the .csv extension): its header, followed by timestamp_monotonic
+// and timestamp_client_corrected
+pub fn start_csv_log(log_file: &str, csv_header: &str) {
+    send(LogMessage::Append(log_file.to_string(), format!("{},timestamp_monotonic,timestamp_client_corrected", csv_header)));
+}
+
+// Append a row to a CSV-only log, followed by the monotonic time read with its timestamp (the first column)
+// and its client timestamp (the fourth column) corrected to server time with the clock offset of the client of the request, if any
+pub fn log_row(log_file: &str, row: &str, timestamp_monotonic_ns: u128, request: Option<&RequestInfo>) {
+    let timestamp_client_corrected = request.and_then(|request| {
+        let columns = parse_csv_row(row);
+        columns.get(3).and_then(|client_time| corrected_client_time(client_time, &request.client_ip))
+    });
+    send(LogMessage::Append(log_file.to_string(), format!("{},{},{}", row, timestamp_monotonic_ns, optional_column(timestamp_client_corrected))));
+    println!("\x1b[2m    \x1b[38;5;8mAppended to log file: {}\x1b[0m", log_file);
+}
+
+// Write a whole file next to the logs (audiograms, session sidecars), replacing it if it exists
+pub fn write_log_file(file_name: &str, content: String) {
+    send(LogMessage::Write(file_name.to_string(), content));
+}
+
+// Event of a scheduled start (start_at): requested and actual start times, and the error (positive = late) in nanoseconds, see scheduled_start_text
+// actual_monotonic_ns is the monotonic time read with actual_ns
+pub fn scheduled_start_event(stimulus: Option<&str>, requested_ns: u128, actual_ns: u128, actual_monotonic_ns: u128, client_time: &str) -> Event {
+    let kind = EventKind::ScheduledStart { stimulus: stimulus.map(|stimulus| stimulus.to_string()), requested_ns, actual_ns, error_ns: actual_ns as i128 - requested_ns as i128 };
+    Event::new(actual_ns, kind, "success", client_time).monotonic(actual_monotonic_ns)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_field("1000.wav"), "1000.wav");
+        assert_eq!(csv_field("a,b.wav"), "\"a,b.wav\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn csv_rows_round_trip() {
+        let fields = ["1706709600000000000", "a,b.wav", "say \"hi\", twice", "", "\"", "line\nbreak", ",,", "N/A"];
+        let row = fields.iter().map(|field| csv_field(field)).collect::<Vec<String>>().join(",");
+        assert_eq!(row, "1706709600000000000,\"a,b.wav\",\"say \"\"hi\"\", twice\",,\"\"\"\",\"line\nbreak\",\",,\",N/A");
+        assert_eq!(parse_csv_row(&row), fields);
+    }
+
+    #[test]
+    fn csv_rows() {
+        assert_eq!(parse_csv_row(""), vec![""]);
+        assert_eq!(parse_csv_row("a,,b,"), vec!["a", "", "b", ""]);
+        assert_eq!(parse_csv_row("1,\"Received /playlist/a.txt (iti: 0 ms | seed: 42)\",success"), vec!["1", "Received /playlist/a.txt (iti: 0 ms | seed: 42)", "success"]);
+    }
+}
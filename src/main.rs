@@ -1,34 +1,63 @@
-use std::{
-    fs,
-    fs::OpenOptions,
-    io::Write,
-    sync::{Arc, Mutex},
-};
-
-use actix_web::{web, App, HttpServer};
+use std::sync::{Arc, Mutex};
+
+use actix_web::{dev::Service, web, App, HttpMessage, HttpServer};
 use chrono::Utc;
 use local_ip_address::local_ip;
 
 mod structs;
-use structs::{AudioFiles, Playlists};
+use structs::{AudioFiles, Playlists, Schedules};
 
 mod audio;
 use audio::{preload_audio_files, load_and_validate_playlists};
 
+mod audiometry;
+mod clock;
+use clock::{log_clock_sync, start_clock_sync};
+mod config;
+use config::{load_config, CONFIG_FILE};
+mod diagnostics;
 mod file_io;
+mod logging;
+use logging::{start_log, start_log_writer, flush_logs, log_file_names, LogFormat, LogMessage};
+mod playlist_formats;
+mod protocol;
+mod randomization;
+mod render;
+mod responses;
 mod routes;
+mod scheduler;
+use scheduler::{load_schedules, start_scheduler, SCHEDULES_FILE};
+mod session;
+mod staircase;
 
 
 // Define the port number
 static PORT: u16 = 5055;
 
 
+// Define the global variable for the start time of the server, the origin of the monotonic timestamps of the logs (see clock.rs)
+lazy_static::lazy_static! {
+    static ref SERVER_START: std::time::Instant = std::time::Instant::now();
+}
+
 // Define the global variable for the log file name
 // This will be updated whenever a new /startnewlog request is received
 lazy_static::lazy_static! {
     static ref LOG_FILE_NAME: Arc<Mutex<String>> = Arc::new(Mutex::new(Utc::now().format("logs/log_%Y%m%d-%H%M%S").to_string()));
 }
 
+// Define the global variable for the format of the main, /play/random and playlist logs (CSV, JSON Lines event log or both)
+// Set at startup from log_format in ./config.txt
+lazy_static::lazy_static! {
+    static ref LOG_FORMAT: Arc<Mutex<LogFormat>> = Arc::new(Mutex::new(LogFormat::Csv));
+}
+
+// Define the global variable for the channel of the background log writer (see logging.rs)
+// Every log row is queued to it, and written to disk by a single thread
+lazy_static::lazy_static! {
+    static ref LOG_WRITER: Arc<Mutex<Option<std::sync::mpsc::Sender<LogMessage>>>> = Arc::new(Mutex::new(None));
+}
+
 // Define the global variable for the current validated playlists
 // This will allow us to update the playlists without having to restart the server (hot reload)
 lazy_static::lazy_static! {
@@ -38,6 +67,62 @@ lazy_static::lazy_static! {
     }));
 }
 
+// Define the global variable for the recurring playback schedules
+// Edited over HTTP (/schedules/...) and saved to ./schedules.txt
+lazy_static::lazy_static! {
+    static ref SCHEDULES: Arc<Mutex<Schedules>> = Arc::new(Mutex::new(Schedules {
+        schedules: Vec::new(),
+        running: std::collections::HashSet::new(),
+    }));
+}
+
+// Define the global variable for the playlist variables (name --> value)
+// Set over HTTP (/variables/set) during playback, and checked by the conditional jumps of the playlists
+lazy_static::lazy_static! {
+    static ref PLAYLIST_VARIABLES: Arc<Mutex<std::collections::HashMap<String, String>>> = Arc::new(Mutex::new(std::collections::HashMap::new()));
+}
+
+// Define the global variable for the most recent stimulus onset
+// Updated when an audio file or a tone starts playing, and used to time the responses (/response)
+lazy_static::lazy_static! {
+    static ref LAST_STIMULUS: Arc<Mutex<Option<structs::StimulusOnset>>> = Arc::new(Mutex::new(None));
+}
+
+// Define the global variable for the adaptive staircases (name --> staircase)
+// Started, played and answered over HTTP (/staircase/...)
+lazy_static::lazy_static! {
+    static ref STAIRCASES: Arc<Mutex<std::collections::HashMap<String, staircase::Staircase>>> = Arc::new(Mutex::new(std::collections::HashMap::new()));
+}
+
+// Define the global variable for the pure-tone audiograms (name --> audiogram)
+// Started, played and answered over HTTP (/audiometry/...)
+lazy_static::lazy_static! {
+    static ref AUDIOGRAMS: Arc<Mutex<std::collections::HashMap<String, audiometry::Audiometry>>> = Arc::new(Mutex::new(std::collections::HashMap::new()));
+}
+
+// Define the global variable for the current (or last) protocol run
+// Started, continued and stopped over HTTP (/protocol/...), its steps run in a background thread
+lazy_static::lazy_static! {
+    static ref PROTOCOL: Arc<Mutex<Option<protocol::ProtocolRun>>> = Arc::new(Mutex::new(None));
+}
+
+// Define the global variable for the clock offsets registered by the clients (/clock/offset), by client IP, until /session/end
+lazy_static::lazy_static! {
+    static ref CLIENT_CLOCKS: Arc<Mutex<std::collections::HashMap<String, structs::ClientClock>>> = Arc::new(Mutex::new(std::collections::HashMap::new()));
+}
+
+// Define the global variable for the timing diagnostics (receipt-to-onset and planned-to-actual onset latencies)
+// Enabled at startup with timing_diagnostics in ./config.txt, or with /diagnostics/timing/enable
+lazy_static::lazy_static! {
+    static ref TIMING_DIAGNOSTICS: Arc<Mutex<diagnostics::TimingDiagnostics>> = Arc::new(Mutex::new(diagnostics::TimingDiagnostics::default()));
+}
+
+// Define the global variable for the current session (participant, experiment, ...)
+// Started and ended over HTTP (/session/...), its metadata is written next to every log file of the session
+lazy_static::lazy_static! {
+    static ref SESSION: Arc<Mutex<Option<structs::Session>>> = Arc::new(Mutex::new(None));
+}
+
 /// ---------- APP & ROUTES ---------- ///
 
 // See individual route functions in src/routes/*.rs
@@ -46,6 +131,7 @@ lazy_static::lazy_static! {
 async fn main() -> std::io::Result<()> {
     println!(" --------------- AUDIO SERVER ---------------");
     println!("The source code for this program is available at https://github.com/codynhanpham/rust_audio_server\n\n");
+    lazy_static::initialize(&SERVER_START); // the monotonic clock starts now
 
     println!("Looking for audio files in ./audio/*.wav ...");
 
@@ -60,8 +146,31 @@ async fn main() -> std::io::Result<()> {
     *playlists = Playlists { playlists: current_playlists }; // update playlists
     drop(playlists); // release the lock on PLAYLISTS global
 
-    // make sure the log folder exists
-    fs::create_dir_all("./logs").unwrap();
+    // read the configuration: the format of the logs
+    let config = load_config(CONFIG_FILE);
+    let log_format = match config.get("log_format").map(|format| (format, LogFormat::parse(format))) {
+        Some((_, Some(log_format))) => log_format,
+        Some((format, None)) => {
+            println!("\x1b[33mInvalid log_format \"{}\" in {} (csv, jsonl or both), using csv\x1b[0m", format, CONFIG_FILE);
+            LogFormat::Csv
+        },
+        None => LogFormat::Csv,
+    };
+    *LOG_FORMAT.lock().unwrap() = log_format;
+    println!("Log format: {}", log_format.as_str());
+
+    // and the timing diagnostics
+    let timing_diagnostics = match config.get("timing_diagnostics").map(|value| value.to_lowercase()).as_deref() {
+        Some("on") => true,
+        Some("off") | None => false,
+        Some(value) => {
+            println!("\x1b[33mInvalid timing_diagnostics \"{}\" in {} (on or off), using off\x1b[0m", value, CONFIG_FILE);
+            false
+        },
+    };
+    TIMING_DIAGNOSTICS.lock().unwrap().enabled = timing_diagnostics;
+    println!("Timing diagnostics: {}\n", if timing_diagnostics { "on" } else { "off" });
+    start_log_writer();
 
     // init a new log file name with the current date time
     let mut log_file_name = LOG_FILE_NAME.lock().unwrap();
@@ -69,20 +178,21 @@ async fn main() -> std::io::Result<()> {
     
     
     // create new log file
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("{}.csv", *log_file_name))
-        .unwrap();
-    if let Err(e) = writeln!(file, "timestamp_audio,audio_filename,status,timestamp_client") {
-        eprintln!("Couldn't create new file: {}", e);
-    } else {
-        println!("Started new log file: ./{}.csv\n", *log_file_name);
-    }
+    start_log(&log_file_name, "timestamp_audio,audio_filename,status,timestamp_client");
+    log_clock_sync(&log_file_name);
+    println!("Started new log file: {}\n", log_file_names(&log_file_name));
 
     drop(log_file_name); // release the lock
-    drop(file); // release the lock
+
+
+    // load the schedules and start the scheduler
+    let schedules = load_schedules(SCHEDULES_FILE);
+    println!("Loaded {} schedule(s) from {} ({} enabled)\n", schedules.len(), SCHEDULES_FILE, schedules.iter().filter(|s| s.enabled).count());
+    SCHEDULES.lock().unwrap().schedules = schedules;
+    start_scheduler(audio_files.clone());
+
+    // map the monotonic clock to the wall clock in the main log, every minute
+    start_clock_sync();
 
 
     // start the server
@@ -92,6 +202,11 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .app_data(audio_files.clone())
+            // stamp the receipt time of every request, before the routing (receipt-to-onset latency of the timing diagnostics)
+            .wrap_fn(|req, srv| {
+                req.extensions_mut().insert(structs::ReceivedAt(clock::wall_clock_ns()));
+                srv.call(req)
+            })
             .service(routes::info::index)
             .service(routes::ping::ping)
             .service(routes::list::list)
@@ -100,12 +215,56 @@ async fn main() -> std::io::Result<()> {
             .service(routes::tone::play_tone)
             .service(routes::tone::save_tone)
             .service(routes::playlists::create_playlist)
+            .service(routes::playlists::create_oddball_playlist)
+            .service(routes::playlists::create_latin_square_playlist)
+            .service(routes::playlists::resume_playlist)
+            .service(routes::import_export::export)
+            .service(routes::import_export::import)
+            .service(routes::playlists::preview_playlist)
+            .service(routes::playlists::render_playlist)
             .service(routes::playlists::play)
+            .service(routes::schedules::list_schedules)
+            .service(routes::schedules::add_schedule)
+            .service(routes::schedules::enable_schedule)
+            .service(routes::schedules::disable_schedule)
+            .service(routes::schedules::remove_schedule)
+            .service(routes::schedules::reload_schedules)
+            .service(routes::response::response)
+            .service(routes::variables::list_variables)
+            .service(routes::variables::set_variable)
+            .service(routes::variables::clear_variables)
+            .service(routes::staircases::start_staircase)
+            .service(routes::staircases::play_staircase)
+            .service(routes::staircases::answer_staircase)
+            .service(routes::staircases::staircase_summary)
+            .service(routes::audiometry::start_audiometry)
+            .service(routes::audiometry::play_audiometry)
+            .service(routes::audiometry::answer_audiometry)
+            .service(routes::audiometry::audiometry_results_csv)
+            .service(routes::audiometry::audiometry_results)
+            .service(routes::protocols::start_protocol)
+            .service(routes::protocols::protocol_status)
+            .service(routes::protocols::continue_protocol)
+            .service(routes::protocols::stop_protocol)
             .service(routes::startnewlog::start_new_log)
+            .service(routes::session::start_session)
+            .service(routes::session::current_session)
+            .service(routes::session::end_session)
+            .service(routes::clock::clock_sync)
+            .service(routes::clock::set_clock_offset)
+            .service(routes::clock::clock_status)
+            .service(routes::diagnostics::timing_report)
+            .service(routes::diagnostics::timing_diagnostics)
+            .service(routes::diagnostics::self_test)
             .service(routes::batch_files::generate_batch_files)
             .service(routes::batch_files::generate_batch_files_async)
     })
     .bind(("0.0.0.0", PORT))? // bind to all interfaces
     .run()
-    .await
+    .await?;
+
+    // the server was stopped (eg. Ctrl+C): write the buffered log rows before exiting
+    flush_logs();
+    println!("Flushed the logs, bye!");
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    io::BufReader,
+};
+
+use rodio::{source::Buffered, Decoder, Source};
+
+use crate::audio::{audio_file_line, jump_line, parse_audio_file_line, parse_tone_spec, tone_spec, db_to_linear, Playlist, PlaylistTypes};
+use crate::logging::{csv_field, parse_csv_row};
+
+
+// Conversions between the native playlist format and M3U / M3U8, CSV (file, isi, gain) and PsychoPy conditions files
+// Only the audio file names are kept from the paths of the imported files: the files must be in the audio folder of the server
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PlaylistFormat {
+    M3u,
+    M3u8,
+    Csv, // file, isi (ms) and gain (dB) columns
+    Psychopy, // sound, isi (s) and volume (0-1) columns, as in a PsychoPy conditions file
+}
+
+impl PlaylistFormat {
+    pub fn parse(format: &str) -> Option<PlaylistFormat> {
+        match format.to_lowercase().as_str() {
+            "m3u" => Some(PlaylistFormat::M3u),
+            "m3u8" => Some(PlaylistFormat::M3u8),
+            "csv" => Some(PlaylistFormat::Csv),
+            "psychopy" => Some(PlaylistFormat::Psychopy),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "m3u",
+            PlaylistFormat::M3u8 => "m3u8",
+            PlaylistFormat::Csv => "csv",
+            PlaylistFormat::Psychopy => "psychopy",
+        }
+    }
+
+    // file name suffix of the exported files
+    pub fn file_suffix(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => ".m3u",
+            PlaylistFormat::M3u8 => ".m3u8",
+            PlaylistFormat::Csv => ".csv",
+            PlaylistFormat::Psychopy => "_conditions.csv",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "audio/x-mpegurl",
+            PlaylistFormat::M3u8 => "application/vnd.apple.mpegurl",
+            PlaylistFormat::Csv | PlaylistFormat::Psychopy => "text/csv",
+        }
+    }
+}
+
+// Column names of the CSV and PsychoPy tables. The first name is used for export, all of them are accepted for import (case insensitive)
+const CSV_FILE_COLUMNS: [&str; 4] = ["file", "filename", "stimulus", "sound"];
+const CSV_ISI_COLUMNS: [&str; 2] = ["isi", "isi_ms"];
+const CSV_GAIN_COLUMNS: [&str; 2] = ["gain", "gain_db"];
+const PSYCHOPY_FILE_COLUMNS: [&str; 4] = ["sound", "stimulus", "file", "filename"];
+const PSYCHOPY_ISI_COLUMNS: [&str; 2] = ["isi", "iti"];
+const PSYCHOPY_GAIN_COLUMNS: [&str; 1] = ["volume"];
+const BLOCK_COLUMN: &str = "block";
+
+
+// One row of the CSV and PsychoPy tables: a stimulus (or nothing, for a pause at the start of a block) and the silence after it
+struct TableRow {
+    block: String,
+    stimulus: String, // audio file name or tone spec, empty for a leading pause
+    isi: u32, // in milliseconds
+    gain: f32, // in dB
+}
+
+// Group the playlist items into rows: each stimulus with the pauses that follow it
+fn table_rows(items: &[PlaylistTypes], block: &str, rows: &mut Vec<TableRow>) {
+    let first_row = rows.len();
+    for item in items {
+        match item {
+            PlaylistTypes::AudioFiles(audio_file_name, gain) => rows.push(TableRow { block: block.to_string(), stimulus: audio_file_name.clone(), isi: 0, gain: *gain }),
+            PlaylistTypes::Tone(tone) => rows.push(TableRow { block: block.to_string(), stimulus: tone_spec(tone), isi: 0, gain: 0.0 }),
+            PlaylistTypes::Pause(pause_duration) => {
+                // the pause goes to the previous stimulus of the same block, if any
+                if rows.len() > first_row && rows[rows.len() - 1].block == block {
+                    let last_row = rows.len() - 1;
+                    rows[last_row].isi += pause_duration;
+                } else {
+                    rows.push(TableRow { block: block.to_string(), stimulus: String::new(), isi: *pause_duration, gain: 0.0 });
+                }
+            },
+            // the blocks of a playlist included in a block are trials of that block, as during playback
+            PlaylistTypes::Block(name, trials) => table_rows(trials, if block.is_empty() { name } else { block }, rows),
+            PlaylistTypes::Include(_, repeat, included_items) => {
+                for _ in 0..*repeat {
+                    table_rows(included_items, block, rows);
+                }
+            },
+            PlaylistTypes::ResponseWindow(_, _) => {}, // tables have no response window column
+            // the playlists with random groups or jumps are not exported as tables (see export_playlist)
+            PlaylistTypes::Random(_) | PlaylistTypes::Label(_) | PlaylistTypes::Jump(_, _) | PlaylistTypes::Branch(_) => {},
+        }
+    }
+}
+
+
+// Random groups and jumps (included playlists too), which have no equivalent in a table of trials
+fn has_branching(items: &[PlaylistTypes]) -> bool {
+    items.iter().any(|item| match item {
+        PlaylistTypes::Random(_) | PlaylistTypes::Label(_) | PlaylistTypes::Jump(_, _) => true,
+        PlaylistTypes::Block(_, items) | PlaylistTypes::Include(_, _, items) => has_branching(items),
+        _ => false,
+    })
+}
+
+// Export a playlist. The M3U files keep everything a media player can't play (header, blocks, pauses, tones, random groups, jumps) as "#" lines, so that they can be imported back
+// The playlists with random groups or jumps can only be exported as M3U
+pub fn export_playlist(playlist: &Playlist, format: PlaylistFormat, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Result<String, String> {
+    let mut output = String::new();
+
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => {
+            output.push_str("#EXTM3U\n");
+            for (key, value) in &playlist.header {
+                output.push_str(&format!("# {}: {}\n", key, value));
+            }
+            export_m3u_items(&playlist.items, false, audio_files, &mut output);
+        },
+        PlaylistFormat::Csv | PlaylistFormat::Psychopy => {
+            if has_branching(&playlist.items) {
+                return Err(format!("A playlist with random groups or jumps can't be exported as {} (use m3u)", format.name()));
+            }
+            let mut rows: Vec<TableRow> = Vec::new();
+            table_rows(&playlist.items, "", &mut rows);
+            let with_blocks = rows.iter().any(|row| !row.block.is_empty());
+
+            let header = match format {
+                PlaylistFormat::Csv => [CSV_FILE_COLUMNS[0], CSV_ISI_COLUMNS[0], CSV_GAIN_COLUMNS[0]],
+                _ => [PSYCHOPY_FILE_COLUMNS[0], PSYCHOPY_ISI_COLUMNS[0], PSYCHOPY_GAIN_COLUMNS[0]],
+            };
+            output.push_str(&header.join(","));
+            if with_blocks {
+                output.push_str(&format!(",{}", BLOCK_COLUMN));
+            }
+            output.push('\n');
+
+            for row in rows {
+                let (stimulus, isi, gain) = match format {
+                    PlaylistFormat::Csv => (row.stimulus, row.isi.to_string(), row.gain.to_string()),
+                    // PsychoPy: sound files relative to the experiment folder, ISI in seconds and linear volume
+                    _ => (
+                        if row.stimulus.is_empty() || parse_tone_spec(&row.stimulus).is_some() { row.stimulus } else { format!("audio/{}", row.stimulus) },
+                        (row.isi as f64 / 1000.0).to_string(),
+                        db_to_linear(row.gain).to_string(),
+                    ),
+                };
+                output.push_str(&format!("{},{},{}", csv_field(&stimulus), isi, gain));
+                if with_blocks {
+                    output.push_str(&format!(",{}", csv_field(&row.block)));
+                }
+                output.push('\n');
+            }
+        },
+    }
+
+    Ok(output)
+}
+
+fn export_m3u_items(items: &[PlaylistTypes], in_block: bool, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>, output: &mut String) {
+    for item in items {
+        match item {
+            PlaylistTypes::AudioFiles(audio_file_name, gain) => {
+                let duration = audio_files.get(audio_file_name).and_then(|source| source.total_duration()).map_or(-1.0, |duration| duration.as_secs_f64());
+                output.push_str(&format!("#EXTINF:{},{}\n", duration, audio_file_line(audio_file_name, *gain)));
+                output.push_str(&format!("audio/{}\n", audio_file_name));
+            },
+            PlaylistTypes::Pause(pause_duration) => output.push_str(&format!("#pause_{}ms\n", pause_duration)),
+            PlaylistTypes::Tone(tone) => output.push_str(&format!("#{}\n", tone_spec(tone))),
+            PlaylistTypes::Block(name, trials) => {
+                // the blocks of a playlist included in a block are trials of that block, as during playback
+                if !in_block {
+                    output.push_str(&format!("#[block {}]\n", name));
+                }
+                export_m3u_items(trials, true, audio_files, output);
+            },
+            // a media player can't include another playlist, so the included items are written out
+            PlaylistTypes::Include(_, repeat, included_items) => {
+                for _ in 0..*repeat {
+                    export_m3u_items(included_items, in_block, audio_files, output);
+                }
+            },
+            // all the options are written out, a media player plays them one after the other
+            PlaylistTypes::Random(options) => {
+                output.push_str("#[random]\n");
+                for (weight, option_items) in options {
+                    output.push_str(&format!("#[option {}]\n", weight));
+                    export_m3u_items(option_items, in_block, audio_files, output);
+                }
+                output.push_str("#[end random]\n");
+            },
+            PlaylistTypes::Label(label) => output.push_str(&format!("#label_{}\n", label)),
+            PlaylistTypes::Jump(label, condition) => output.push_str(&format!("#{}\n", jump_line(label, condition))),
+            PlaylistTypes::ResponseWindow(start, end) => output.push_str(&format!("#respond_{}-{}ms\n", start, end)),
+            PlaylistTypes::Branch(_) => {}, // only in the expanded steps
+        }
+    }
+}
+
+
+// Column names to look for in an imported table, the query parameters take precedence
+pub struct ImportColumns {
+    pub file: String,
+    pub isi: String,
+    pub gain: String,
+}
+
+// Import a playlist: returns the lines of the native playlist file, or the list of errors (eg. audio files not found on the server)
+pub fn import_playlist(content: &str, format: PlaylistFormat, columns: &ImportColumns, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Result<Vec<String>, Vec<String>> {
+    let content = content.trim_start_matches('\u{feff}'); // UTF-8 BOM, eg. from Excel
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => import_m3u(content, audio_files),
+        PlaylistFormat::Csv | PlaylistFormat::Psychopy => import_table(content, format, columns, audio_files),
+    }
+}
+
+// Audio file name from a path or URL of an imported file (eg. C:\stimuli\1000.wav, ../audio/1000.wav or file:///home/me/1000%20Hz.wav)
+fn audio_file_name_from_path(path: &str) -> String {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    name.replace("%20", " ")
+}
+
+fn import_m3u(content: &str, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Result<Vec<String>, Vec<String>> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+    let mut gain = 0.0; // from the "#EXTINF:{duration},{name} gain_{gain}dB" line of an exported playlist
+
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line == "#EXTM3U" {
+            continue;
+        }
+
+        if let Some(title) = line.strip_prefix("#EXTINF:") {
+            gain = title.split_once(',').and_then(|(_, title)| parse_audio_file_line(title.trim()).ok()).map_or(0.0, |(_, gain)| gain);
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            let comment = comment.trim();
+            if ["pause_", "tone_", "respond_", "label_", "jump_", "[block", "[random]", "[option", "[end random]"].iter().any(|prefix| comment.starts_with(prefix)) {
+                lines.push(comment.to_string()); // native lines of an exported playlist
+            } else if comment.split_once(": ").is_some() && !comment.starts_with("EXT") {
+                lines.push(format!("# {}", comment)); // header lines of an exported playlist
+            }
+            continue; // other M3U directives
+        }
+
+        let audio_file_name = audio_file_name_from_path(line);
+        if audio_files.contains_key(&audio_file_name) {
+            lines.push(audio_file_line(&audio_file_name, gain));
+        } else {
+            errors.push(format!("line {}: audio file {} not found", line_number + 1, audio_file_name));
+        }
+        gain = 0.0;
+    }
+
+    if errors.is_empty() { Ok(lines) } else { Err(errors) }
+}
+
+fn import_table(content: &str, format: PlaylistFormat, columns: &ImportColumns, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Result<Vec<String>, Vec<String>> {
+    let mut rows = content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let header: Vec<String> = match rows.next() {
+        Some((_, line)) => parse_csv_row(line).iter().map(|column| column.trim().to_lowercase()).collect(),
+        None => return Err(vec!["the file is empty".to_string()]),
+    };
+
+    // index of the first column found
+    let find_column = |requested: &str, defaults: &[&str]| -> Option<usize> {
+        if !requested.is_empty() {
+            return header.iter().position(|column| *column == requested.to_lowercase());
+        }
+        defaults.iter().find_map(|name| header.iter().position(|column| column == name))
+    };
+    let (file_defaults, isi_defaults, gain_defaults): (&[&str], &[&str], &[&str]) = match format {
+        PlaylistFormat::Csv => (&CSV_FILE_COLUMNS, &CSV_ISI_COLUMNS, &CSV_GAIN_COLUMNS),
+        _ => (&PSYCHOPY_FILE_COLUMNS, &PSYCHOPY_ISI_COLUMNS, &PSYCHOPY_GAIN_COLUMNS),
+    };
+    let file_column = match find_column(&columns.file, file_defaults) {
+        Some(column) => column,
+        None => return Err(vec![format!("no file column (use one of {} or ?file_column=)", file_defaults.join(", "))]),
+    };
+    let isi_column = find_column(&columns.isi, isi_defaults);
+    let gain_column = find_column(&columns.gain, gain_defaults);
+    let block_column = header.iter().position(|column| column == BLOCK_COLUMN);
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut errors: Vec<String> = Vec::new();
+    let mut current_block = String::new();
+
+    for (line_number, line) in rows {
+        let fields: Vec<String> = parse_csv_row(line).iter().map(|field| field.trim().to_string()).collect(); // spreadsheets may pad the cells
+        let field = |column: Option<usize>| column.and_then(|column| fields.get(column)).map_or("", |field| field.as_str());
+        let row = line_number + 1;
+
+        // a new block starts when the block column changes
+        let block = field(block_column);
+        if block != current_block {
+            if !block.is_empty() {
+                lines.push(format!("[block {}]", block));
+            }
+            current_block = block.to_string();
+        }
+
+        // CSV: isi in ms and gain in dB. PsychoPy: isi in seconds and linear volume
+        let isi = match (field(isi_column), format) {
+            ("", _) => Some(0),
+            (isi, PlaylistFormat::Csv) => isi.parse::<f64>().ok().filter(|isi| *isi >= 0.0).map(|isi| isi.round() as u32),
+            (isi, _) => isi.parse::<f64>().ok().filter(|isi| *isi >= 0.0).map(|isi| (isi * 1000.0).round() as u32),
+        };
+        let gain = match (field(gain_column), format) {
+            ("", _) => Some(0.0),
+            (gain, PlaylistFormat::Csv) => gain.parse::<f32>().ok().filter(|gain| gain.is_finite()),
+            (volume, _) => volume.parse::<f32>().ok().filter(|volume| *volume > 0.0 && volume.is_finite()).map(|volume| 20.0 * volume.log10()),
+        };
+        let (isi, gain) = match (isi, gain) {
+            (Some(isi), Some(gain)) => (isi, gain),
+            _ => {
+                errors.push(format!("row {}: invalid isi \"{}\" or gain \"{}\"", row, field(isi_column), field(gain_column)));
+                continue;
+            }
+        };
+
+        let stimulus = field(Some(file_column));
+        if !stimulus.is_empty() {
+            if let Some(mut tone) = parse_tone_spec(stimulus) {
+                tone.amplitude += gain; // the gain of a tone goes to its amplitude
+                lines.push(tone_spec(&tone));
+            } else {
+                let audio_file_name = audio_file_name_from_path(stimulus);
+                if audio_files.contains_key(&audio_file_name) {
+                    lines.push(audio_file_line(&audio_file_name, gain));
+                } else {
+                    errors.push(format!("row {}: audio file {} not found", row, audio_file_name));
+                }
+            }
+        }
+        if isi > 0 {
+            lines.push(format!("pause_{}ms", isi));
+        }
+    }
+
+    if errors.is_empty() { Ok(lines) } else { Err(errors) }
+}
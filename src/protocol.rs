@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::BufReader,
+};
+
+use actix_web::web;
+use rodio::{source::Buffered, Decoder};
+
+use crate::structs::{AudioFiles, PlaylistPlaybackOptions, TimeQuery, Tone, ProtocolStatus, RequestInfo};
+use crate::audio::{parse_tone_spec, tone_spec};
+use crate::routes::{play::play_audio_file, playlists::play_playlist, startnewlog::start_new_log_file, tone::play_tone_and_log};
+use crate::logging::{start_csv_log, log_row, csv_field};
+use crate::clock::monotonic_ns;
+use crate::session::register_log_file;
+use crate::{PLAYLISTS, PROTOCOL};
+
+
+// Experiment protocols: a sequence of steps read from ./protocols/{name}.txt, one per line, run one after the other:
+//   startnewlog                    --> start a new main log file (same as /startnewlog)
+//   playlist_{name}[.txt]          --> play a playlist (same as /playlist/{name}.txt)
+//   {audio_file}                   --> play an audio file (same as /play/{audio_file})
+//   tone_{freq}Hz_{duration}ms_{amplitude}dB_@{sample_rate}Hz --> play a tone (same as /tone/...)
+//   rest_{duration}ms              --> timed rest
+//   wait [message]                 --> gate: wait for /protocol/continue (eg. for the experimenter), the message is shown in /protocol/status
+// Lines starting with # are comments
+pub static PROTOCOLS_FOLDER: &str = "./protocols";
+
+#[derive(Clone)]
+pub enum ProtocolStep {
+    StartNewLog,
+    Playlist(String),
+    AudioFile(String),
+    Tone(Tone),
+    Rest(u32),
+    Wait(String),
+}
+
+impl ProtocolStep {
+    // The step as a protocol line
+    pub fn line(&self) -> String {
+        match self {
+            ProtocolStep::StartNewLog => "startnewlog".to_string(),
+            ProtocolStep::Playlist(playlist_file_name) => format!("playlist_{}", playlist_file_name),
+            ProtocolStep::AudioFile(audio_file_name) => audio_file_name.clone(),
+            ProtocolStep::Tone(tone) => tone_spec(tone),
+            ProtocolStep::Rest(duration) => format!("rest_{}ms", duration),
+            ProtocolStep::Wait(message) if message.is_empty() => "wait".to_string(),
+            ProtocolStep::Wait(message) => format!("wait {}", message),
+        }
+    }
+}
+
+// Read and validate a protocol file. The errors of all the lines are returned together
+pub fn load_protocol(protocol_name: &str, audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Result<Vec<ProtocolStep>, String> {
+    if protocol_name.is_empty() || protocol_name.contains(['/', '\\']) || protocol_name.starts_with('.') {
+        return Err(format!("Invalid protocol name \"{}\"", protocol_name));
+    }
+    let protocol_file_name = if protocol_name.ends_with(".txt") { protocol_name.to_string() } else { format!("{}.txt", protocol_name) };
+    let path = format!("{}/{}", PROTOCOLS_FOLDER, protocol_file_name);
+    let content = fs::read_to_string(&path).map_err(|_| format!("Protocol {} not found", path))?;
+
+    let playlists = PLAYLISTS.lock().unwrap();
+    let mut steps = Vec::new();
+    let mut errors = Vec::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // the step lines are written to the CSV logs
+        if line.contains(',') {
+            errors.push(format!("line {}: \"{}\" can't contain commas", line_number + 1, line));
+            continue;
+        }
+
+        let step = if audio_files.contains_key(line) {
+            // audio files come first, so that an audio file named like a tone, a rest or a playlist step is played
+            ProtocolStep::AudioFile(line.to_string())
+        } else if line == "startnewlog" {
+            ProtocolStep::StartNewLog
+        } else if line == "wait" || line.starts_with("wait ") {
+            ProtocolStep::Wait(line["wait".len()..].trim().to_string())
+        } else if let Some(playlist_name) = line.strip_prefix("playlist_") {
+            let playlist_file_name = if playlist_name.ends_with(".txt") { playlist_name.to_string() } else { format!("{}.txt", playlist_name) };
+            if !playlists.playlists.contains_key(&playlist_file_name) {
+                errors.push(format!("line {}: playlist {} not found", line_number + 1, playlist_file_name));
+                continue;
+            }
+            ProtocolStep::Playlist(playlist_file_name)
+        } else if line.starts_with("tone_") {
+            match parse_tone_spec(line) {
+                Some(tone) => ProtocolStep::Tone(tone),
+                None => {
+                    errors.push(format!("line {}: invalid tone \"{}\" (expected tone_{{freq}}Hz_{{duration}}ms_{{amplitude}}dB_@{{sample_rate}}Hz)", line_number + 1, line));
+                    continue;
+                }
+            }
+        } else if line.starts_with("rest_") {
+            match line.strip_prefix("rest_").and_then(|rest| rest.strip_suffix("ms")).and_then(|duration| duration.parse::<u32>().ok()) {
+                Some(duration) => ProtocolStep::Rest(duration),
+                None => {
+                    errors.push(format!("line {}: invalid rest \"{}\" (expected rest_{{duration}}ms)", line_number + 1, line));
+                    continue;
+                }
+            }
+        } else {
+            errors.push(format!("line {}: unknown step or audio file \"{}\"", line_number + 1, line));
+            continue;
+        };
+        steps.push(step);
+    }
+    drop(playlists); // release the lock on PLAYLISTS global
+
+    if !errors.is_empty() {
+        return Err(format!("Invalid protocol {}: {}", path, errors.join(" | ")));
+    }
+    if steps.is_empty() {
+        return Err(format!("Protocol {} has no steps", path));
+    }
+    Ok(steps)
+}
+
+
+// A protocol run, one at a time. The steps run in a background thread (see run_protocol)
+pub struct ProtocolRun {
+    pub name: String,
+    pub steps: Vec<ProtocolStep>,
+    pub step: usize, // index of the current step
+    pub state: String, // "running", "waiting" (for /protocol/continue), "finished", "stopped" or "failed"
+    pub continue_requested: bool,
+    pub stop_requested: bool,
+    pub error: Option<String>,
+    pub log_file: String,
+    pub started_ns: u128,
+    pub step_started_ns: u128,
+    pub ended_ns: Option<u128>, // once finished, stopped or failed
+}
+
+impl ProtocolRun {
+    pub fn is_active(&self) -> bool {
+        self.state == "running" || self.state == "waiting"
+    }
+
+    pub fn status(&self) -> ProtocolStatus {
+        let now_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        let current = self.steps.get(self.step).filter(|_| self.is_active());
+        ProtocolStatus {
+            protocol: self.name.clone(),
+            state: self.state.clone(),
+            step: if current.is_some() { Some(self.step + 1) } else { None },
+            step_count: self.steps.len(),
+            current_step: current.map(|step| step.line()),
+            message: match current {
+                Some(ProtocolStep::Wait(message)) if self.state == "waiting" => Some(message.clone()),
+                _ => None,
+            },
+            step_elapsed_ms: if current.is_some() { Some(now_ns.saturating_sub(self.step_started_ns) / 1_000_000) } else { None },
+            elapsed_ms: self.ended_ns.unwrap_or(now_ns).saturating_sub(self.started_ns) / 1_000_000,
+            error: self.error.clone(),
+            steps: self.steps.iter().map(|step| step.line()).collect(),
+            log_file: self.log_file.clone(),
+        }
+    }
+}
+
+
+// Protocol log: one row per step transition (started, finished, failed, ...) and per request (continue, stop)
+pub fn create_protocol_log(name: &str, start_step: usize, step_count: usize, time_ns: u128, client_time: &str, request: &RequestInfo) -> String {
+    let log_file = chrono::Utc::now().format(&format!("logs/log_protocol_{}_%Y%m%d-%H%M%S.csv", name.trim_end_matches(".txt"))).to_string();
+
+    start_csv_log(&log_file, "timestamp_audio,audio_filename,status,timestamp_client,step,step_line,duration_ms");
+    log_row(&log_file, &format!("{},{},success,{},,,", time_ns, csv_field(&format!("Received /protocol/start (protocol: {} | from step: {} | steps: {})", name, start_step, step_count)), csv_field(client_time)), monotonic_ns(), Some(request));
+    println!("\x1b[1m    Started new log file: ./{}\x1b[0m", log_file);
+    register_log_file(&log_file); // session metadata sidecar
+    log_file
+}
+
+
+pub fn now_ns() -> u128 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos()
+}
+
+// Run the steps of the protocol in PROTOCOL, from its current step, until the end, a failed step or /protocol/stop
+// A stop request is applied between two steps, during a rest, or during a wait
+pub fn run_protocol(audio_files: web::Data<AudioFiles>) {
+    loop {
+        // next step
+        let mut protocol = PROTOCOL.lock().unwrap();
+        let run = protocol.as_mut().unwrap();
+        let log_file = run.log_file.clone();
+        if run.stop_requested {
+            run.state = "stopped".to_string();
+            run.ended_ns = Some(now_ns());
+            log_row(&log_file, &format!("{},Stopped before step {},success,N/A,{},,", now_ns(), run.step + 1, run.step + 1), monotonic_ns(), None);
+            println!("\x1b[1m    Protocol {} stopped before step {}\x1b[0m", run.name, run.step + 1);
+            return;
+        }
+        let step_index = run.step;
+        let (step_line, step) = match run.steps.get(step_index) {
+            Some(step) => (step.line(), step.clone()),
+            None => {
+                run.state = "finished".to_string();
+                run.ended_ns = Some(now_ns());
+                let elapsed_ms = now_ns().saturating_sub(run.started_ns) / 1_000_000;
+                log_row(&log_file, &format!("{},{},success,N/A,,,{}", now_ns(), csv_field(&format!("End (protocol: {} | steps: {})", run.name, run.steps.len())), elapsed_ms), monotonic_ns(), None);
+                println!("\x1b[1m    Protocol {} finished ({} steps in {} ms)\x1b[0m", run.name, run.steps.len(), elapsed_ms);
+                return;
+            }
+        };
+        let step_started_ns = now_ns();
+        let step_started_monotonic = monotonic_ns();
+        run.step_started_ns = step_started_ns;
+        run.continue_requested = false;
+        run.state = if matches!(step, ProtocolStep::Wait(_)) { "waiting".to_string() } else { "running".to_string() };
+        let query = TimeQuery {
+            time: format!("protocol:{}", run.name), // the protocol name is logged as the client timestamp of the steps, like the schedules
+            ..Default::default()
+        };
+        let request = RequestInfo::internal(&query.time); // no HTTP request: the request path is the protocol
+        println!("\x1b[36m    Protocol {}: step {}/{} {}\x1b[0m", run.name, step_index + 1, run.steps.len(), step_line);
+        drop(protocol); // release the lock on PROTOCOL global during the step
+
+        let status = if matches!(step, ProtocolStep::Wait(_)) { "waiting" } else { "started" };
+        log_row(&log_file, &format!("{},{},success,N/A,{},{},", step_started_ns, csv_field(&format!("Step {} {} ({})", step_index + 1, status, step_line)), step_index + 1, csv_field(&step_line)), step_started_monotonic, None);
+
+        let result = match &step {
+            ProtocolStep::StartNewLog => {
+                start_new_log_file();
+                Ok(())
+            },
+            ProtocolStep::Playlist(playlist_file_name) => {
+                let response = play_playlist(playlist_file_name, &audio_files, &query, &PlaylistPlaybackOptions::default(), (step_started_ns, step_started_monotonic), None, &request);
+                if response.status().is_success() { Ok(()) } else { Err(format!("playlist {} failed ({})", playlist_file_name, response.status())) }
+            },
+            ProtocolStep::AudioFile(audio_file_name) => {
+                let response = play_audio_file(audio_file_name, &audio_files, &query, &request);
+                if response.status().is_success() { Ok(()) } else { Err(format!("audio file {} failed ({})", audio_file_name, response.status())) }
+            },
+            ProtocolStep::Tone(tone) => {
+                let response = play_tone_and_log(tone.clone(), &query, &request);
+                if response.status().is_success() { Ok(()) } else { Err(format!("{} failed ({})", tone_spec(tone), response.status())) }
+            },
+            ProtocolStep::Rest(duration) => {
+                // sleep in short chunks, so that a stop request ends the rest early
+                let rest_end_ns = step_started_ns + *duration as u128 * 1_000_000;
+                while now_ns() < rest_end_ns && !PROTOCOL.lock().unwrap().as_ref().unwrap().stop_requested {
+                    std::thread::sleep(std::time::Duration::from_millis(((rest_end_ns - now_ns()) / 1_000_000).clamp(1, 50) as u64));
+                }
+                Ok(())
+            },
+            ProtocolStep::Wait(_) => {
+                loop {
+                    let protocol = PROTOCOL.lock().unwrap();
+                    let run = protocol.as_ref().unwrap();
+                    if run.continue_requested || run.stop_requested {
+                        break;
+                    }
+                    drop(protocol);
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                }
+                Ok(())
+            },
+        };
+
+        let finished_ns = now_ns();
+        let finished_monotonic = monotonic_ns();
+        let duration_ms = finished_ns.saturating_sub(step_started_ns) / 1_000_000;
+        let mut protocol = PROTOCOL.lock().unwrap();
+        let run = protocol.as_mut().unwrap();
+        match result {
+            Ok(()) => {
+                let interrupted = run.stop_requested && !run.continue_requested && matches!(step, ProtocolStep::Rest(_) | ProtocolStep::Wait(_));
+                if interrupted {
+                    log_row(&log_file, &format!("{},{},success,N/A,{},{},{}", finished_ns, csv_field(&format!("Step {} interrupted ({})", step_index + 1, step_line)), step_index + 1, csv_field(&step_line), duration_ms), finished_monotonic, None);
+                    continue; // stopped at the top of the loop, at this step
+                }
+                let status = if matches!(step, ProtocolStep::Wait(_)) { "continued" } else { "finished" };
+                log_row(&log_file, &format!("{},{},success,N/A,{},{},{}", finished_ns, csv_field(&format!("Step {} {} ({})", step_index + 1, status, step_line)), step_index + 1, csv_field(&step_line), duration_ms), finished_monotonic, None);
+                run.step += 1;
+            },
+            Err(e) => {
+                run.state = "failed".to_string();
+                run.ended_ns = Some(finished_ns);
+                run.error = Some(format!("Step {} ({}): {}", step_index + 1, step_line, e));
+                log_row(&log_file, &format!("{},{},error,N/A,{},{},{}", finished_ns, csv_field(&format!("Step {} failed ({} | {})", step_index + 1, step_line, e)), step_index + 1, csv_field(&step_line), duration_ms), finished_monotonic, None);
+                println!("\x1b[2m    \x1b[31mProtocol {} failed at step {}: {}\x1b[0m", run.name, step_index + 1, e);
+                return;
+            },
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use std::{collections::HashMap, io::BufReader};
+
+use rand::{distributions::{Distribution, WeightedIndex}, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rodio::{source::Buffered, Decoder, Source};
+
+
+// All randomized routes (/play/random, /playlist/create, ...) draw from this RNG.
+// ChaCha8Rng is used instead of thread_rng / StdRng because its output is documented to be reproducible:
+// the same seed gives the same stream of numbers on every platform and every rand_chacha 0.3.x release.
+pub type SessionRng = ChaCha8Rng;
+pub const RNG_NAME: &str = "ChaCha8Rng";
+
+// Use the seed from the request if there is one, otherwise draw a fresh one.
+// Either way, the seed is logged so that the session can be reproduced later.
+pub fn resolve_seed(seed: Option<u64>) -> u64 {
+    seed.unwrap_or_else(rand::random::<u64>)
+}
+
+pub fn seeded_rng(seed: u64) -> SessionRng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
+// HashMap iteration order is different every time the server starts,
+// so the file names must be sorted before drawing from them, otherwise the same seed would not give the same order.
+pub fn sorted_audio_file_names(audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> Vec<String> {
+    let mut names: Vec<String> = audio_files.keys().cloned().collect();
+    names.sort();
+    names
+}
+
+// Duration of each audio file in milliseconds (0 if unknown), for the sequences limited by max_duration
+pub fn audio_file_durations(audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> HashMap<String, u128> {
+    audio_files.iter()
+        .map(|(name, source)| (name.clone(), source.total_duration().map_or(0, |duration| duration.as_millis())))
+        .collect()
+}
+
+
+// How files are drawn when generating a random sequence
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sampling {
+    Replacement, // every draw is independent (default)
+    WithoutReplacement, // every file is used once before any file is used again
+    Balanced, // every file is used the same number of times (+/- 1), or in proportion to its weight
+}
+
+impl Sampling {
+    pub fn parse(sampling: &str) -> Result<Sampling, String> {
+        match sampling {
+            "" | "replacement" => Ok(Sampling::Replacement),
+            "without_replacement" => Ok(Sampling::WithoutReplacement),
+            "balanced" => Ok(Sampling::Balanced),
+            _ => Err(format!("Unknown sampling \"{}\". Use replacement, without_replacement or balanced", sampling)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sampling::Replacement => "replacement",
+            Sampling::WithoutReplacement => "without_replacement",
+            Sampling::Balanced => "balanced",
+        }
+    }
+}
+
+// Constraints applied when generating a random sequence of audio files
+pub struct SequenceConstraints {
+    pub sampling: Sampling,
+    pub max_repeats: u32, // maximum number of times the same file can play in a row, 0 = no limit
+    pub min_distance: u32, // minimum number of other files between two plays of the same file, 0 = no limit
+    pub weights: HashMap<String, f64>, // per-file weights, files not listed have a weight of 1
+}
+
+impl SequenceConstraints {
+    // Build the constraints from the raw query parameters
+    // weights are formatted as "file_a.wav:2,file_b.wav:0.5"
+    pub fn from_query(sampling: &str, max_repeats: u32, min_distance: u32, weights: &str, audio_file_names: &[String]) -> Result<SequenceConstraints, String> {
+        let sampling = Sampling::parse(sampling)?;
+
+        let mut parsed_weights = HashMap::new();
+        for weight in weights.split(',') {
+            let weight = weight.trim();
+            if weight.is_empty() {
+                continue;
+            }
+
+            let (file_name, value) = match weight.rsplit_once(':') {
+                Some(pair) => pair,
+                None => return Err(format!("Invalid weight \"{}\". Use file_name:weight", weight)),
+            };
+            if !audio_file_names.iter().any(|name| name == file_name) {
+                return Err(format!("Invalid weight: audio file \"{}\" not found", file_name));
+            }
+            let value = match value.parse::<f64>() {
+                Ok(value) if value >= 0.0 && value.is_finite() => value,
+                _ => return Err(format!("Invalid weight \"{}\" for {}. Weights must be positive numbers", value, file_name)),
+            };
+            parsed_weights.insert(file_name.to_string(), value);
+        }
+
+        // each weight is finite, but their sum can still overflow (the files without a weight count as 1)
+        let total_weight: f64 = audio_file_names.iter().map(|name| *parsed_weights.get(name).unwrap_or(&1.0)).sum();
+        if !total_weight.is_finite() {
+            return Err("Invalid weights: their total is too large".to_string());
+        }
+
+        Ok(SequenceConstraints { sampling, max_repeats, min_distance, weights: parsed_weights })
+    }
+
+    // Short description for the log files and the playlist header
+    pub fn describe(&self) -> String {
+        let mut weights: Vec<String> = self.weights.iter().map(|(name, weight)| format!("{}:{}", name, weight)).collect();
+        weights.sort();
+        format!("sampling: {} | max_repeats: {} | min_distance: {} | weights: {}", self.sampling.name(), self.max_repeats, self.min_distance, if weights.is_empty() { "none".to_string() } else { weights.join(" ") })
+    }
+}
+
+// Number of times the generation is restarted from scratch before giving up on the constraints
+const MAX_SEQUENCE_ATTEMPTS: u32 = 200;
+
+// Generate a random sequence of `count` audio file names that satisfies the constraints.
+// The draws are made one by one, only among the files that are still allowed at that position.
+// If a position has no allowed file left, the whole sequence is drawn again (the RNG keeps going, so this stays reproducible).
+// Returns an error if the constraints can't be met.
+pub fn constrained_sequence(audio_file_names: &[String], count: usize, constraints: &SequenceConstraints, rng: &mut SessionRng) -> Result<Vec<String>, String> {
+    // files with a weight of 0 are never played
+    let pool: Vec<(&String, f64)> = audio_file_names.iter()
+        .map(|name| (name, *constraints.weights.get(name).unwrap_or(&1.0)))
+        .filter(|(_, weight)| *weight > 0.0)
+        .collect();
+
+    if pool.is_empty() {
+        return Err("No audio file left to choose from (all weights are 0)".to_string());
+    }
+
+    for _ in 0..MAX_SEQUENCE_ATTEMPTS {
+        if let Some(sequence) = try_constrained_sequence(&pool, count, constraints, rng) {
+            return Ok(sequence.into_iter().map(|i| pool[i].0.clone()).collect());
+        }
+    }
+
+    Err(format!(
+        "Could not generate {} files from {} audio files with these constraints ({}) after {} attempts. Try relaxing max_repeats / min_distance, or adding more audio files.",
+        count, pool.len(), constraints.describe(), MAX_SEQUENCE_ATTEMPTS
+    ))
+}
+
+fn try_constrained_sequence(pool: &[(&String, f64)], count: usize, constraints: &SequenceConstraints, rng: &mut SessionRng) -> Option<Vec<usize>> {
+    // remaining number of plays per file (only for the sampling without replacement)
+    let mut remaining: Vec<u32> = match constraints.sampling {
+        Sampling::Replacement => Vec::new(),
+        Sampling::WithoutReplacement => vec![1; pool.len()],
+        Sampling::Balanced => balanced_counts(pool, count),
+    };
+
+    let mut sequence: Vec<usize> = Vec::with_capacity(count);
+    for _ in 0..count {
+        // without replacement: start a new round once every file has been used
+        if constraints.sampling == Sampling::WithoutReplacement && remaining.iter().all(|r| *r == 0) {
+            remaining = vec![1; pool.len()];
+        }
+
+        let candidate_weights: Vec<f64> = (0..pool.len())
+            .map(|i| {
+                if !remaining.is_empty() && remaining[i] == 0 {
+                    return 0.0;
+                }
+                if constraints.max_repeats > 0 && sequence.len() >= constraints.max_repeats as usize
+                    && sequence.iter().rev().take(constraints.max_repeats as usize).all(|j| *j == i) {
+                    return 0.0;
+                }
+                if constraints.min_distance > 0 && sequence.iter().rev().take(constraints.min_distance as usize).any(|j| *j == i) {
+                    return 0.0;
+                }
+                // balanced: favor the files with the most plays left, so that the last positions are not all the same file
+                match constraints.sampling {
+                    Sampling::Balanced => remaining[i] as f64,
+                    _ => pool[i].1,
+                }
+            })
+            .collect();
+
+        let distribution = WeightedIndex::new(&candidate_weights).ok()?; // no allowed file at this position
+        let chosen = distribution.sample(rng);
+        if !remaining.is_empty() {
+            remaining[chosen] -= 1;
+        }
+        sequence.push(chosen);
+    }
+
+    Some(sequence)
+}
+
+// Split `count` plays among the files in proportion to their weights (largest remainder method)
+fn balanced_counts(pool: &[(&String, f64)], count: usize) -> Vec<u32> {
+    let total_weight: f64 = pool.iter().map(|(_, weight)| weight).sum();
+    let quotas: Vec<f64> = pool.iter().map(|(_, weight)| count as f64 * weight / total_weight).collect();
+    let mut counts: Vec<u32> = quotas.iter().map(|quota| quota.floor() as u32).collect();
+
+    let mut by_remainder: Vec<usize> = (0..pool.len()).collect();
+    by_remainder.sort_by(|a, b| (quotas[*b] - quotas[*b].floor()).total_cmp(&(quotas[*a] - quotas[*a].floor())).then(a.cmp(b)));
+
+    let assigned: u32 = counts.iter().sum();
+    for i in by_remainder.into_iter().take(count - assigned as usize) {
+        counts[i] += 1;
+    }
+
+    counts
+}
+
+
+// Cap for the number of files drawn to fill max_duration, in case of (nearly) empty audio files
+const MAX_FILE_COUNT_FOR_DURATION: usize = 100_000;
+
+// Random sequence limited by max_duration (ms), with break_between_files (ms) between the files, and by max_count files (0 = no count limit)
+// As many files as could fit are drawn, the ones that fit are kept, and then only that many files are drawn again: so the sampling
+// (balanced, without replacement) holds over the files that are actually played, not over a longer draw cut at max_duration.
+// Repeated until a whole draw fits (the count only goes down). Empty if no drawn file fits
+pub fn sequence_for_duration(audio_file_names: &[String], file_durations: &HashMap<String, u128>, max_count: u32, max_duration: u32, break_between_files: u32, constraints: &SequenceConstraints, rng: &mut SessionRng) -> Result<Vec<String>, String> {
+    let mut count = if max_count != 0 {
+        max_count as usize
+    } else {
+        max_count_for_duration(audio_file_names, file_durations, max_duration, break_between_files, constraints)
+    };
+
+    loop {
+        let sequence = constrained_sequence(audio_file_names, count, constraints, rng)?;
+        let fitting_count = count_within_duration(&sequence, file_durations, max_duration, break_between_files);
+        if fitting_count == count {
+            return Ok(sequence);
+        }
+        count = fitting_count;
+    }
+}
+
+// Upper bound on the number of files that can fit in max_duration: the shortest file that can be drawn (weight above 0), with a break after each
+fn max_count_for_duration(audio_file_names: &[String], file_durations: &HashMap<String, u128>, max_duration: u32, break_between_files: u32, constraints: &SequenceConstraints) -> usize {
+    let shortest = audio_file_names.iter()
+        .filter(|name| *constraints.weights.get(*name).unwrap_or(&1.0) > 0.0)
+        .map(|name| *file_durations.get(name).unwrap_or(&0))
+        .min()
+        .unwrap_or(0);
+
+    let file_count = max_duration as u128 / (shortest + break_between_files as u128).max(1) + 1;
+    file_count.min(MAX_FILE_COUNT_FOR_DURATION as u128) as usize
+}
+
+// Number of files at the start of a sequence that fit in max_duration (ms), with break_between_files (ms) between them
+fn count_within_duration(sequence: &[String], file_durations: &HashMap<String, u128>, max_duration: u32, break_between_files: u32) -> usize {
+    let mut total_duration: u128 = 0;
+    for (i, name) in sequence.iter().enumerate() {
+        let break_duration = if i > 0 { break_between_files as u128 } else { 0 };
+        total_duration += break_duration + *file_durations.get(name).unwrap_or(&0);
+        if total_duration > max_duration as u128 {
+            return i;
+        }
+    }
+    sequence.len()
+}
+
+
+// Oddball sequence: for each trial, None is the standard and Some(i) is the deviant i.
+// The first `initial_standards` trials are always standards, and at least `min_standards` standards follow each deviant.
+// On every other trial, deviant i is drawn with probability deviant_probabilities[i] (so the actual deviant count can be a bit lower than the nominal one).
+pub fn oddball_sequence(trial_count: u32, deviant_probabilities: &[f64], min_standards: u32, initial_standards: u32, rng: &mut SessionRng) -> Vec<Option<usize>> {
+    let mut sequence: Vec<Option<usize>> = Vec::with_capacity(trial_count as usize);
+    let mut standards_since_deviant: Option<u32> = None; // None until the first deviant
+
+    for trial in 0..trial_count {
+        let deviant_allowed = trial >= initial_standards && standards_since_deviant.is_none_or(|count| count >= min_standards);
+
+        let mut deviant = None;
+        if deviant_allowed {
+            let draw = rng.gen::<f64>();
+            let mut cumulative = 0.0;
+            for (i, probability) in deviant_probabilities.iter().enumerate() {
+                cumulative += probability;
+                if draw < cumulative {
+                    deviant = Some(i);
+                    break;
+                }
+            }
+        }
+
+        standards_since_deviant = match deviant {
+            Some(_) => Some(0),
+            None => standards_since_deviant.map(|count| count + 1),
+        };
+        sequence.push(deviant);
+    }
+
+    sequence
+}
+
+
+// Number of rows of a Latin square of n conditions.
+// A balanced Latin square (Williams design) needs 2n rows when n is odd: the n rows, then the same rows reversed.
+pub fn latin_square_row_count(n: usize, balanced: bool) -> usize {
+    if balanced && n % 2 == 1 { 2 * n } else { n }
+}
+
+// Condition order (indices 0..n) for one row of a Latin square (row starts at 0, and wraps around the row count)
+// Plain Latin square: row r is r, r+1, ..., r+n-1 (mod n)
+// Balanced Latin square: row r is r, r+1, r-1, r+2, r-2, ... (mod n), so every condition follows every other condition equally often
+pub fn latin_square_row(n: usize, row: usize, balanced: bool) -> Vec<usize> {
+    let row = row % latin_square_row_count(n, balanced);
+
+    if !balanced {
+        return (0..n).map(|j| (row + j) % n).collect();
+    }
+
+    let base_row = row % n;
+    let mut order: Vec<usize> = (0..n)
+        .map(|j| if j % 2 == 1 { (base_row + j.div_ceil(2)) % n } else { (base_row + n - j / 2) % n })
+        .collect();
+
+    // odd n: the second half of the rows are the first half reversed
+    if row >= n {
+        order.reverse();
+    }
+
+    order
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn constraints(sampling: &str, max_repeats: u32, min_distance: u32, weights: &str, audio_file_names: &[String]) -> SequenceConstraints {
+        SequenceConstraints::from_query(sampling, max_repeats, min_distance, weights, audio_file_names).unwrap()
+    }
+
+    fn count_of(sequence: &[String], name: &str) -> usize {
+        sequence.iter().filter(|item| *item == name).count()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_sequence() {
+        let names = file_names(&["a.wav", "b.wav", "c.wav"]);
+        let constraints = constraints("", 2, 0, "", &names);
+        let first = constrained_sequence(&names, 50, &constraints, &mut seeded_rng(42)).unwrap();
+        let second = constrained_sequence(&names, 50, &constraints, &mut seeded_rng(42)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 50);
+    }
+
+    #[test]
+    fn max_repeats_limits_the_runs() {
+        let names = file_names(&["a.wav", "b.wav"]);
+        let constraints = constraints("", 1, 0, "", &names);
+        for seed in 0..20 {
+            let sequence = constrained_sequence(&names, 40, &constraints, &mut seeded_rng(seed)).unwrap();
+            assert!(sequence.windows(2).all(|pair| pair[0] != pair[1]), "{:?}", sequence);
+        }
+    }
+
+    #[test]
+    fn min_distance_keeps_other_files_in_between() {
+        let names = file_names(&["a.wav", "b.wav", "c.wav", "d.wav"]);
+        let constraints = constraints("", 0, 2, "", &names);
+        let sequence = constrained_sequence(&names, 60, &constraints, &mut seeded_rng(7)).unwrap();
+        assert!(sequence.windows(3).all(|window| window[0] != window[1] && window[0] != window[2] && window[1] != window[2]), "{:?}", sequence);
+    }
+
+    #[test]
+    fn without_replacement_plays_every_file_once_per_round() {
+        let names = file_names(&["a.wav", "b.wav", "c.wav"]);
+        let constraints = constraints("without_replacement", 0, 0, "", &names);
+        let sequence = constrained_sequence(&names, 9, &constraints, &mut seeded_rng(3)).unwrap();
+        for round in sequence.chunks(3) {
+            let mut round = round.to_vec();
+            round.sort();
+            assert_eq!(round, names);
+        }
+    }
+
+    #[test]
+    fn balanced_follows_the_weights() {
+        let names = file_names(&["a.wav", "b.wav", "c.wav"]);
+        let constraints = constraints("balanced", 0, 0, "a.wav:2,c.wav:0", &names);
+        let sequence = constrained_sequence(&names, 30, &constraints, &mut seeded_rng(11)).unwrap();
+        assert_eq!(count_of(&sequence, "a.wav"), 20);
+        assert_eq!(count_of(&sequence, "b.wav"), 10);
+        assert_eq!(count_of(&sequence, "c.wav"), 0);
+    }
+
+    #[test]
+    fn balanced_splits_the_remainder() {
+        let names = file_names(&["a.wav", "b.wav", "c.wav"]);
+        let constraints = constraints("balanced", 0, 0, "", &names);
+        let sequence = constrained_sequence(&names, 10, &constraints, &mut seeded_rng(5)).unwrap();
+        let mut counts: Vec<usize> = names.iter().map(|name| count_of(&sequence, name)).collect();
+        counts.sort();
+        assert_eq!(counts, vec![3, 3, 4]);
+    }
+
+    #[test]
+    fn impossible_constraints_are_an_error() {
+        let names = file_names(&["a.wav", "b.wav"]);
+        let constraints = constraints("", 0, 2, "", &names); // 2 other files between two plays of the same file, with 2 files
+        assert!(constrained_sequence(&names, 5, &constraints, &mut seeded_rng(1)).is_err());
+
+        let constraints = SequenceConstraints::from_query("", 0, 0, "a.wav:0,b.wav:0", &names).unwrap();
+        assert!(constrained_sequence(&names, 5, &constraints, &mut seeded_rng(1)).is_err());
+    }
+
+    #[test]
+    fn invalid_query_constraints_are_rejected() {
+        let names = file_names(&["a.wav", "b.wav"]);
+        assert!(SequenceConstraints::from_query("shuffle", 0, 0, "", &names).is_err());
+        assert!(SequenceConstraints::from_query("", 0, 0, "a.wav", &names).is_err());
+        assert!(SequenceConstraints::from_query("", 0, 0, "x.wav:1", &names).is_err());
+        assert!(SequenceConstraints::from_query("", 0, 0, "a.wav:-1", &names).is_err());
+        assert!(SequenceConstraints::from_query("", 0, 0, "a.wav:NaN", &names).is_err());
+        assert!(SequenceConstraints::from_query("", 0, 0, "a.wav:1e308,b.wav:1e308", &names).is_err());
+
+        let constraints = SequenceConstraints::from_query("balanced", 1, 0, " a.wav:2 ,", &names).unwrap();
+        assert_eq!(constraints.describe(), "sampling: balanced | max_repeats: 1 | min_distance: 0 | weights: a.wav:2");
+    }
+
+    #[test]
+    fn latin_square_rows() {
+        assert_eq!(latin_square_row(3, 0, false), vec![0, 1, 2]);
+        assert_eq!(latin_square_row(3, 1, false), vec![1, 2, 0]);
+        assert_eq!(latin_square_row(3, 4, false), vec![1, 2, 0]); // wraps around the 3 rows
+
+        assert_eq!(latin_square_row(4, 0, true), vec![0, 1, 3, 2]);
+        assert_eq!(latin_square_row(4, 1, true), vec![1, 2, 0, 3]);
+        assert_eq!(latin_square_row(3, 0, true), vec![0, 1, 2]);
+        assert_eq!(latin_square_row(3, 3, true), vec![2, 1, 0]); // odd n: the first rows reversed
+        assert_eq!(latin_square_row_count(3, true), 6);
+        assert_eq!(latin_square_row_count(4, true), 4);
+    }
+
+    #[test]
+    fn balanced_latin_square_is_carry_over_balanced() {
+        for n in 2..8 {
+            let rows: Vec<Vec<usize>> = (0..latin_square_row_count(n, true)).map(|row| latin_square_row(n, row, true)).collect();
+            // every condition once per row, and once per position over the rows
+            for row in &rows {
+                let mut sorted = row.clone();
+                sorted.sort();
+                assert_eq!(sorted, (0..n).collect::<Vec<usize>>());
+            }
+            let expected = if n % 2 == 1 { 2 } else { 1 };
+            for position in 0..n {
+                for condition in 0..n {
+                    assert_eq!(rows.iter().filter(|row| row[position] == condition).count(), expected);
+                }
+            }
+            // every condition follows every other condition equally often
+            let mut follows = vec![vec![0; n]; n];
+            for row in &rows {
+                for pair in row.windows(2) {
+                    follows[pair[0]][pair[1]] += 1;
+                }
+            }
+            for (a, row) in follows.iter().enumerate() {
+                for (b, count) in row.iter().enumerate() {
+                    assert_eq!(*count, if a == b { 0 } else { expected }, "n = {}: {} --> {}", n, a, b);
+                }
+            }
+        }
+    }
+
+    fn durations(durations: &[(&str, u128)]) -> HashMap<String, u128> {
+        durations.iter().map(|(name, duration)| (name.to_string(), *duration)).collect()
+    }
+
+    #[test]
+    fn sequence_for_duration_is_balanced_over_the_files_that_fit() {
+        let names = file_names(&["a.wav", "b.wav", "c.wav"]);
+        let file_durations = durations(&[("a.wav", 100), ("b.wav", 300), ("c.wav", 500)]);
+        let constraints = constraints("balanced", 0, 0, "", &names);
+        for seed in 0..20 {
+            let sequence = sequence_for_duration(&names, &file_durations, 0, 10_000, 50, &constraints, &mut seeded_rng(seed)).unwrap();
+            let total: u128 = sequence.iter().map(|name| file_durations[name]).sum::<u128>() + 50 * (sequence.len() as u128 - 1);
+            assert!(total <= 10_000, "{} ms", total);
+            let counts: Vec<usize> = names.iter().map(|name| count_of(&sequence, name)).collect();
+            assert!(counts.iter().max().unwrap() - counts.iter().min().unwrap() <= 1, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn sequence_for_duration_ignores_the_files_that_are_never_drawn() {
+        // the empty file has a weight of 0: the draw is sized on the 1 s file, not on the empty one
+        let names = file_names(&["empty.wav", "long.wav"]);
+        let file_durations = durations(&[("empty.wav", 0), ("long.wav", 1000)]);
+        let constraints = constraints("", 0, 0, "empty.wav:0", &names);
+        assert_eq!(max_count_for_duration(&names, &file_durations, 5000, 0, &constraints), 6);
+        let sequence = sequence_for_duration(&names, &file_durations, 0, 5000, 0, &constraints, &mut seeded_rng(1)).unwrap();
+        assert_eq!(sequence, vec!["long.wav".to_string(); 5]);
+    }
+
+    #[test]
+    fn sequence_for_duration_stops_at_the_first_limit() {
+        let names = file_names(&["a.wav", "b.wav"]);
+        let file_durations = durations(&[("a.wav", 1000), ("b.wav", 1000)]);
+        let constraints = constraints("", 0, 0, "", &names);
+        assert_eq!(sequence_for_duration(&names, &file_durations, 3, 60_000, 0, &constraints, &mut seeded_rng(1)).unwrap().len(), 3);
+        assert_eq!(sequence_for_duration(&names, &file_durations, 10, 2500, 500, &constraints, &mut seeded_rng(1)).unwrap().len(), 2);
+        assert!(sequence_for_duration(&names, &file_durations, 0, 999, 0, &constraints, &mut seeded_rng(1)).unwrap().is_empty());
+    }
+}
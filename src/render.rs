@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    io::{BufReader, Cursor},
+};
+
+use rodio::{
+    source::{Buffered, UniformSourceIterator},
+    Decoder,
+    Sample,
+    Source,
+};
+
+use crate::audio::{tone_spec, tone_to_source, audio_file_line, db_to_linear, playlist_item_duration, PlaylistStep, PlaylistTypes};
+use crate::logging::csv_field;
+
+
+// Offline rendering of a playlist into a single audio file, with the onset of every item in samples (frames)
+// The items are converted to the output sample rate and channel count by rodio, the same way the Sink does it for the output device during live playback
+
+// The render is built in memory (32-bit float samples): 30 minutes of 48 kHz stereo (about 700 MB) at most
+pub static MAX_RENDER_SAMPLES: u64 = 48_000 * 2 * 60 * 30;
+
+pub enum RenderFormat {
+    Wav, // 32-bit float, like the tones of /save_tone
+    Flac, // 24-bit integer
+}
+
+impl RenderFormat {
+    pub fn parse(format: &str) -> Option<RenderFormat> {
+        match format.to_lowercase().as_str() {
+            "" | "wav" => Some(RenderFormat::Wav),
+            "flac" => Some(RenderFormat::Flac),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RenderFormat::Wav => "wav",
+            RenderFormat::Flac => "flac",
+        }
+    }
+
+    // the FLAC encoder only writes streamable-subset files, up to 96 kHz
+    pub fn max_sample_rate(&self) -> u32 {
+        match self {
+            RenderFormat::Wav => 384_000,
+            RenderFormat::Flac => 96_000,
+        }
+    }
+}
+
+// One rendered item: its position in the output, in frames (one sample per channel)
+pub struct RenderedItem {
+    pub onset: u64,
+    pub length: u64,
+    pub name: String, // same as the audio_filename column of the playlist logs
+    pub log_columns: String, // block, block_name, trial and source_playlist columns of the playlist logs
+}
+
+pub struct Render {
+    pub samples: Vec<f32>, // interleaved
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub items: Vec<RenderedItem>,
+    pub ended_by: &'static str, // "completion" or "duration" (max_duration reached), as in the playlist logs
+}
+
+// Highest sample rate and channel count of the items, used when the request doesn't set them
+pub fn default_render_format(steps: &[PlaylistStep], audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>) -> (u32, u16) {
+    let mut sample_rate = 0;
+    let mut channels = 1;
+    for step in steps {
+        match &step.item {
+            PlaylistTypes::AudioFiles(audio_file_name, _) => {
+                if let Some(source) = audio_files.get(audio_file_name) {
+                    sample_rate = sample_rate.max(source.sample_rate());
+                    channels = channels.max(source.channels());
+                }
+            },
+            PlaylistTypes::Tone(tone) => sample_rate = sample_rate.max(tone.sample_rate),
+            _ => {}
+        }
+    }
+    if sample_rate == 0 {
+        sample_rate = 48000; // only pauses
+    }
+    (sample_rate, channels)
+}
+
+// Convert a source to the output sample rate and channel count
+fn convert_source<S>(source: S, channels: u16, sample_rate: u32) -> Vec<f32>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    UniformSourceIterator::<S, f32>::new(source, channels, sample_rate).collect()
+}
+
+// Render the playlist steps one after the other, pauses included
+// With max_duration (ms), the render stops before an item that would not finish within max_duration, as the live playback does
+// Renders longer than MAX_RENDER_SAMPLES (estimated from the nominal durations of the items) are rejected before anything is rendered
+pub fn render_steps(steps: &[PlaylistStep], audio_files: &HashMap<String, Buffered<Decoder<BufReader<std::fs::File>>>>, channels: u16, sample_rate: u32, max_duration: u32) -> Result<Render, String> {
+    let mut duration_ms: u128 = steps.iter().map(|step| playlist_item_duration(&step.item, audio_files)).sum();
+    if max_duration > 0 {
+        duration_ms = duration_ms.min(max_duration as u128);
+    }
+    let estimated_frames = duration_ms * sample_rate as u128 / 1000;
+    if estimated_frames * channels as u128 > MAX_RENDER_SAMPLES as u128 {
+        return Err(format!(
+            "The render would be too large ({} frames of {} channel(s), about {:.1} minutes at {} Hz). Renders are limited to {} samples: use max_duration, or a lower sample_rate or fewer channels",
+            estimated_frames, channels, duration_ms as f64 / 60_000.0, sample_rate, MAX_RENDER_SAMPLES
+        ));
+    }
+
+    let mut render = Render { samples: Vec::new(), channels, sample_rate, items: Vec::new(), ended_by: "completion" };
+
+    for step in steps {
+        let (name, samples) = match &step.item {
+            PlaylistTypes::AudioFiles(audio_file_name, gain) => {
+                let source = audio_files.get(audio_file_name).unwrap().clone().amplify(db_to_linear(*gain));
+                (audio_file_line(audio_file_name, *gain), convert_source(source, channels, sample_rate))
+            },
+            PlaylistTypes::Tone(tone) => {
+                let source: Buffered<Decoder<BufReader<Cursor<Vec<u8>>>>> = tone_to_source(&tone.freq, &tone.duration, &tone.amplitude, &tone.sample_rate);
+                (tone_spec(tone), convert_source(source, channels, sample_rate))
+            },
+            PlaylistTypes::Pause(pause_duration) => {
+                let frames = (*pause_duration as u64 * sample_rate as u64 + 500) / 1000;
+                (format!("pause_{}ms", pause_duration), vec![0.0; frames as usize * channels as usize])
+            },
+            PlaylistTypes::Branch(branch) => (branch.clone(), Vec::new()), // logged, with no length
+            PlaylistTypes::ResponseWindow(_, _) => continue, // part of the previous stimulus
+            PlaylistTypes::Label(_) | PlaylistTypes::Jump(_, _) => continue, // the playlist variables are only set during live playback, so the jumps are not taken
+            PlaylistTypes::Block(_, _) | PlaylistTypes::Include(_, _, _) | PlaylistTypes::Random(_) => continue, // expanded by expand_playlist
+        };
+
+        let onset = (render.samples.len() / channels as usize) as u64;
+        let length = (samples.len() / channels as usize) as u64;
+        if max_duration > 0 && (onset + length) * 1000 > max_duration as u64 * sample_rate as u64 {
+            render.ended_by = "duration";
+            break;
+        }
+
+        render.samples.extend(samples);
+        render.items.push(RenderedItem { onset, length, name, log_columns: step.log_columns() });
+    }
+
+    Ok(render)
+}
+
+// Encode the render as a WAV (32-bit float) or FLAC (24-bit) file in memory
+pub fn encode_render(render: &Render, format: &RenderFormat) -> Result<Vec<u8>, String> {
+    match format {
+        RenderFormat::Wav => {
+            let mut cursor = Cursor::new(Vec::new());
+            let spec = hound::WavSpec {
+                channels: render.channels,
+                sample_rate: render.sample_rate,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            {
+                let mut writer = hound::WavWriter::new(&mut cursor, spec).map_err(|e| e.to_string())?;
+                for sample in &render.samples {
+                    writer.write_sample(*sample).map_err(|e| e.to_string())?;
+                }
+                writer.finalize().map_err(|e| e.to_string())?;
+            }
+            Ok(cursor.into_inner())
+        },
+        RenderFormat::Flac => {
+            use flacenc::component::BitRepr;
+            use flacenc::error::Verify;
+
+            let samples: Vec<i32> = render.samples.iter().map(|sample| (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32).collect();
+            let config = flacenc::config::Encoder::default().into_verified().map_err(|(_, e)| e.to_string())?;
+            let source = flacenc::source::MemSource::from_samples(&samples, render.channels as usize, 24, render.sample_rate as usize);
+            let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size).map_err(|e| format!("{:?}", e))?;
+
+            let mut sink = flacenc::bitsink::ByteSink::new();
+            stream.write(&mut sink).map_err(|e| e.to_string())?;
+            Ok(sink.as_slice().to_vec())
+        },
+    }
+}
+
+// Sidecar CSV: onset and length of every item, in frames and in milliseconds from the start of the rendered file
+pub fn render_onsets_csv(render: &Render) -> String {
+    let mut csv = String::from("onset_sample,onset_ms,length_samples,length_ms,audio_filename,block,block_name,trial,source_playlist\n");
+    for item in &render.items {
+        csv.push_str(&format!(
+            "{},{:.3},{},{:.3},{},{}\n",
+            item.onset,
+            item.onset as f64 * 1000.0 / render.sample_rate as f64,
+            item.length,
+            item.length as f64 * 1000.0 / render.sample_rate as f64,
+            csv_field(&item.name),
+            item.log_columns
+        ));
+    }
+    csv
+}
@@ -0,0 +1,103 @@
+use crate::audio::wait_until;
+use crate::structs::{StimulusOnset, ResponseSummary, Event, EventKind, RequestInfo};
+use crate::logging::{log_event, log_row, csv_field};
+use crate::LAST_STIMULUS;
+
+
+// Participant responses (/response), timed from the onset of the most recent stimulus
+// A playlist item can have a response window ("respond_{start}-{end}ms" line after the stimulus): in a playlist with response windows,
+// the first response within the window of a stimulus is a hit, any other response is a false alarm, and a window without a hit is a miss
+
+// Record the onset of a stimulus (audio file or tone), called when the stimulus starts playing
+// The previous stimulus is closed first (a miss is logged if it had a response window without a hit)
+pub fn record_stimulus_onset(onset: StimulusOnset) {
+    let mut last_stimulus = LAST_STIMULUS.lock().unwrap();
+    if let Some(previous) = last_stimulus.as_ref() {
+        log_miss(previous, onset.time_ns);
+    }
+    *last_stimulus = Some(onset);
+}
+
+// End of a playlist: wait for the end of the response window of its last stimulus (if any), and close it
+// The responses received after that are written to the main log, without a stimulus
+pub fn close_response_window(log_file: &str) {
+    let window_end = LAST_STIMULUS.lock().unwrap().as_ref()
+        .filter(|stimulus| stimulus.log_file == log_file)
+        .and_then(|stimulus| stimulus.window.map(|(_, end)| stimulus.time_ns + end as u128 * 1_000_000));
+    if let Some(window_end) = window_end {
+        wait_until(window_end);
+    }
+
+    let mut last_stimulus = LAST_STIMULUS.lock().unwrap();
+    if last_stimulus.as_ref().is_some_and(|stimulus| stimulus.log_file == log_file) {
+        let time_ns = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        log_miss(last_stimulus.as_ref().unwrap(), time_ns);
+        *last_stimulus = None;
+    }
+}
+
+// Log a miss if the stimulus had a response window without a hit. The window is closed at until_ns (the next onset) at the latest
+fn log_miss(stimulus: &StimulusOnset, until_ns: u128) {
+    let (start, end) = match (stimulus.window, stimulus.hit) {
+        (Some(window), false) => window,
+        _ => return,
+    };
+    let time_ns = until_ns.min(stimulus.time_ns + end as u128 * 1_000_000);
+    let monotonic_ns = stimulus.monotonic_ns + time_ns.saturating_sub(stimulus.time_ns); // same time after the onset on both clocks
+    println!("\x1b[2m    \x1b[33mMiss: no response to {} within {}-{} ms\x1b[0m", stimulus.name, start, end);
+    let kind = EventKind::Miss { stimulus: stimulus.name.clone(), window_start_ms: start, window_end_ms: end };
+    write_log_row(stimulus, &format!("{},{},success,N/A{}", time_ns, csv_field(&format!("Miss (stimulus: {} | window: {}-{} ms)", stimulus.name, start, end)), stimulus.log_columns), Event::new(time_ns, kind, "success", "N/A").monotonic(monotonic_ns));
+}
+
+// Score and log a response received at time_ns. Written to the log of the most recent stimulus, or to main_log_file if there is none
+pub fn record_response(label: &str, time_ns: u128, client_time: &str, main_log_file: &str, request: &RequestInfo) -> ResponseSummary {
+    let mut last_stimulus = LAST_STIMULUS.lock().unwrap();
+
+    let stimulus = match last_stimulus.as_mut() {
+        Some(stimulus) => stimulus,
+        None => {
+            let kind = EventKind::Response { label: label.to_string(), stimulus: None, latency_ms: None, outcome: None };
+            log_event(main_log_file, &format!("{},Response {} (no stimulus),success,{}", time_ns, label, csv_field(client_time)), &Event::new(time_ns, kind, "success", client_time).for_request(request));
+            return ResponseSummary { label: label.to_string(), stimulus: None, latency_ms: None, outcome: None, log_file: main_log_file.to_string() };
+        }
+    };
+
+    // responses can arrive before the onset of a scheduled stimulus (start_at), with a negative latency
+    let latency_ms = (time_ns as f64 - stimulus.time_ns as f64) / 1_000_000.0;
+    let outcome = if !stimulus.scored {
+        None
+    } else {
+        Some(match stimulus.window {
+            Some((start, end)) if latency_ms >= start as f64 && latency_ms <= end as f64 => {
+                if stimulus.hit {
+                    "repeat" // a second response in the same window
+                } else {
+                    stimulus.hit = true;
+                    "hit"
+                }
+            },
+            _ => "false_alarm",
+        })
+    };
+
+    let outcome_text = outcome.map_or(String::new(), |outcome| format!(" | outcome: {}", outcome));
+    let kind = EventKind::Response { label: label.to_string(), stimulus: Some(stimulus.name.clone()), latency_ms: Some(latency_ms), outcome: outcome.map(|outcome| outcome.to_string()) };
+    write_log_row(stimulus, &format!("{},{},success,{}{}", time_ns, csv_field(&format!("Response {} (stimulus: {} | latency: {:.3} ms{})", label, stimulus.name, latency_ms, outcome_text)), csv_field(client_time), stimulus.log_columns), Event::new(time_ns, kind, "success", client_time).for_request(request));
+
+    ResponseSummary {
+        label: label.to_string(),
+        stimulus: Some(stimulus.name.clone()),
+        latency_ms: Some(latency_ms),
+        outcome: outcome.map(|outcome| outcome.to_string()),
+        log_file: stimulus.log_file.clone(),
+    }
+}
+
+// Write a response row to the log of a stimulus: with log_event for the main, /play/random and playlist logs, the CSV row only for the others (staircases, audiograms)
+fn write_log_row(stimulus: &StimulusOnset, row: &str, event: Event) {
+    if stimulus.event_log {
+        log_event(&stimulus.log_file, row, &event.at(stimulus.position.clone()));
+    } else {
+        log_row(&stimulus.log_file, row, event.timestamp_monotonic_ns, event.request.as_ref());
+    }
+}
@@ -5,4 +5,14 @@ pub mod tone;
 pub mod startnewlog;
 pub mod batch_files;
 pub mod playlists;
-pub mod list;
\ No newline at end of file
+pub mod list;
+pub mod schedules;
+pub mod import_export;
+pub mod variables;
+pub mod response;
+pub mod staircases;
+pub mod audiometry;
+pub mod protocols;
+pub mod session;
+pub mod clock;
+pub mod diagnostics;
@@ -0,0 +1,219 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::structs::{ResponseMessage, TimeQuery, AudiometryOptions, StimulusOnset, RequestInfo};
+use crate::audiometry::{Audiometry, Ear, load_calibration, create_audiometry_log, save_audiogram, audiogram_csv, CALIBRATION_FILE};
+use crate::responses::record_stimulus_onset;
+use crate::diagnostics::record_onset_timing;
+use crate::logging::{log_row, csv_field};
+use crate::clock::monotonic_ns;
+use crate::audio::{tone_to_ear_source, play_source_blocking, handle_audio_error};
+use crate::{LOG_FILE_NAME, AUDIOGRAMS};
+
+
+// Start a pure-tone audiogram (modified Hughson-Westlake, 10-down/5-up) for each ear and frequency (replaces any audiogram with the same name)
+// The tones are played with /audiometry/play/{name}, and answered with /audiometry/answer/{name}/{answer}
+#[get("/audiometry/start")]
+async fn start_audiometry(req: HttpRequest, audiometry_opt: web::Query<AudiometryOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /audiometry/start (name: {})", time_ns, audiometry_opt.name);
+
+    let calibration = match load_calibration(CALIBRATION_FILE) {
+        Ok(calibration) => calibration,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::InternalServerError().json(ResponseMessage { message });
+        }
+    };
+    if calibration.is_none() {
+        println!("\x1b[2m    \x1b[33mNo calibration file ({}): the levels are not calibrated (0 dB HL = -100 dB re full scale)\x1b[0m", CALIBRATION_FILE);
+    }
+
+    let mut audiometry = match Audiometry::from_options(&audiometry_opt, calibration.as_ref()) {
+        Ok(audiometry) => audiometry,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    create_audiometry_log(&mut audiometry, time_ns, &audiometry_opt.time, &RequestInfo::from_request(&req));
+    let summary = audiometry.summary();
+    let test_count = audiometry.tests.len();
+
+    let mut audiograms = AUDIOGRAMS.lock().unwrap();
+    if audiograms.insert(audiometry.name.clone(), audiometry).is_some() {
+        println!("\x1b[2m    \x1b[33mReplaced the previous audiogram {}\x1b[0m", summary.name);
+    }
+    drop(audiograms); // release the lock on AUDIOGRAMS global
+
+    println!("\x1b[36m    Started audiogram {} ({} ear and frequency tests)\x1b[0m", summary.name, test_count);
+    HttpResponse::Ok().json(summary)
+}
+
+
+// Play the next tone of an audiogram, on one ear (synchronously, like /tone)
+// The previous tone must have been answered first
+#[get("/audiometry/play/{name}")]
+async fn play_audiometry(req: HttpRequest, name: web::Path<String>, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /audiometry/play/{}", time_ns, name);
+
+    // prepare the presentation, and release the lock on AUDIOGRAMS during playback
+    let mut audiograms = AUDIOGRAMS.lock().unwrap();
+    let audiometry = match audiograms.get_mut(name.as_str()) {
+        Some(audiometry) => audiometry,
+        None => {
+            println!("\x1b[2m    \x1b[31mAudiogram Not Found\x1b[0m");
+            let message = format!("Audiogram {} not found. Start it with /audiometry/start", name);
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    };
+    let conflict = if audiometry.finished() {
+        Some(format!("Audiogram {} is finished, see /audiometry/results/{}", name, name))
+    } else if audiometry.awaiting_answer {
+        Some(format!("The last tone of audiogram {} has not been answered yet", name))
+    } else {
+        None
+    };
+    if let Some(message) = conflict {
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::Conflict().json(ResponseMessage { message });
+    }
+
+    let (ear, tone) = audiometry.next_tone().unwrap(); // not finished
+    audiometry.presentations += 1;
+    audiometry.total_presentations += 1;
+    audiometry.awaiting_answer = true; // the answer can be sent during playback
+    let level = audiometry.level;
+    let ascending = audiometry.next_is_ascending();
+    let tone_name = audiometry.next_tone_name();
+    let log_file = audiometry.log_file.clone();
+    drop(audiograms); // release the lock on AUDIOGRAMS global
+
+    // responses (/response) during the audiogram are written to the audiometry log
+    let source = tone_to_ear_source(&tone.freq, &tone.duration, &tone.amplitude, &tone.sample_rate, ear == Ear::Left);
+    let result = play_source_blocking(source, |time_start_nano, time_start_monotonic| {
+        println!("\x1b[2m    \x1b[38;5;8m{}: Started {} ({} dB HL)...\x1b[0m", time_start_nano, tone_name, level);
+        record_stimulus_onset(StimulusOnset { time_ns: time_start_nano, monotonic_ns: time_start_monotonic, name: tone_name.clone(), log_file: log_file.clone(), log_columns: format!(",{},{},{},{},,", ear.as_str(), tone.freq, level, ascending), window: None, scored: false, hit: false, event_log: false, position: None });
+    });
+
+    let (time_start_nano, time_start_monotonic) = match result {
+        Ok((time_start_nano, time_start_monotonic)) => {
+            record_onset_timing(RequestInfo::from_request(&req).received_ns, None, time_start_nano);
+            (time_start_nano, time_start_monotonic)
+        },
+        Err(e) => {
+            // the tone was not played: it can be played again (unless the audiogram was restarted under the same name during playback)
+            if let Some(audiometry) = AUDIOGRAMS.lock().unwrap().get_mut(name.as_str()).filter(|audiometry| audiometry.log_file == log_file) {
+                audiometry.presentations = audiometry.presentations.saturating_sub(1);
+                audiometry.total_presentations = audiometry.total_presentations.saturating_sub(1);
+                audiometry.awaiting_answer = false;
+            }
+            let time_error_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+            log_row(&log_file, &format!("{},{},error,{},{},{},{},{},,", time_error_nano, tone_name, csv_field(&query.time), ear.as_str(), tone.freq, level, ascending), monotonic_ns(), Some(&RequestInfo::from_request(&req)));
+            return handle_audio_error(&tone_name, &query.time, &e, &LOG_FILE_NAME, &RequestInfo::from_request(&req));
+        }
+    };
+    println!("\x1b[2m    \x1b[38;5;8mFinished (job at {})\x1b[0m", time_start_nano);
+
+    log_row(&log_file, &format!("{},{},success,{},{},{},{},{},,", time_start_nano, tone_name, csv_field(&query.time), ear.as_str(), tone.freq, level, ascending), time_start_monotonic, Some(&RequestInfo::from_request(&req)));
+
+    let message = format!("At {} played {} ({} dB HL)", time_start_nano, tone_name, level);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+// Answer the last tone of an audiogram: heard (also yes or 1) or not_heard (also no or 0)
+// Returns the summary of the audiogram, with the next ear, frequency and level, and the thresholds found so far
+#[get("/audiometry/answer/{name}/{answer}")]
+async fn answer_audiometry(req: HttpRequest, path: web::Path<(String, String)>, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let (name, answer) = path.into_inner();
+    println!("{}: Received /audiometry/answer/{}/{}", time_ns, name, answer);
+
+    let heard = match answer.to_lowercase().as_str() {
+        "heard" | "yes" | "1" => true,
+        "not_heard" | "no" | "0" => false,
+        _ => {
+            let message = format!("Invalid answer \"{}\". Use heard or not_heard (also yes/no or 1/0)", answer);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    let mut audiograms = AUDIOGRAMS.lock().unwrap();
+    let audiometry = match audiograms.get_mut(&name) {
+        Some(audiometry) => audiometry,
+        None => {
+            println!("\x1b[2m    \x1b[31mAudiogram Not Found\x1b[0m");
+            let message = format!("Audiogram {} not found. Start it with /audiometry/start", name);
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    };
+    if !audiometry.awaiting_answer {
+        let message = format!("Audiogram {} has no tone to answer, play one with /audiometry/play/{}", name, name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::Conflict().json(ResponseMessage { message });
+    }
+
+    let step = audiometry.answer(heard);
+    let answer_text = if heard { "heard" } else { "not_heard" };
+    let next_level_text = step.next_level.map_or(String::new(), |next_level| next_level.to_string());
+    log_row(&audiometry.log_file, &format!(
+        "{},Answer {} ({} ear | {} Hz | {} dB HL),success,{},{},{},{},{},{},{}",
+        time_ns, answer_text, step.ear.as_str(), step.frequency, step.level, csv_field(&query.time), step.ear.as_str(), step.frequency, step.level, step.ascending, answer_text, next_level_text
+    ), monotonic_ns(), Some(&RequestInfo::from_request(&req)));
+    println!("\x1b[36m    {} ear, {} Hz at {} dB HL: {}\x1b[0m", step.ear.as_str(), step.frequency, step.level, answer_text);
+
+    if let Some(threshold) = &step.threshold {
+        let threshold_text = threshold.threshold.map_or(threshold.status.clone(), |level| format!("{} dB HL", level));
+        log_row(&audiometry.log_file, &format!(
+            "{},Threshold {} ({} ear | {} Hz | status: {} | presentations: {}),success,N/A,{},{},{},,,",
+            time_ns, threshold_text, threshold.ear, threshold.frequency, threshold.status, threshold.presentations, threshold.ear, threshold.frequency, threshold.threshold.map_or(String::new(), |level| level.to_string())
+        ), monotonic_ns(), None);
+        println!("\x1b[1m    Threshold {} ear, {} Hz: {}\x1b[0m", threshold.ear, threshold.frequency, threshold_text);
+    }
+    if step.threshold.is_some() && audiometry.finished() {
+        log_row(&audiometry.log_file, &format!("{},End ({} thresholds | presentations: {}),success,N/A,,,,,,", time_ns, audiometry.thresholds.len(), audiometry.total_presentations), monotonic_ns(), None);
+        save_audiogram(audiometry);
+    }
+
+    HttpResponse::Ok().json(audiometry.summary())
+}
+
+
+// Thresholds of an audiogram, and the next ear, frequency and level if it is not finished (JSON)
+#[get("/audiometry/results/{name}")]
+async fn audiometry_results(name: web::Path<String>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /audiometry/results/{}", time_ns, name);
+
+    match AUDIOGRAMS.lock().unwrap().get(name.as_str()) {
+        Some(audiometry) => HttpResponse::Ok().json(audiometry.summary()),
+        None => {
+            println!("\x1b[2m    \x1b[31mAudiogram Not Found\x1b[0m");
+            let message = format!("Audiogram {} not found", name);
+            HttpResponse::NotFound().json(ResponseMessage { message })
+        }
+    }
+}
+
+
+// Thresholds of an audiogram as a CSV file to download (the thresholds found so far if it is not finished)
+#[get("/audiometry/results/{name}/csv")]
+async fn audiometry_results_csv(name: web::Path<String>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /audiometry/results/{}/csv", time_ns, name);
+
+    match AUDIOGRAMS.lock().unwrap().get(name.as_str()) {
+        Some(audiometry) => HttpResponse::Ok()
+            .content_type("text/csv")
+            .append_header(("Content-Disposition", format!("attachment; filename=audiogram_{}.csv", name)))
+            .body(audiogram_csv(audiometry)),
+        None => {
+            println!("\x1b[2m    \x1b[31mAudiogram Not Found\x1b[0m");
+            let message = format!("Audiogram {} not found", name);
+            HttpResponse::NotFound().json(ResponseMessage { message })
+        }
+    }
+}
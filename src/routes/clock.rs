@@ -0,0 +1,70 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::structs::{ClockSyncQuery, ClockSyncReply, ClockOffsetOptions, ClientClock, Event, EventKind, RequestInfo};
+use crate::clock::{monotonic_ns, wall_clock_ns};
+use crate::logging::{log_event, csv_field};
+use crate::session::write_sidecar;
+use crate::{CLIENT_CLOCKS, LOG_FILE_NAME, SESSION};
+
+
+// NTP-style clock exchange: the server receive and transmit times (UNIX nanoseconds), with the client transmit time t0 echoed back
+// Not logged, so that a client can repeat it (eg. 20 times) and keep the exchange with the shortest round trip
+#[get("/clock/sync")]
+async fn clock_sync(query: web::Query<ClockSyncQuery>) -> HttpResponse {
+    let server_receive_ns = wall_clock_ns();
+    println!("{}: Received /clock/sync", server_receive_ns);
+
+    HttpResponse::Ok().json(ClockSyncReply {
+        t0: query.t0.clone(),
+        server_receive_ns,
+        server_transmit_ns: wall_clock_ns(),
+    })
+}
+
+
+// Register the clock offset of the client (server clock - client clock, in nanoseconds), estimated with /clock/sync
+// Until /session/end, the client timestamps of its requests (same client IP) are also logged corrected to server time (timestamp_client_corrected)
+#[get("/clock/offset")]
+async fn set_clock_offset(req: HttpRequest, offset_opt: web::Query<ClockOffsetOptions>) -> HttpResponse {
+    let time_ns = wall_clock_ns();
+    println!("{}: Received /clock/offset (offset: {} ns)", time_ns, offset_opt.offset_ns);
+
+    let request = RequestInfo::from_request(&req);
+    let client_clock = ClientClock {
+        offset_ns: offset_opt.offset_ns,
+        rtt_ns: offset_opt.rtt_ns,
+        client_ip: request.client_ip.clone(),
+        registered_at_ns: time_ns,
+    };
+    CLIENT_CLOCKS.lock().unwrap().insert(client_clock.client_ip.clone(), client_clock.clone());
+    if let Some(session) = SESSION.lock().unwrap().as_mut() {
+        session.client_clocks.insert(client_clock.client_ip.clone(), client_clock.clone());
+        // the sidecar JSON of the logs already written get the new offset too
+        for log_file in &session.log_files {
+            write_sidecar(log_file, session);
+        }
+    }
+
+    // Append to the log file, so that the corrected client timestamps can be traced back to their offset
+    let rtt_text = offset_opt.rtt_ns.map_or(String::new(), |rtt_ns| format!(" | rtt: {} ns", rtt_ns));
+    let log_file_name = LOG_FILE_NAME.lock().unwrap().clone();
+    let kind = EventKind::ClockOffset { offset_ns: offset_opt.offset_ns, rtt_ns: offset_opt.rtt_ns };
+    log_event(&log_file_name, &format!("{},Client clock offset (offset: {} ns{}),success,{}", time_ns, offset_opt.offset_ns, rtt_text, csv_field(&offset_opt.time)), &Event::new(time_ns, kind, "success", &offset_opt.time).for_request(&request));
+
+    println!("\x1b[36m    Client clock offset: {} ns\x1b[0m", offset_opt.offset_ns);
+    HttpResponse::Ok().json(client_clock)
+}
+
+
+// Server clocks (wall clock and monotonic), and the registered client clock offsets, by client IP (JSON)
+#[get("/clock")]
+async fn clock_status() -> HttpResponse {
+    let time_ns = wall_clock_ns();
+    println!("{}: Received /clock", time_ns);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "server_time_ns": time_ns,
+        "server_monotonic_ns": monotonic_ns(),
+        "client_clocks": *CLIENT_CLOCKS.lock().unwrap(),
+    }))
+}
@@ -0,0 +1,114 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use rodio::{OutputStream, Sink};
+
+use crate::structs::{ResponseMessage, SelfTestOptions, SelfTestReport, Event, EventKind, RequestInfo};
+use crate::audio::{handle_audio_error, click_source, wait_until};
+use crate::clock::{monotonic_ns, wall_clock_ns};
+use crate::diagnostics::{latency_ms, latency_stats};
+use crate::logging::{log_event, csv_field};
+use crate::{LOG_FILE_NAME, TIMING_DIAGNOSTICS};
+
+static CLICK_DURATION_US: u32 = 1000;
+static CLICK_SAMPLE_RATE: u32 = 48000;
+static SELF_TEST_LEAD_NS: u128 = 200_000_000; // the first click is planned 200 ms after the request
+static SELF_TEST_MAX_DURATION_MS: u64 = 60_000; // count x interval: the self-test blocks a worker of the server until its last click
+
+
+// Rolling statistics of the receipt-to-onset and planned-to-actual onset latencies (JSON, see diagnostics.rs)
+#[get("/diagnostics/timing")]
+async fn timing_report() -> HttpResponse {
+    let time_ns = wall_clock_ns();
+    println!("{}: Received /diagnostics/timing", time_ns);
+
+    HttpResponse::Ok().json(TIMING_DIAGNOSTICS.lock().unwrap().report())
+}
+
+
+// Turn the timing diagnostics on or off (also set at startup with timing_diagnostics in ./config.txt). The statistics are kept
+#[get("/diagnostics/timing/{action}")]
+async fn timing_diagnostics(action: web::Path<String>) -> HttpResponse {
+    let time_ns = wall_clock_ns();
+    println!("{}: Received /diagnostics/timing/{}", time_ns, action);
+
+    let mut diagnostics = TIMING_DIAGNOSTICS.lock().unwrap();
+    let message = match action.as_str() {
+        "enable" => {
+            diagnostics.enabled = true;
+            "Timing diagnostics enabled".to_string()
+        },
+        "disable" => {
+            diagnostics.enabled = false;
+            "Timing diagnostics disabled".to_string()
+        },
+        "reset" => {
+            diagnostics.reset();
+            "Timing diagnostics reset".to_string()
+        },
+        _ => {
+            let message = format!("Unknown action {} (enable, disable or reset)", action);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    };
+    println!("\x1b[36m    {}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+// Play count short clicks, one every interval ms, each started at its planned onset (see wait_until),
+// and report the distribution of the errors (actual - planned onset). The result is also written to the main log
+// The actual onset is read when sink.play() returns, like the onsets of the playbacks (see diagnostics.rs)
+#[get("/diagnostics/selftest")]
+async fn self_test(req: HttpRequest, query: web::Query<SelfTestOptions>) -> HttpResponse {
+    let time_ns = wall_clock_ns();
+    println!("{}: Received /diagnostics/selftest (count: {} | interval: {} ms)", time_ns, query.count, query.interval);
+
+    let click_ms = CLICK_DURATION_US as f64 / 1000.0;
+    if query.count == 0 || query.count > 1000 || (query.interval as f64) < click_ms + 10.0 || query.count as u64 * query.interval as u64 > SELF_TEST_MAX_DURATION_MS {
+        let message = format!("Invalid self-test: count must be between 1 and 1000, interval at least {} ms, and count x interval at most {} ms", click_ms + 10.0, SELF_TEST_MAX_DURATION_MS);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    // In case audio device error --> handle this first
+    // Linux with ALSA will panic here if there is no audio output device available, Windows returns an error
+    let request = RequestInfo::from_request(&req);
+    let (_stream, stream_handle) = match std::panic::catch_unwind(OutputStream::try_default) {
+        Err(_) => return handle_audio_error("/diagnostics/selftest", "N/A", "OutputStream NoDevice", &LOG_FILE_NAME, &request),
+        Ok(Err(e)) => return handle_audio_error("/diagnostics/selftest", "N/A", &e.to_string(), &LOG_FILE_NAME, &request),
+        Ok(Ok(output_stream)) => output_stream,
+    };
+    let sink = Sink::try_new(&stream_handle).unwrap();
+
+    let (first_onset_ns, first_onset_monotonic) = (wall_clock_ns() + SELF_TEST_LEAD_NS, monotonic_ns() + SELF_TEST_LEAD_NS);
+    let mut onset_errors_ms = Vec::with_capacity(query.count as usize);
+    for click in 0..query.count {
+        let planned_ns = first_onset_ns + click as u128 * query.interval as u128 * 1_000_000;
+        sink.pause(); // buffer the click in the paused sink, then start it at its planned onset
+        sink.append(click_source(CLICK_DURATION_US, CLICK_SAMPLE_RATE));
+        wait_until(planned_ns);
+        sink.play();
+        let onset_ns = wall_clock_ns();
+        onset_errors_ms.push(latency_ms(planned_ns, onset_ns));
+        sink.sleep_until_end();
+    }
+
+    let report = SelfTestReport {
+        count: query.count,
+        interval_ms: query.interval,
+        click: format!("{} us at {} Hz", CLICK_DURATION_US, CLICK_SAMPLE_RATE),
+        planned_to_actual: latency_stats(onset_errors_ms.clone()),
+        onset_errors_ms,
+    };
+    let stats = &report.planned_to_actual;
+    let stat_text = |value: Option<f64>| value.map_or("N/A".to_string(), |value| format!("{:.3}", value));
+    let text = format!("Timing self-test (clicks: {} | interval: {} ms | mean: {} ms | sd: {} ms | p95: {} ms | max: {} ms)",
+        query.count, query.interval, stat_text(stats.mean_ms), stat_text(stats.sd_ms), stat_text(stats.p95_ms), stat_text(stats.max_ms));
+    println!("\x1b[36m    {}\x1b[0m", text);
+
+    let log_file_name = LOG_FILE_NAME.lock().unwrap().clone();
+    let kind = EventKind::TimingSelfTest { click_count: query.count, interval_ms: query.interval, mean_ms: stats.mean_ms, sd_ms: stats.sd_ms, p95_ms: stats.p95_ms, max_ms: stats.max_ms };
+    log_event(&log_file_name, &format!("{},{},success,N/A", first_onset_ns, csv_field(&text)), &Event::new(first_onset_ns, kind, "success", "N/A").monotonic(first_onset_monotonic).for_request(&request));
+
+    HttpResponse::Ok().json(report)
+}
@@ -0,0 +1,136 @@
+use std::fs;
+
+use actix_web::{get, post, web, HttpResponse};
+
+use crate::structs::{ResponseMessage, AudioFiles, ImportOptions};
+use crate::audio::parse_playlist;
+use crate::file_io::{save_playlist_and_reload, restore_playlist_and_reload};
+use crate::playlist_formats::{export_playlist, import_playlist, ImportColumns, PlaylistFormat};
+use crate::PLAYLISTS;
+
+
+// Download a loaded playlist as M3U / M3U8, CSV (file, isi, gain) or PsychoPy conditions file
+#[get("/playlist/export/{format}/{playlist_file_name}")]
+async fn export(path: web::Path<(String, String)>, audio_files: web::Data<AudioFiles>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let (format_name, playlist_file_name) = path.into_inner();
+    println!("{}: Received /playlist/export/{}/{}", time_ns, format_name, playlist_file_name);
+
+    let format = match PlaylistFormat::parse(&format_name) {
+        Some(format) => format,
+        None => {
+            let message = format!("Unknown format \"{}\". Use m3u, m3u8, csv or psychopy", format_name);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    let playlists = PLAYLISTS.lock().unwrap();
+    let playlist = match playlists.playlists.get(&playlist_file_name) {
+        Some(playlist) => playlist.clone(),
+        None => {
+            println!("\x1b[2m    \x1b[31mPlaylist file name not found\x1b[0m");
+            let message = "Playlist file name not found".to_string();
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    };
+    drop(playlists); // release the lock on PLAYLISTS global
+
+    let content = match export_playlist(&playlist, format, &audio_files.files) {
+        Ok(content) => content,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    let export_file_name = format!("{}{}", playlist_file_name.trim_end_matches(".txt"), format.file_suffix());
+    println!("\x1b[2m    \x1b[38;5;8mExported {} as {}\x1b[0m", playlist_file_name, export_file_name);
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .append_header(("Content-Disposition", format!("attachment; filename=\"{}\"", export_file_name)))
+        .body(content)
+}
+
+
+// Upload an M3U / M3U8, CSV or PsychoPy conditions file (as the request body) and save it as a native playlist
+// All the audio files must be loaded on the server, otherwise nothing is saved and the missing files are listed
+#[post("/playlist/import/{format}/{playlist_file_name}")]
+async fn import(path: web::Path<(String, String)>, body: web::Bytes, audio_files: web::Data<AudioFiles>, import_opt: web::Query<ImportOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let (format_name, playlist_file_name) = path.into_inner();
+    println!("{}: Received /playlist/import/{}/{}", time_ns, format_name, playlist_file_name);
+
+    let format = match PlaylistFormat::parse(&format_name) {
+        Some(format) => format,
+        None => {
+            let message = format!("Unknown format \"{}\". Use m3u, m3u8, csv or psychopy", format_name);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    // the playlist is saved as ./playlists/{name}.txt
+    let playlist_file_name = if playlist_file_name.ends_with(".txt") { playlist_file_name } else { format!("{}.txt", playlist_file_name) };
+    if playlist_file_name.contains('/') || playlist_file_name.contains('\\') || playlist_file_name.starts_with('.') {
+        let message = format!("Invalid playlist name {}", playlist_file_name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+    if !import_opt.overwrite && PLAYLISTS.lock().unwrap().playlists.contains_key(&playlist_file_name) {
+        let message = format!("Playlist {} already exists (use ?overwrite=true to replace it)", playlist_file_name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::Conflict().json(ResponseMessage { message });
+    }
+
+    let content = match String::from_utf8(body.to_vec()) {
+        Ok(content) => content,
+        Err(_) => {
+            let message = "The uploaded file is not UTF-8 text".to_string();
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    let columns = ImportColumns {
+        file: import_opt.file_column.clone(),
+        isi: import_opt.isi_column.clone(),
+        gain: import_opt.gain_column.clone(),
+    };
+    let lines = match import_playlist(&content, format, &columns, &audio_files.files) {
+        Ok(lines) => lines,
+        Err(errors) => {
+            let message = format!("Couldn't import the {} file ({} error(s)): {}", format.name(), errors.len(), errors.join("; "));
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    if !lines.iter().any(|line| !line.starts_with('#') && !line.starts_with("[block")) {
+        let message = format!("The {} file has no items", format.name());
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    // the native lines and the header of an exported M3U are checked before anything is written, so that an invalid file can't replace a playlist
+    let output_string = format!("# imported_from: {}\n{}", format.name(), lines.join("\n"));
+    if parse_playlist(&playlist_file_name, &output_string, &audio_files.files).is_none() {
+        let message = format!("The imported {} file is not a valid playlist (see the server console)", format.name());
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    let previous_content = fs::read_to_string(format!("./playlists/{}", playlist_file_name)).ok();
+    save_playlist_and_reload(&playlist_file_name, &output_string, &audio_files);
+
+    // the jumps are only checked once the playlists are reloaded: if the playlist is still rejected, the previous file is put back
+    if !PLAYLISTS.lock().unwrap().playlists.contains_key(&playlist_file_name) {
+        restore_playlist_and_reload(&playlist_file_name, previous_content.as_deref(), &audio_files);
+        let message = format!("Couldn't import {}: the playlist is invalid (see the server console)", playlist_file_name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    let message = format!("Imported {} file as ./playlists/{} ({} lines)", format.name(), playlist_file_name, lines.len());
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
@@ -14,6 +14,58 @@ async fn index() -> impl Responder {
         - GET /startnewlog                  --> start a new log file
                 (eg. /startnewlog ==> Started new log file: ./logs/log_20210321-171234.csv)
 
+
+        - GET /session/start                --> start a session and a new log file (JSON session). 1 required parameter:
+                - participant (without spaces, / or \\)
+            and 3 optional parameters:
+                - experiment
+                - experimenter
+                - time (client timestamp)
+            Any other parameter is a free-form key/value. Until /session/end, every log file gets a sidecar JSON with the session metadata ({log}_session.json)
+                (eg. /session/start?participant=P01&experiment=pitch&experimenter=AB&age=25 ==> logs/log_20240131-140000.csv + logs/log_20240131-140000_session.json)
+
+
+        - GET /session                      --> the current session, with its log files (JSON)
+
+
+        - GET /session/end                  --> end the current session, and add the end time and the list of log files to all its sidecar JSON. 1 optional parameter:
+                - time (client timestamp)
+
+
+        - GET /clock/sync                   --> NTP-style clock exchange (JSON: t0, server_receive_ns, server_transmit_ns, not logged). 1 optional parameter:
+                - t0 (client transmit time, echoed back)
+            offset (server - client) = ((server_receive_ns - t0) + (server_transmit_ns - t3)) / 2, with t3 the client receive time
+            round trip = (t3 - t0) - (server_transmit_ns - server_receive_ns)
+
+
+        - GET /clock/offset                 --> register the clock offset of the client, until /session/end. 1 required parameter:
+                - offset_ns (server clock - client clock, in nanoseconds)
+            and 2 optional parameters:
+                - rtt_ns (round trip of the exchange)
+                - time (client timestamp)
+            The client timestamps (in UNIX nanoseconds) of the requests from the same client IP are then also logged corrected to server time (timestamp_client_corrected)
+                (eg. /clock/offset?offset_ns=-4873512&rtt_ns=812000)
+
+
+        - GET /clock                        --> the server clocks and the registered client clock offsets, by client IP (JSON)
+
+
+        - GET /diagnostics/timing           --> rolling statistics of the timing latencies of the last 1000 playbacks, in ms (JSON: count, mean, sd, min, p50, p90, p95, p99, max)
+                - receipt_to_onset: from the receipt of the request to the onset of the first stimulus (without start_at)
+                - planned_to_actual: from the planned onset (start_at, or the end of the previous item of /play/random and /playlist) to the actual onset
+            Measured with the timing diagnostics on: timing_diagnostics: on in ./config.txt, or /diagnostics/timing/enable
+            The receipt is stamped before the routing, the onset once the output sink is started: the latency of the audio device output buffer is not included
+
+
+        - GET /diagnostics/timing/{action}  --> enable or disable the timing diagnostics, or reset their statistics (action: enable, disable or reset)
+
+
+        - GET /diagnostics/selftest         --> play short clicks at planned onsets, and report the onset errors (actual - planned, in ms, JSON). 2 optional parameters:
+                - count (number of clicks, default = 20, max = 1000)
+                - interval (milliseconds between the planned onsets, default = 250)
+            count x interval is at most 60000 ms
+                (eg. /diagnostics/selftest?count=50&interval=200 ==> Timing self-test (clicks: 50 | interval: 200 ms | mean: 0.012 ms | ...))
+
                 
         - GET /play/{audio_file_name}       --> play the audio file
                 (eg. /play/1.wav ==> 1.wav started playing on the server)
@@ -29,24 +81,208 @@ async fn index() -> impl Responder {
                 (eg. /save_tone/1000/500/40/96000 ==> generate file 1000Hz_500ms_40dB_@96000Hz.wav to download)
 
 
-        - GET /play/random                  --> play some random audio files. 2 optional parameters:
+        - GET /play/random                  --> play some random audio files. 8 optional parameters:
                 - break_between_files (in milliseconds, default = 0)
-                - file_count (number of files to play, default = 100)
+                - file_count (number of files to play, default = 100, or no limit if max_duration is set)
+                - max_duration (in milliseconds, no file is started if it would not finish in time, default = 0 = no limit)
+                - seed (unsigned 64-bit integer, default = a random seed. The seed used is written to the log file)
+                - sampling (replacement, without_replacement or balanced, default = replacement)
+                - max_repeats (maximum number of times the same file can play in a row, default = 0 = no limit)
+                - min_distance (minimum number of other files between two plays of the same file, default = 0 = no limit)
+                - weights (per-file weights, eg. a.wav:2,b.wav:0.5. Files not listed have a weight of 1, 0 excludes the file)
+                (eg. /play/random?file_count=40&sampling=balanced&max_repeats=1 ==> 40 files, each file the same number of times, never twice in a row)
                 (eg. /play/random?break_between_files=1000&file_count=10 ==> 10 random files started playing on the server)
 
 
-        - GET /playlist/create              --> create a random playlist with available audio files. 3 optional parameters:
+        - GET /playlist/create              --> create a random playlist with available audio files. 9 optional parameters:
                 - break_between_files (in milliseconds, default = 0)
-                - file_count (number of files to play, default = 100)
+                - file_count (number of files to play, default = 10, or no limit if max_duration is set)
+                - max_duration (in milliseconds, fill the playlist up to this duration, default = 0 = no limit)
                 - no_download (don't download the files, only create the playlist server-side and return the new playlist name, default = false)
+                - seed (unsigned 64-bit integer, default = a random seed. The seed used is written to the playlist header)
+                - sampling, max_repeats, min_distance, weights (same as /play/random)
                 (eg. /playlist/create?break_between_files=1000&file_count=10 ==> random playlist: playlist_{hash}_{duration}s_{size}count.txt to download)
                 (eg. /playlist/create?break_between_files=1000&file_count=20&no_download=true ==> random playlist: playlist_{hash}_{duration}s_{size}count.txt created on the server)
                 
                 * The {hash} is the first 8 characters of the SHA256 hash of the playlist file. This serves as a unique identifier for the playlist, so that no two duplicate playlists are created.
 
 
-        - GET /playlist/{playlist_name}     --> play a playlist on the server
+        - GET /playlist/create/oddball      --> create an oddball playlist server-side (standard + rare deviants) and return a JSON summary. 3 required parameters:
+                - standard (audio file name or tone, eg. 1000.wav or tone_1000Hz_50ms_40dB_@48000Hz)
+                - deviants (comma-separated audio file names or tones)
+                - deviant_probabilities (comma-separated, one per deviant, sum <= 1)
+            and 5 optional parameters:
+                - trial_count (number of trials, default = 200)
+                - isi (silence between two trials in milliseconds, default = 0)
+                - min_standards (minimum number of standards between two deviants, default = 0)
+                - initial_standards (number of standards before the first deviant, default = 0)
+                - seed (unsigned 64-bit integer, default = a random seed)
+                (eg. /playlist/create/oddball?standard=1000.wav&deviants=1200.wav&deviant_probabilities=0.1&isi=500&min_standards=2 ==> JSON summary with the deviant count and positions)
+
+
+        - GET /playlist/create/latin_square --> create the counterbalanced playlist of one participant and return a JSON summary. 2 required parameters:
+                - playlists (comma-separated names of the condition playlists)
+                - participant (participant number, starting at 1)
+            and 2 optional parameters:
+                - balanced (balanced Latin square, aka Williams design, default = false)
+                - block_rest (rest between two conditions in milliseconds, default = 0)
+                (eg. /playlist/create/latin_square?playlists=a.txt,b.txt,c.txt&participant=4&balanced=true ==> playlist_balanced_latin_square_p4_row4_{hash}.txt)
+                The conditions must have the same iti and shuffle headers (carried over, shuffle blocks/both are rejected), and their block_rest header must match block_rest
+
+
+        - GET /playlist/{playlist_name}     --> play a playlist on the server. 5 optional parameters:
+                - max_duration (in milliseconds, no item is started if it would not finish in time, default = 0 = no limit)
+            and for playlists with blocks:
+                - iti (silence between two trials of the same block in milliseconds, default = # iti: ... in the playlist header, or 0)
+                - block_rest (rest between two blocks in milliseconds, default = # block_rest: ... in the playlist header, or 0)
+                - shuffle (none, blocks, trials or both, default = # shuffle: ... in the playlist header, or none)
+                - seed (unsigned 64-bit integer for the shuffles, default = a random seed. The seed used is written to the log file)
                 (eg. /playlist/playlist_file.txt ==> playlist_file.txt started playing on the server)
+                (eg. /playlist/blocks.txt?iti=800&block_rest=30000&shuffle=trials ==> trials shuffled within each block, 30 seconds rest between blocks)
+
+
+        - GET /playlist/preview/{playlist_name} --> preview a playlist without playing it (JSON): total duration, item count, duration and onset of every item,
+            the audio files and tones used (and how often), and the total pause time. Same optional parameters as /playlist/{playlist_name}.
+                (eg. /playlist/preview/playlist_file.txt?shuffle=trials&seed=7 ==> JSON preview of that playback order)
+
+
+        - GET /playlist/render/{playlist_name} --> render a playlist offline (pauses included) into one audio file, and download it in a .zip with a CSV of the item onsets (in samples).
+            Same optional parameters as /playlist/{playlist_name} (iti, block_rest, shuffle, seed, max_duration), and 3 more:
+                - format (wav = 32-bit float, or flac = 24-bit up to 96000 Hz, default = wav)
+                - sample_rate (in Hz, default = the highest sample rate of the items)
+                - channels (default = the highest channel count of the items)
+                (eg. /playlist/render/playlist_file.txt?format=flac&sample_rate=48000&seed=7 ==> playlist_file_seed7_48000Hz.zip)
+                Renders above 172800000 samples (frames x channels, eg. 30 minutes of 48 kHz stereo) are rejected (413)
+
+
+        - GET /playlist/resume/{log_name}   --> resume a playlist after the last item of one of its log files (eg. after a break or a crash). 1 optional parameter:
+                - max_duration (in milliseconds, for the continuation only, default = 0 = no limit)
+            The playlist is played again with the settings and the seed of the log, from the next item, into a new log file linked to the resumed one.
+                (eg. /playlist/resume/log_playlist_20240131-140000.csv ==> the rest of the playlist started playing on the server)
+
+
+        - GET /playlist/export/{format}/{playlist_name} --> download a playlist as another format:
+                - m3u or m3u8 (for media players, blocks, pauses and tones are kept as # lines)
+                - csv (columns file, isi in milliseconds, gain in dB, and block for playlists with blocks)
+                - psychopy (conditions file for a PsychoPy loop: columns sound, isi in seconds, volume from 0 to 1, and block)
+                (eg. /playlist/export/psychopy/playlist_file.txt ==> playlist_file_conditions.csv)
+
+
+        - POST /playlist/import/{format}/{playlist_name} --> save the uploaded file (request body) as ./playlists/{playlist_name}.txt, same formats as /playlist/export.
+            Every audio file must be in the audio folder, otherwise nothing is saved and the errors are returned. 4 optional parameters:
+                - overwrite (replace an existing playlist, default = false)
+                - file_column, isi_column, gain_column (column names for csv and psychopy, default = file/sound, isi, gain/volume)
+                (eg. curl --data-binary @conditions.csv localhost:5055/playlist/import/psychopy/experiment ==> ./playlists/experiment.txt)
+
+
+        - GET /schedules                    --> list the recurring playback schedules (JSON), with their next run time
+
+
+        - GET /schedules/add                --> add (or replace) a recurring playback schedule, saved to ./schedules.txt. 3 required parameters:
+                - name (schedule name, without | or /)
+                - cron (5-field cron expression in local time: minute hour day-of-month month day-of-week, or @hourly, @daily, @weekly, @monthly, @yearly)
+                - playlist or file (the playlist or audio file to play)
+            and 1 optional parameter:
+                - enabled (true or false, default = true)
+                (eg. /schedules/add?name=morning&cron=30+9+*+*+1-5&playlist=playlist_file.txt ==> playlist_file.txt plays at 9:30 every weekday)
+
+
+        - GET /schedules/enable/{name}      --> enable a schedule
+        - GET /schedules/disable/{name}     --> disable a schedule (a run in progress is not stopped)
+        - GET /schedules/remove/{name}      --> remove a schedule
+        - GET /schedules/reload             --> reload ./schedules.txt after editing it by hand
+
+
+        - GET /response/{label}             --> log a participant response, with its latency from the onset of the most recent stimulus (JSON summary). 1 optional parameter:
+                - time (client timestamp)
+            The response is written to the log of that stimulus. In playlists with response windows, it is scored as a hit, a false alarm or a repeat.
+                (eg. /response/left?time=1706709600123456789 ==> Response left (stimulus: 1200.wav | latency: 412.803 ms | outcome: hit) in the playlist log)
+
+
+        - GET /variables                    --> list the playlist variables (JSON), checked by the conditional jumps of the playlists
+
+
+        - GET /variables/set                --> set a playlist variable, also during playback (logged to the current log file). 1 required parameter:
+                - name (variable name, without spaces, = ! or ,)
+            and 2 optional parameters:
+                - value (default = \"\", the same as unset)
+                - time (client timestamp)
+                (eg. /variables/set?name=response&value=correct ==> the next jump_{label} if_response=correct line jumps)
+
+
+        - GET /variables/clear              --> unset all the playlist variables
+
+
+        - GET /staircase/start              --> start an adaptive staircase (1-up/2-down or 1-up/3-down) on the level of a tone or of an audio file, with its own log file (JSON summary). 2 required parameters:
+                - name (letters, digits, - and _)
+                - tone (eg. tone_1000Hz_200ms_-20dB_@48000Hz, the level is the amplitude) OR file (audio file name, the level is the gain)
+            and 8 optional parameters:
+                - rule (1up2down (default, 70.7% correct) or 1up3down (79.4% correct))
+                - start_level (dB, default = the amplitude of the tone, or 0 for a file)
+                - step_sizes (dB after 0, 1, 2, ... reversals, default = 8,4,2)
+                - min_level, max_level (dB, default = -100 and 0)
+                - max_reversals (default = 8), max_trials (default = 0 = no limit) --> stop criteria
+                - threshold_reversals (default = 6, the threshold is the mean level of the last reversals)
+                - time (client timestamp)
+                (eg. /staircase/start?name=detect&tone=tone_1000Hz_200ms_-30dB_@48000Hz&rule=1up3down&step_sizes=6,3,1 ==> logs/log_staircase_detect_20240131-140000.csv)
+
+
+        - GET /staircase/play/{name}        --> play the next trial at the current level (the previous trial must be answered first). 1 optional parameter:
+                - time (client timestamp)
+
+
+        - GET /staircase/answer/{name}/{answer} --> answer the last trial: correct or incorrect (also yes/no or 1/0), updates the level (JSON summary). 1 optional parameter:
+                - time (client timestamp)
+
+
+        - GET /staircase/summary/{name}     --> level, trials, reversal levels and threshold estimate of a staircase (JSON)
+
+
+        - GET /audiometry/start             --> start a pure-tone audiogram (modified Hughson-Westlake: 10 dB down after a tone heard, 5 dB up after a tone not heard) for each ear and frequency (JSON summary). 1 required parameter:
+                - name (letters, digits, - and _)
+            and 8 optional parameters:
+                - frequencies (Hz, in test order, default = 1000,2000,4000,8000,500,250)
+                - ears (default = right,left)
+                - start_level, min_level, max_level (dB HL, multiples of 5, default = 30, -10 and 90)
+                - duration (ms, default = 1000), sample_rate (Hz, default = 48000)
+                - max_presentations (per ear and frequency, default = 30)
+                - time (client timestamp)
+            The level in dB HL + the calibration gain of ./calibration.txt (lines: frequency | left, right or both | gain) is the amplitude of the tone. Without ./calibration.txt, the gain is -100 dB.
+                (eg. /audiometry/start?name=P01&frequencies=1000,2000,4000 ==> logs/log_audiometry_P01_20240131-140000.csv)
+
+
+        - GET /audiometry/play/{name}       --> play the next tone, on one ear (the previous tone must be answered first). 1 optional parameter:
+                - time (client timestamp)
+
+
+        - GET /audiometry/answer/{name}/{answer} --> answer the last tone: heard or not_heard (also yes/no or 1/0) (JSON summary). 1 optional parameter:
+                - time (client timestamp)
+            Once finished, the thresholds are saved to logs/audiogram_{name}_%Y%m%d-%H%M%S.csv
+
+
+        - GET /audiometry/results/{name}    --> per-ear thresholds of an audiogram, and the next ear, frequency and level (JSON)
+
+
+        - GET /audiometry/results/{name}/csv --> per-ear thresholds of an audiogram as a CSV file to download
+
+
+        - GET /protocol/start/{name}        --> run the steps of ./protocols/{name}.txt in the background, one after the other (JSON status). 2 optional parameters:
+                - from_step (default = 1, eg. to restart a failed protocol)
+                - time (client timestamp)
+            Protocol lines: startnewlog | playlist_{playlist_filename} | {audio_filename} | tone_{freq}Hz_{duration}ms_{amplitude}dB_@{sample_rate}Hz | rest_{duration}ms | wait [message]
+            Every step transition is logged to logs/log_protocol_{name}_%Y%m%d-%H%M%S.csv
+                (eg. /protocol/start/session_a ==> Step 1 started (startnewlog) ...)
+
+
+        - GET /protocol/status              --> state of the current (or last) protocol: running, waiting, finished, stopped or failed, with the current step and wait message (JSON)
+
+
+        - GET /protocol/continue            --> continue after the current wait step of the protocol. 1 optional parameter:
+                - time (client timestamp)
+
+
+        - GET /protocol/stop                --> stop the protocol, right away during a rest or a wait, or after the current playback. 1 optional parameter:
+                - time (client timestamp)
 
 
         - GET /generate_batch_files         --> generate a .zip containing batch files to request the audio files and playlists (close when audio file is finished playing)
@@ -69,5 +305,72 @@ async fn index() -> impl Responder {
 
         - /playlist/create will also hot reload the playlists folder, so you can create a new playlist and play it right away.
 
+        - Randomization uses the ChaCha8Rng generator (rand_chacha), seeded with ?seed=. The same seed and the same audio folder always give the same order.
+
+        - When both file_count and max_duration are set, the session stops at whichever comes first.
+          The last row of the /play/random and /playlist logs tells how the session ended: by count, by duration or by completion.
+
+        - /play, /play/random, /tone and /playlist accept an optional start_at parameter: the audio is buffered right away, and starts at that time.
+          start_at is either UNIX nanoseconds or an ISO-8601 date time (eg. 2024-01-31T14:00:00.000Z, UTC if no timezone is given), at most 24 hours ahead.
+          The requested and actual start times, and the error between them, are written to the log.
+                (eg. /play/1.wav?start_at=1706709600000000000 ==> 1.wav starts playing at 2024-01-31T14:00:00Z)
+
+        - If the randomization constraints can't be met, /play/random and /playlist/create return 400 Bad Request with the reason.
+
+        - A playlist line can also be a tone: tone_{freq}Hz_{duration}ms_{amplitude}dB_@{sample_rate}Hz (eg. tone_1000Hz_500ms_40dB_@96000Hz).
+
+        - A playlist line \"[block {name}]\" starts a new block: the lines after it are the trials of that block.
+          /playlist logs have 4 extra columns: block, block_name and trial (numbers in playback order, starting at 1), and source_playlist.
+
+        - A playlist line include_{playlist} (or include_{playlist} repeat_{count}) plays another playlist of the playlists folder at that position (eg. include_practice.txt repeat_2, at most repeat_1000 and 1000000 items in total).
+          Includes can be nested, and are resolved when the playlists are loaded (a playlist with a missing or cyclic include is ignored).
+          The source_playlist column of the /playlist logs has the included playlist each item comes from (eg. session.txt>practice.txt).
+
+        - Lines between \"[random]\" and \"[end random]\" are weighted options, each starting with \"[option {weight}]\": one option is played, chosen with the seed,
+          and logged as random_option_{k}/{n}. A line label_{name} marks a position, and jump_{label} if_{variable}={value} (or !=, or no condition) goes back
+          (or forward) to it during playback, with the variables of /variables/set. Taken jumps are logged. Preview and render don't take the jumps.
+
+        - A playlist line respond_{start}-{end}ms (or respond_{end}ms) after a stimulus is its response window, in milliseconds after the onset (eg. respond_150-1000ms).
+          The responses (/response) are then scored, and a window without a hit is logged as a Miss.
+
+        - Lines starting with # in a playlist file are header / comment lines, and are ignored during playback (eg. # seed: 1234).
+
+        - Scheduled runs are logged exactly like the /playlist/{name} and /play/{name} requests, with \"schedule:{name}\" as the client timestamp.
+          A schedule is skipped if its previous run is still playing.
+
+        - The main, /play/random and /playlist logs can also (or instead) be written as a JSON Lines event log, {log}.jsonl next to {log}.csv,
+          with log_format: csv (default), jsonl or both in ./config.txt (one \"key: value\" per line, read at startup).
+          Each line is one JSON object: timestamp_ns, timestamp_monotonic_ns, event, status, timestamp_client and timestamp_client_corrected_ns (if any), job_id (/play/random and /playlist: the request time),
+          the playlist position (item, block, block_name, trial, source_playlist), and the fields of its event kind:
+                request         route, options (the parameters of /play/random or /playlist, with the seed)
+                audio_file      file, gain_db
+                tone            frequency_hz, duration_ms, amplitude_db, sample_rate_hz
+                pause           duration_ms
+                scheduled_start stimulus, requested_ns, actual_ns, error_ns (positive = late)
+                branch          option (eg. random_option_2/3)
+                jump            line, label, variable, value, target_item (null if the label is not in this playback)
+                continued       continued_in, from_item, item_count (written to a resumed playlist log)
+                end             route, ended_by, played, item_count, elapsed_ms
+                response        label, stimulus, latency_ms, outcome
+                miss            stimulus, window_start_ms, window_end_ms
+                variable        name, value (/variables/set)
+                session         action (started or ended), session_id, participant, experiment, experimenter, log_file_count
+                error           stimulus, message
+                clock_sync      offset_ns (wall clock - monotonic clock)
+                clock_offset    offset_ns, rtt_ns (/clock/offset)
+                timing_self_test click_count, interval_ms, mean_ms, sd_ms, p95_ms, max_ms (/diagnostics/selftest)
+          /playlist/resume reads the CSV log, so it needs csv or both. The staircase, audiometry and protocol logs are always CSV.
+
+        - The main, /play/random and /playlist logs end with 7 more columns: timestamp_end (end of the playback, UNIX ns), measured_duration_ms,
+          nominal_duration_ms, source_sample_rate_hz, client_ip, request_path and request_id (the X-Request-ID header, or a random ID).
+          The event log has the same fields (timestamp_end_ns instead of timestamp_end), each left out if empty.
+          Every row of every log ends with timestamp_monotonic: nanoseconds since the server started, from a monotonic clock (not adjusted by NTP).
+          A \"Clock sync\" row in the main log maps it to the wall clock (offset = wall clock - monotonic), when the log starts and then every minute.
+          After it, timestamp_client_corrected: the client timestamp corrected to server time, with the offset registered by its client (/clock/offset).
+          CSV fields with a comma, a double quote or a line break are quoted (\"a,b.wav\"), with the double quotes doubled.
+
+        - All the log files are written by a background thread: the rows are buffered, and written to disk at least every second,
+          on /session/end, before /playlist/resume reads a log, and when the server is stopped (Ctrl+C).
+
     "
 }
\ No newline at end of file
@@ -1,24 +1,32 @@
-use std::{
-    fs,
-    fs::OpenOptions,
-    io::Write,
-};
+use std::time::Duration;
 
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use rodio::{OutputStream, Sink, Source};
 
-use crate::structs::{ResponseMessage, TimeQuery, AudioFiles, RandomAudioQueueOptions};
-use crate::audio::{handle_audio_error, pause_sink_duration};
+use crate::structs::{ResponseMessage, TimeQuery, AudioFiles, RandomAudioQueueOptions, StimulusOnset, Event, EventKind, RequestInfo};
+use crate::responses::{record_stimulus_onset, close_response_window};
+use crate::audio::{handle_audio_error, pause_sink_duration, elapsed_ms, parse_start_at, wait_until, play_off_worker, scheduled_start_text};
+use crate::randomization::{resolve_seed, seeded_rng, sorted_audio_file_names, audio_file_durations, constrained_sequence, sequence_for_duration, SequenceConstraints, RNG_NAME};
+use crate::logging::{start_log, log_event, csv_field, log_file_names, scheduled_start_event};
+use crate::clock::monotonic_ns;
+use crate::session::register_log_file;
+use crate::diagnostics::record_onset_timing;
 use crate::LOG_FILE_NAME;
 
 
 #[get("/play/{audio_file_name}")]
-async fn play(audio_files: web::Data<AudioFiles> , audio_file_name: web::Path<String>, query: web::Query<TimeQuery>) -> HttpResponse {
+async fn play(req: HttpRequest, audio_files: web::Data<AudioFiles> , audio_file_name: web::Path<String>, query: web::Query<TimeQuery>) -> HttpResponse {
     let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
     println!("{}: Received /play/{}", time_ns, audio_file_name);
 
-    let audio_file_name = audio_file_name.into_inner();
-    let source = audio_files.files.get(&audio_file_name); // find decoded audio file by name
+    let request = RequestInfo::from_request(&req);
+    let start_at = query.start_at.clone();
+    play_off_worker(&start_at, move || play_audio_file(&audio_file_name, &audio_files, &query, &request)).await
+}
+
+// Play a single audio file and append it to the main log, shared by the route above and the scheduler
+pub fn play_audio_file(audio_file_name: &str, audio_files: &AudioFiles, query: &TimeQuery, request: &RequestInfo) -> HttpResponse {
+    let source = audio_files.files.get(audio_file_name); // find decoded audio file by name
 
     // if the audio file is not found, return 404
     if source.is_none() {
@@ -27,18 +35,26 @@ async fn play(audio_files: web::Data<AudioFiles> , audio_file_name: web::Path<St
         return HttpResponse::NotFound().json(ResponseMessage { message });
     }
 
+    let start_at = match parse_start_at(&query.start_at) {
+        Ok(start_at) => start_at,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
     // Linux with ALSA will panic here if there is no audio output device available
     let output_stream_result = std::panic::catch_unwind(|| OutputStream::try_default());
 
     if output_stream_result.is_err() {
-        return handle_audio_error(&audio_file_name, &query.time, "OutputStream NoDevice", &LOG_FILE_NAME);
+        return handle_audio_error(audio_file_name, &query.time, "OutputStream NoDevice", &LOG_FILE_NAME, request);
     }
 
     let output_stream_result = output_stream_result.unwrap();
 
     // Windows somehow panics when unwraping the output_stream_result for the same reason (no audio output device available)
     if let Err(e) = output_stream_result {
-        return handle_audio_error(&audio_file_name, &query.time, &e.to_string(), &LOG_FILE_NAME);
+        return handle_audio_error(audio_file_name, &query.time, &e.to_string(), &LOG_FILE_NAME, request);
     }
 
     // now safe to unwrap
@@ -48,12 +64,24 @@ async fn play(audio_files: web::Data<AudioFiles> , audio_file_name: web::Path<St
     println!("\x1b[2m    \x1b[38;5;8mSource's Sample Rate: {} Hz\x1b[0m", source.unwrap().sample_rate());
 
     let sink = Sink::try_new(&stream_handle).unwrap();
+    sink.pause(); // buffer the audio file in the paused sink, then start it right away (or at start_at)
     sink.append(source.unwrap().clone()); // init the sink with the audio file
 
+    if let Some(start_at) = start_at {
+        println!("\x1b[2m    \x1b[38;5;8mWaiting for start_at {}...\x1b[0m", start_at);
+        wait_until(start_at);
+    }
+    sink.play();
+    // the onset is read once the sink is started (see diagnostics.rs)
     let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    let time_start_monotonic = monotonic_ns();
+    let log_file = format!("{}.csv", *LOG_FILE_NAME.lock().unwrap());
+    record_stimulus_onset(StimulusOnset { time_ns: time_start_nano, monotonic_ns: time_start_monotonic, name: audio_file_name.to_string(), log_file, log_columns: String::new(), window: None, scored: false, hit: false, event_log: true, position: None });
+    record_onset_timing(request.received_ns.filter(|_| start_at.is_none()), start_at, time_start_nano); // the wait for start_at is not a latency
     
     println!("\x1b[2m    \x1b[38;5;8m{}: Started {}...\x1b[0m", time_start_nano, audio_file_name);
     sink.sleep_until_end(); // play the audio file synchronously. this thread will be blocked until the audio file has finished playing.
+    let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
     println!("\x1b[2m    \x1b[38;5;8mFinished (job at {})\x1b[0m", time_start_nano);
 
     let message = format!("At {} played {}", time_start_nano, audio_file_name);
@@ -62,52 +90,93 @@ async fn play(audio_files: web::Data<AudioFiles> , audio_file_name: web::Path<St
     drop(_stream);
     
     // Append to the log file
-    fs::create_dir_all("./logs").unwrap(); // make sure the logs/ folder exists first
-
-    let log_file_name = LOG_FILE_NAME.lock().unwrap();
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("{}.csv", *log_file_name))
-        .unwrap();
-    if let Err(e) = writeln!(file, "{},{},{},{}", time_start_nano, audio_file_name, "success", &query.time) {
-        eprintln!("Couldn't write to file: {}", e);
-    } else {
-        println!("\x1b[2m    \x1b[38;5;8mAppended to log file: {}\x1b[0m", *log_file_name);
+    let log_file_name = LOG_FILE_NAME.lock().unwrap().clone();
+    let source = source.unwrap();
+    let event = Event::new(time_start_nano, EventKind::AudioFile { file: audio_file_name.to_string(), gain_db: 0.0 }, "success", &query.time)
+        .played(time_end_nano, source.total_duration(), Some(source.sample_rate()))
+        .monotonic(time_start_monotonic)
+        .for_request(request);
+    log_event(&log_file_name, &format!("{},{},{},{}", time_start_nano, csv_field(audio_file_name), "success", csv_field(&query.time)), &event);
+    if let Some(start_at) = start_at {
+        log_event(&log_file_name, &format!("{},{},success,{}", time_start_nano, csv_field(&format!("{} of {}", scheduled_start_text(start_at, time_start_nano), audio_file_name)), csv_field(&query.time)), &scheduled_start_event(Some(audio_file_name), start_at, time_start_nano, time_start_monotonic, &query.time).for_request(request));
     }
 
-    drop(log_file_name); // release the lock
-    drop(file); // release the lock
-
     HttpResponse::Ok().json(ResponseMessage { message })
 }
 
 #[get("/play/random")]
-async fn play_random(audio_files: web::Data<AudioFiles>, query: web::Query<TimeQuery>, audio_queue_opt: web::Query<RandomAudioQueueOptions>) -> HttpResponse {
+async fn play_random(req: HttpRequest, audio_files: web::Data<AudioFiles>, query: web::Query<TimeQuery>, audio_queue_opt: web::Query<RandomAudioQueueOptions>) -> HttpResponse {
     let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let time_monotonic = monotonic_ns();
     println!("{}: Received /play/random", time_ns);
 
+    let request = RequestInfo::from_request(&req);
+    let start_at = query.start_at.clone();
+    play_off_worker(&start_at, move || play_random_files(&audio_files, &query, &audio_queue_opt, &request, (time_ns, time_monotonic))).await
+}
+
+// Play random audio files into their own log file, for the route above
+fn play_random_files(audio_files: &AudioFiles, query: &TimeQuery, audio_queue_opt: &RandomAudioQueueOptions, request: &RequestInfo, (time_ns, time_monotonic): (u128, u128)) -> HttpResponse {
+
     // If no audio_files are found, return 404
     if audio_files.files.len() == 0 {
         println!("\x1b[2m    \x1b[31mNo audio files found\x1b[0m");
         let message = format!("No audio files found");
         return HttpResponse::NotFound().json(ResponseMessage { message });
     }
+
+    // file_count and max_duration: stop at whichever limit comes first
+    // With max_duration, only the files that fit in it are drawn (see sequence_for_duration). Default to 100 files if neither is specified
+    let max_duration = audio_queue_opt.max_duration;
+    let file_count = if audio_queue_opt.file_count != 0 { audio_queue_opt.file_count } else { 100 };
+
+    // Seed the RNG for this session. The seed goes into the log header so that the same order can be replayed later with ?seed=
+    let seed = resolve_seed(audio_queue_opt.seed);
+    let mut rng = seeded_rng(seed);
+    let audio_file_names = sorted_audio_file_names(&audio_files.files);
+
+    // Draw the whole sequence up front, so that the request fails right away if the constraints can't be met
+    let constraints = match SequenceConstraints::from_query(&audio_queue_opt.sampling, audio_queue_opt.max_repeats, audio_queue_opt.min_distance, &audio_queue_opt.weights, &audio_file_names) {
+        Ok(constraints) => constraints,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    let sequence = if max_duration != 0 {
+        sequence_for_duration(&audio_file_names, &audio_file_durations(&audio_files.files), audio_queue_opt.file_count, max_duration, audio_queue_opt.break_between_files, &constraints, &mut rng)
+    } else {
+        constrained_sequence(&audio_file_names, file_count as usize, &constraints, &mut rng)
+    };
+    let sequence = match sequence {
+        Ok(sequence) => sequence,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    let start_at = match parse_start_at(&query.start_at) {
+        Ok(start_at) => start_at,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
     
     // In case audio device error --> handle this first
     // Linux with ALSA will panic here if there is no audio output device available
     let output_stream_result = std::panic::catch_unwind(|| OutputStream::try_default());
 
     if output_stream_result.is_err() {
-        return handle_audio_error("/play/random", "/play/random", "OutputStream NoDevice", &LOG_FILE_NAME);
+        return handle_audio_error("/play/random", &query.time, "OutputStream NoDevice", &LOG_FILE_NAME, request);
     }
 
     let output_stream_result = output_stream_result.unwrap();
 
     // Windows somehow panics when unwraping the output_stream_result for the same reason (no audio output device available)
     if let Err(e) = output_stream_result {
-        return handle_audio_error("/play/random", "/play/random", &e.to_string(), &LOG_FILE_NAME);
+        return handle_audio_error("/play/random", &query.time, &e.to_string(), &LOG_FILE_NAME, request);
     }
 
     // now safe to unwrap
@@ -120,98 +189,124 @@ async fn play_random(audio_files: web::Data<AudioFiles>, query: web::Query<TimeQ
 
     // Each play random request will have its own log file, and the first line of the log file will be the request start time.
     // Need to start the log file here first.
-    fs::create_dir_all("./logs").unwrap();
-
     // init a new log file name with the current date time, specific for random, though
     let log_file_name_process = chrono::Utc::now().format("logs/log_playrandom_%Y%m%d-%H%M%S").to_string();
 
     // create new log file
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("{}.csv", &log_file_name_process))
-        .unwrap();
-
-    if let Err(e) = writeln!(file, "timestamp_audio,audio_filename,status,timestamp_client") {
-        eprintln!("Couldn't create new file: {}", e);
-    } else {
-        println!("\x1b[1m    Started new log file: ./{}.csv\x1b[0m", &log_file_name_process);
-    }
+    start_log(&log_file_name_process, "timestamp_audio,audio_filename,status,timestamp_client");
+    println!("\x1b[1m    Started new log file: {}\x1b[0m", log_file_names(&log_file_name_process));
+    register_log_file(&log_file_name_process); // session metadata sidecar
 
     // Append to the log file the request start time
-    let text = format!("{},Received /play/random (break: {} ms | file_count: {}),{},{}", time_ns, &audio_queue_opt.break_between_files, audio_queue_opt.file_count, "success", &query.time);
-
-    if let Err(e) = writeln!(file, "{}", text) {
-        eprintln!("Couldn't write to file: {}", e);
-    } else {
-        println!("\x1b[2m    \x1b[38;5;8mAppended request info to log file: {}\x1b[0m\n", &log_file_name_process);
-    }
-
-
-
-    // default to 100 files if file_count is not specified
-    let file_count = if audio_queue_opt.file_count == 0 { 100 } else { audio_queue_opt.file_count };
-
-    let mut remaining_files: u32 = file_count;
-    if remaining_files == 0 {
-        remaining_files = 100;
-    }
-
-    while remaining_files > 0 {
-        // randomly choose a file from the audio_files HashMap
-        let audio_file_name = audio_files.files.keys().nth(rand::random::<usize>() % audio_files.files.len()).unwrap();
+    let request_text = format!("Received /play/random (break: {} ms | file_count: {} | max_duration: {} ms | seed: {} | rng: {} | {})", &audio_queue_opt.break_between_files, audio_queue_opt.file_count, max_duration, seed, RNG_NAME, constraints.describe());
+    let text = format!("{},{},{},{}", time_ns, csv_field(&request_text), "success", csv_field(&query.time));
+    let options = serde_json::json!({
+        "break_ms": audio_queue_opt.break_between_files,
+        "file_count": audio_queue_opt.file_count,
+        "max_duration_ms": max_duration,
+        "seed": seed,
+        "rng": RNG_NAME,
+        "constraints": constraints.describe(),
+    });
+    log_event(&log_file_name_process, &text, &Event::new(time_ns, EventKind::Request { route: "/play/random".to_string(), options }, "success", &query.time).monotonic(time_monotonic).in_job(time_ns).for_request(request));
+
+
+
+    let mut remaining_files: u32 = sequence.len() as u32;
+    let mut played_files: u32 = 0;
+    let mut ended_by = "completion"; // "completion" (the whole sequence was played), "count" (file_count reached) or "duration" (max_duration reached)
+
+    // With start_at, the session (and max_duration) starts at start_at
+    let mut scheduled_start: Option<(u128, u128)> = None; // actual start time of the first file (wall clock and monotonic)
+    let time_ns_playback = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos().max(start_at.unwrap_or(0));
+    // Timing diagnostics: the first file is planned at start_at, and the next ones at the end of the previous file or break
+    let mut received_ns = request.received_ns.filter(|_| start_at.is_none());
+    let mut planned_ns = start_at;
+    for audio_file_name in sequence.iter() {
         let source = audio_files.files.get(audio_file_name).unwrap().clone(); // find decoded audio file by name
 
+        // stop before a file that would not finish within max_duration
+        let file_duration = source.total_duration().map_or(0, |duration| duration.as_millis());
+        if max_duration > 0 && elapsed_ms(time_ns_playback) + file_duration > max_duration as u128 {
+            ended_by = "duration";
+            break;
+        }
+
         // append the audio file to the sink
+        let (nominal_duration, sample_rate) = (source.total_duration(), source.sample_rate());
         sink.append(source);
         
         remaining_files -= 1;
+        played_files += 1;
         
+        let first_scheduled = start_at.filter(|_| played_files == 1);
+        if let Some(start_at) = first_scheduled {
+            println!("\x1b[2m    \x1b[38;5;8mWaiting for start_at {}...\x1b[0m", start_at);
+            wait_until(start_at); // the first file is already buffered in the paused sink
+        }
+        sink.play(); // play the audio file
+        // the onset is read once the sink is started (see diagnostics.rs)
         let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        let time_start_monotonic = monotonic_ns();
+        if first_scheduled.is_some() {
+            scheduled_start = Some((time_start_nano, time_start_monotonic));
+        }
         println!("\x1b[32m    {}: Playing {}...\x1b[0m", time_start_nano, audio_file_name);
-        sink.play(); // play the audio file
+        record_stimulus_onset(StimulusOnset { time_ns: time_start_nano, monotonic_ns: time_start_monotonic, name: audio_file_name.clone(), log_file: format!("{}.csv", &log_file_name_process), log_columns: String::new(), window: None, scored: false, hit: false, event_log: true, position: None });
+        record_onset_timing(received_ns.take(), planned_ns, time_start_nano);
         sink.sleep_until_end(); // play the audio file synchronously. this thread will be blocked until the audio file has finished playing.
+        let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+        planned_ns = Some(time_end_nano);
         
         println!("\x1b[2m    \x1b[38;5;8mFinished (job at {})\x1b[0m", time_start_nano);
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(format!("{}.csv", &log_file_name_process))
-            .unwrap();
-
-        if let Err(e) = writeln!(file, "{},{},{},{}", time_start_nano, audio_file_name, "success", "N/A") {
-            eprintln!("Couldn't write to file: {}", e);
-        } else {
-            println!("\x1b[2m    \x1b[38;5;8mAppended to log file: {}\x1b[0m\n", &log_file_name_process);
-        }
+        let event = Event::new(time_start_nano, EventKind::AudioFile { file: audio_file_name.clone(), gain_db: 0.0 }, "success", "N/A").in_job(time_ns).for_request(request)
+            .played(time_end_nano, nominal_duration, Some(sample_rate)).monotonic(time_start_monotonic);
+        log_event(&log_file_name_process, &format!("{},{},{},{}", time_start_nano, csv_field(audio_file_name), "success", "N/A"), &event);
 
         // if there are more files to play AND the break is not 0, pause for the break duration
         if remaining_files > 0 && audio_queue_opt.break_between_files > 0 {
+            if max_duration > 0 && elapsed_ms(time_ns_playback) + audio_queue_opt.break_between_files as u128 > max_duration as u128 {
+                ended_by = "duration";
+                break;
+            }
+
             let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+            let time_start_monotonic = monotonic_ns();
             println!("\x1b[34m    {}: Pausing for {} ms...\x1b[0m", time_start_nano, &audio_queue_opt.break_between_files);
             pause_sink_duration(&sink, &audio_queue_opt.break_between_files);
+            let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+            planned_ns = Some(time_end_nano);
 
             // Append to the log file here (the "file name" is "pause_{ms}ms")
-            let mut file = OpenOptions::new()
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(format!("{}.csv", &log_file_name_process))
-                .unwrap();
-
-            if let Err(e) = writeln!(file, "{},{},{},{}", time_start_nano, format!("pause_{}ms", &audio_queue_opt.break_between_files), "success", "N/A") {
-                eprintln!("Couldn't write to file: {}", e);
-            } else {
-                println!("\x1b[2m    \x1b[38;5;8mAppended (pause/break/interval) to log file: {}\x1b[0m\n", &log_file_name_process);
-            }
+            let event = Event::new(time_start_nano, EventKind::Pause { duration_ms: audio_queue_opt.break_between_files }, "success", "N/A").in_job(time_ns).for_request(request)
+                .played(time_end_nano, Some(Duration::from_millis(audio_queue_opt.break_between_files as u64)), None).monotonic(time_start_monotonic);
+            log_event(&log_file_name_process, &format!("{},{},{},{}", time_start_nano, format!("pause_{}ms", &audio_queue_opt.break_between_files), "success", "N/A"), &event);
         }
     }
 
+    close_response_window(&format!("{}.csv", &log_file_name_process));
+
+    // The whole sequence was played: max_duration stopped the playback if the sequence was cut to fit it (or if no file fits),
+    // otherwise the count limit did, unless only max_duration was given (file_count or the default 100 files)
+    if ended_by == "completion" {
+        if sequence.is_empty() || (max_duration != 0 && audio_queue_opt.file_count != 0 && sequence.len() < file_count as usize) {
+            ended_by = "duration";
+        } else if audio_queue_opt.file_count != 0 || max_duration == 0 {
+            ended_by = "count";
+        }
+    }
+
+    // Append to the log file how the session ended
+    let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    if let (Some(start_at), Some((scheduled_start, scheduled_start_monotonic))) = (start_at, scheduled_start) {
+        log_event(&log_file_name_process, &format!("{},{},success,N/A", scheduled_start, scheduled_start_text(start_at, scheduled_start)), &scheduled_start_event(None, start_at, scheduled_start, scheduled_start_monotonic, "N/A").in_job(time_ns).for_request(request));
+    }
+    let kind = EventKind::End { route: "/play/random".to_string(), ended_by: ended_by.to_string(), played: played_files as usize, item_count: None, elapsed_ms: elapsed_ms(time_ns_playback) };
+    log_event(&log_file_name_process, &format!("{},End of /play/random (ended by: {} | played: {} files | elapsed: {} ms),success,N/A", time_end_nano, ended_by, played_files, elapsed_ms(time_ns_playback)), &Event::new(time_end_nano, kind, "success", "N/A").in_job(time_ns).for_request(request));
+
     let request_duration = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos() - time_ns;
-    let message = format!("At {} started playing {} random audio files. Took {} seconds.", time_ns, &file_count, (request_duration as f64/ 1_000_000_000 as f64) as f32);
+    let message = format!("At {} started playing {} random audio files (seed: {}, ended by {}). Took {} seconds.", time_ns, played_files, seed, ended_by, (request_duration as f64/ 1_000_000_000 as f64) as f32);
 
     println!("\x1b[1m    \x1b[38;5;8m{}\x1b[0m", message);
 
@@ -1,20 +1,27 @@
 use std::{
-    fs,
-    fs::OpenOptions,
+    collections::HashMap,
     io::Write,
+    time::Duration,
 };
 
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, web, http::StatusCode, HttpRequest, HttpResponse};
 
 use local_ip_address::local_ip;
 
-use rand::Rng;
 use rodio::{OutputStream, Sink, Source};
 use sha256::digest;
 
-use crate::structs::{ResponseMessage, TimeQuery, AudioFiles, PlaylistOptions, Playlists};
-use crate::audio::{handle_audio_error, pause_sink_duration, PlaylistTypes};
-use crate::{LOG_FILE_NAME, PLAYLISTS, PORT};
+use crate::structs::{ResponseMessage, TimeQuery, AudioFiles, PlaylistOptions, PlaylistPlaybackOptions, ResumeOptions, RenderOptions, StimulusOnset, Event, EventKind, PlaylistPreview, PlaylistPreviewItem, StimulusCount, PlaylistLog, OddballOptions, OddballSummary, OddballDeviantSummary, LatinSquareOptions, LatinSquareSummary, RequestInfo};
+use crate::audio::{handle_audio_error, PreparedPlaylist, audio_file_line, db_to_linear, pause_sink_duration, resolve_stimulus, tone_spec, tone_to_source, expand_playlist, playlist_item_lines, playlist_item_duration, jump_line, response_window, elapsed_ms, parse_start_at, wait_until, play_off_worker, scheduled_start_text, BlockShuffle, PlaylistTypes};
+use crate::file_io::{save_playlist_and_reload, read_playlist_log};
+use crate::responses::{record_stimulus_onset, close_response_window};
+use crate::render::{default_render_format, render_steps, encode_render, render_onsets_csv, RenderFormat};
+use crate::randomization::{resolve_seed, seeded_rng, sorted_audio_file_names, audio_file_durations, constrained_sequence, sequence_for_duration, oddball_sequence, latin_square_row, latin_square_row_count, SequenceConstraints, RNG_NAME};
+use crate::logging::{start_log, log_event, csv_field, flush_logs, log_file_names, scheduled_start_event};
+use crate::clock::monotonic_ns;
+use crate::session::register_log_file;
+use crate::diagnostics::record_onset_timing;
+use crate::{LOG_FILE_NAME, PLAYLISTS, PLAYLIST_VARIABLES, PORT};
 
 
 // Create and send back a .txt file containing the playlist
@@ -37,18 +44,46 @@ async fn create_playlist(audio_files: web::Data<AudioFiles>, query: web::Query<P
     let playlist_options = query.into_inner();
     
     // Default playlist options (if the value is 0): file_count = 10
-    let file_count = if playlist_options.file_count == 0 { 10 } else { playlist_options.file_count };
+    // With max_duration, only the files that fit in it are drawn (see sequence_for_duration)
+    let max_duration = playlist_options.max_duration;
+    let file_count = if playlist_options.file_count != 0 { playlist_options.file_count } else { 10 };
     
     let mut total_duration = 0;
 
-    // Pick random audio files from the audio_files HashMap up to the file_count, also count the total duration of the audio files
-    let mut rng = rand::thread_rng();
-    let mut playlist: Vec<String> = Vec::new();
-    for _ in 0..file_count {
-        let random_index = rng.gen_range(0..audio_files.files.len());
-        let random_audio_file_name = audio_files.files.keys().nth(random_index).unwrap();
-        playlist.push(random_audio_file_name.clone());
-        total_duration += audio_files.files.get(random_audio_file_name).unwrap().total_duration().unwrap().as_millis();
+    // Seed the RNG, the seed is written to the playlist header so the same playlist can be re-created with ?seed=
+    let seed = resolve_seed(playlist_options.seed);
+    let mut rng = seeded_rng(seed);
+    let audio_file_names = sorted_audio_file_names(&audio_files.files);
+
+    // Pick random audio files from the audio_files HashMap up to the file_count, following the randomization constraints (if any)
+    let constraints = match SequenceConstraints::from_query(&playlist_options.sampling, playlist_options.max_repeats, playlist_options.min_distance, &playlist_options.weights, &audio_file_names) {
+        Ok(constraints) => constraints,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    let playlist = if max_duration != 0 {
+        sequence_for_duration(&audio_file_names, &audio_file_durations(&audio_files.files), playlist_options.file_count, max_duration, playlist_options.break_between_files, &constraints, &mut rng)
+    } else {
+        constrained_sequence(&audio_file_names, file_count as usize, &constraints, &mut rng)
+    };
+    let mut playlist = match playlist {
+        Ok(sequence) => sequence,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    if playlist.is_empty() {
+        let message = format!("No audio file fits in max_duration ({} ms)", max_duration);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    // Count the total duration of the audio files
+    for audio_file_name in playlist.iter() {
+        total_duration += audio_files.files.get(audio_file_name).unwrap().total_duration().unwrap().as_millis();
     }
 
     // If break_between_files is specified, add the break duration in milliseconds between each audio file, also update the total duration
@@ -67,8 +102,11 @@ async fn create_playlist(audio_files: web::Data<AudioFiles>, query: web::Query<P
         playlist = playlist_with_breaks;
     }
 
+    // The header lines start with "#", and are ignored when the playlist is loaded
+    let header = format!("# seed: {}\n# rng: {}\n# constraints: {}\n# max_duration: {}\n", seed, RNG_NAME, constraints.describe(), max_duration);
+
     // Name: {HashID (8 characters)}_{Duration (in milliseconds)}s_{FileCount}count.txt
-    let output_string = playlist.join("\n");
+    let output_string = format!("{}{}", header, playlist.join("\n"));
     let id = digest(output_string.as_bytes()).chars().take(8).collect::<String>();
 
     // This file name will be used as the return header
@@ -76,32 +114,13 @@ async fn create_playlist(audio_files: web::Data<AudioFiles>, query: web::Query<P
 
 
     // Always update the server-side playlist, then hot reload the playlists
-    fs::create_dir_all("./playlists").unwrap();
-    // Save the new playlist to file
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("./playlists/{}", &playlist_file_name))
-        .unwrap();
-
-    if let Err(e) = writeln!(file, "{}", output_string) {
-        eprintln!("Couldn't create new file: {}", e);
-    } else {
-        println!("\x1b[2m    \x1b[38;5;8mCreated new playlist file server-side: ./playlists/{}\x1b[0m", &playlist_file_name);
-    }
+    save_playlist_and_reload(&playlist_file_name, &output_string, &audio_files);
 
-    // Reload the playlists
-    println!(" !! Hot Reloading Playlists !!");
-    let mut playlists = PLAYLISTS.lock().unwrap();
-    *playlists = Playlists { playlists: crate::load_and_validate_playlists("./playlists", &audio_files.files) };
-    drop(playlists);
-    
 
     // Check for playlist_options.no_download: Change the HTTP response accordingly
     if playlist_options.no_download {
         // If no_download is true, return a JSON response with the playlist file name
-        let message = format!("Created new playlist file server-side: ./playlists/{}. To play this new playlist, visit: http://{}:{}/playlist/{}", &playlist_file_name, local_ip().unwrap(), PORT, &playlist_file_name);
+        let message = format!("Created new playlist file server-side (seed: {}): ./playlists/{}. To play this new playlist, visit: http://{}:{}/playlist/{}", seed, &playlist_file_name, local_ip().unwrap(), PORT, &playlist_file_name);
         return HttpResponse::Ok().json(ResponseMessage { message });
     }
 
@@ -114,48 +133,577 @@ async fn create_playlist(audio_files: web::Data<AudioFiles>, query: web::Query<P
 }
 
 
+// Create an oddball (mismatch negativity) playlist: a standard stimulus with rare deviants
+// The playlist is saved server-side like /playlist/create, and a JSON summary of the actual deviant count and positions is returned
+// The stimuli can be audio file names or tones (tone_{freq}Hz_{duration}ms_{amplitude}dB_@{sample_rate}Hz)
+#[get("/playlist/create/oddball")]
+async fn create_oddball_playlist(audio_files: web::Data<AudioFiles>, query: web::Query<OddballOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /playlist/create/oddball", time_ns);
+
+    let oddball_options = query.into_inner();
+
+    // Default trial count (if the value is 0): 200
+    let trial_count = if oddball_options.trial_count == 0 { 200 } else { oddball_options.trial_count };
+
+    // Validate the stimuli and the probabilities
+    let standard = match resolve_stimulus(&oddball_options.standard, &audio_files.files) {
+        Some(stimulus) => stimulus,
+        None => {
+            let message = format!("Standard stimulus \"{}\" is neither an audio file nor a valid tone", oddball_options.standard);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    let deviant_names: Vec<String> = oddball_options.deviants.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect();
+    let mut deviants = Vec::new();
+    for deviant_name in deviant_names.iter() {
+        match resolve_stimulus(deviant_name, &audio_files.files) {
+            Some(stimulus) => deviants.push(stimulus),
+            None => {
+                let message = format!("Deviant stimulus \"{}\" is neither an audio file nor a valid tone", deviant_name);
+                println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+                return HttpResponse::BadRequest().json(ResponseMessage { message });
+            }
+        }
+    }
+
+    let deviant_probabilities: Vec<f64> = match oddball_options.deviant_probabilities.split(',').map(|p| p.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>() {
+        Ok(probabilities) => probabilities,
+        Err(e) => {
+            let message = format!("Invalid deviant_probabilities: {}", e);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    if deviants.is_empty() || deviants.len() != deviant_probabilities.len() {
+        let message = format!("Expected one probability per deviant, got {} deviants and {} probabilities", deviants.len(), deviant_probabilities.len());
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+    if deviant_probabilities.iter().any(|p| !(0.0..=1.0).contains(p)) || deviant_probabilities.iter().sum::<f64>() > 1.0 {
+        let message = "Deviant probabilities must be between 0 and 1, and add up to at most 1".to_string();
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    // Draw the sequence
+    let seed = resolve_seed(oddball_options.seed);
+    let mut rng = seeded_rng(seed);
+    let sequence = oddball_sequence(trial_count, &deviant_probabilities, oddball_options.min_standards, oddball_options.initial_standards, &mut rng);
+
+    // Build the playlist lines and the summary
+    let mut lines: Vec<String> = Vec::new();
+    let mut total_duration: u128 = 0;
+    let mut standard_count = 0;
+    let mut deviant_summaries: Vec<OddballDeviantSummary> = deviant_names.iter().zip(deviant_probabilities.iter())
+        .map(|(name, probability)| OddballDeviantSummary { stimulus: name.clone(), probability: *probability, count: 0, positions: Vec::new() })
+        .collect();
+
+    for (i, trial) in sequence.iter().enumerate() {
+        if i > 0 && oddball_options.isi > 0 {
+            lines.push(format!("pause_{}ms", oddball_options.isi));
+            total_duration += oddball_options.isi as u128;
+        }
+
+        match trial {
+            Some(deviant) => {
+                lines.push(deviant_names[*deviant].clone());
+                total_duration += deviants[*deviant].1;
+                deviant_summaries[*deviant].count += 1;
+                deviant_summaries[*deviant].positions.push(i as u32 + 1);
+            },
+            None => {
+                lines.push(oddball_options.standard.clone());
+                total_duration += standard.1;
+                standard_count += 1;
+            }
+        }
+    }
+
+    // The header lines start with "#", and are ignored when the playlist is loaded
+    let header = format!(
+        "# paradigm: oddball\n# standard: {}\n# deviants: {}\n# deviant_probabilities: {}\n# trial_count: {}\n# isi: {}\n# min_standards: {}\n# initial_standards: {}\n# seed: {}\n# rng: {}\n",
+        oddball_options.standard, deviant_names.join(","), oddball_options.deviant_probabilities, trial_count, oddball_options.isi, oddball_options.min_standards, oddball_options.initial_standards, seed, RNG_NAME
+    );
+    let output_string = format!("{}{}", header, lines.join("\n"));
+    let id = digest(output_string.as_bytes()).chars().take(8).collect::<String>();
+
+    // Name: playlist_oddball_{HashID (8 characters)}_{Duration}s_{TrialCount}trials.txt
+    let playlist_file_name = format!("playlist_oddball_{}_{:?}s_{}trials.txt", id, total_duration as f64 / 1000.0, trial_count);
+    save_playlist_and_reload(&playlist_file_name, &output_string, &audio_files);
+
+    let deviant_count: u32 = deviant_summaries.iter().map(|deviant| deviant.count).sum();
+    let message = format!("Created oddball playlist server-side (seed: {}): ./playlists/{} with {} deviants in {} trials. To play this new playlist, visit: http://{}:{}/playlist/{}", seed, &playlist_file_name, deviant_count, trial_count, local_ip().unwrap(), PORT, &playlist_file_name);
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+
+    HttpResponse::Ok().json(OddballSummary {
+        message,
+        playlist: playlist_file_name,
+        seed,
+        trial_count,
+        standard: oddball_options.standard,
+        standard_count,
+        deviants: deviant_summaries,
+        total_duration_ms: total_duration,
+    })
+}
+
+
+// Create the counterbalanced playlist of one participant: the condition playlists, concatenated in the order of the
+// participant's row of a (balanced) Latin square. Each condition becomes a block named after its playlist, so the
+// condition of every played item shows up in the block_name column of the playlist log.
+#[get("/playlist/create/latin_square")]
+async fn create_latin_square_playlist(audio_files: web::Data<AudioFiles>, query: web::Query<LatinSquareOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /playlist/create/latin_square", time_ns);
+
+    let latin_square_options = query.into_inner();
+
+    let condition_names: Vec<String> = latin_square_options.playlists.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect();
+    if condition_names.len() < 2 {
+        let message = "At least 2 condition playlists are needed".to_string();
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+    if latin_square_options.participant == 0 {
+        let message = "Participant numbers start at 1".to_string();
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    // make a copy of the condition playlists, and release the lock on PLAYLISTS global
+    let available_playlists = PLAYLISTS.lock().unwrap();
+    let mut conditions = Vec::new();
+    for condition_name in condition_names.iter() {
+        match available_playlists.playlists.get(condition_name) {
+            Some(playlist) => conditions.push(playlist.clone()),
+            None => {
+                let message = format!("Playlist {} not found", condition_name);
+                println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+                return HttpResponse::NotFound().json(ResponseMessage { message });
+            }
+        }
+    }
+    drop(available_playlists);
+
+    // The playback settings of the conditions (# iti, # shuffle, # block_rest) apply to the whole generated playlist,
+    // so the conditions must agree on them. Shuffling the blocks would undo the order of the Latin square,
+    // and the rest between two blocks is block_rest (the rest between two conditions)
+    let mut settings = Vec::new();
+    for (key, default_value) in [("iti", "0"), ("shuffle", "none"), ("block_rest", "0")] {
+        let values: Vec<&str> = conditions.iter().map(|condition| condition.header_value(key).unwrap_or(default_value)).collect();
+        let mismatch = values.iter().zip(condition_names.iter()).find(|(value, _)| **value != values[0]);
+        let error = if let Some((value, condition_name)) = mismatch {
+            Some(format!("The conditions have different \"{}\" headers ({}: {}, {}: {}), the generated playlist can only have one", key, condition_names[0], values[0], condition_name, value))
+        } else if key == "shuffle" && matches!(values[0], "blocks" | "both") {
+            Some(format!("The conditions have \"shuffle: {}\", which would shuffle the order of the Latin square", values[0]))
+        } else if key == "block_rest" && values[0] != latin_square_options.block_rest.to_string() {
+            Some(format!("The conditions have \"block_rest: {}\", set block_rest={} to keep it (it is also the rest between two conditions)", values[0], values[0]))
+        } else {
+            None
+        };
+        if let Some(message) = error {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+        if key != "block_rest" && values[0] != default_value {
+            settings.push(format!("# {}: {}\n", key, values[0]));
+        }
+    }
+
+    // Row of the participant: participants 1, 2, ... get rows 1, 2, ..., and wrap around after the last row
+    let balanced = latin_square_options.balanced;
+    let design = if balanced { "balanced_latin_square" } else { "latin_square" };
+    let row_count = latin_square_row_count(conditions.len(), balanced);
+    let row = (latin_square_options.participant as usize - 1) % row_count;
+    let order = latin_square_row(conditions.len(), row, balanced);
+
+    // Each condition becomes a block. The blocks of a condition playlist are kept (renamed {condition}/{block}),
+    // and its items outside of blocks are grouped into blocks named after the condition.
+    let mut lines: Vec<String> = Vec::new();
+    for i in order.iter() {
+        let condition_name = &condition_names[*i];
+        let mut loose_items: Vec<PlaylistTypes> = Vec::new();
+        for item in conditions[*i].items.iter() {
+            match item {
+                PlaylistTypes::Block(block_name, trials) => {
+                    if !loose_items.is_empty() {
+                        lines.extend(playlist_item_lines(&[PlaylistTypes::Block(condition_name.clone(), std::mem::take(&mut loose_items))]));
+                    }
+                    lines.extend(playlist_item_lines(&[PlaylistTypes::Block(format!("{}/{}", condition_name, block_name), trials.clone())]));
+                },
+                _ => loose_items.push(item.clone()),
+            }
+        }
+        if !loose_items.is_empty() {
+            lines.extend(playlist_item_lines(&[PlaylistTypes::Block(condition_name.clone(), loose_items)]));
+        }
+    }
+
+    let order_names: Vec<String> = order.iter().map(|i| condition_names[*i].clone()).collect();
+
+    // The header lines start with "#", participant and row are also written to the log when the playlist is played
+    let header = format!(
+        "# paradigm: {}\n# conditions: {}\n# participant: {}\n# row: {}\n# row_count: {}\n# order: {}\n# block_rest: {}\n",
+        design, condition_names.join(","), latin_square_options.participant, row + 1, row_count, order_names.join(","), latin_square_options.block_rest
+    );
+    let output_string = format!("{}{}{}", header, settings.concat(), lines.join("\n"));
+    let id = digest(output_string.as_bytes()).chars().take(8).collect::<String>();
+
+    // Name: playlist_{design}_p{Participant}_row{Row}_{HashID (8 characters)}.txt
+    let playlist_file_name = format!("playlist_{}_p{}_row{}_{}.txt", design, latin_square_options.participant, row + 1, id);
+    save_playlist_and_reload(&playlist_file_name, &output_string, &audio_files);
+
+    let message = format!("Created {} playlist server-side for participant {} (row {}/{}): ./playlists/{}. To play this new playlist, visit: http://{}:{}/playlist/{}", design, latin_square_options.participant, row + 1, row_count, &playlist_file_name, local_ip().unwrap(), PORT, &playlist_file_name);
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+
+    HttpResponse::Ok().json(LatinSquareSummary {
+        message,
+        playlist: playlist_file_name,
+        participant: latin_square_options.participant,
+        row: row + 1,
+        row_count,
+        design: design.to_string(),
+        order: order_names,
+    })
+}
+
+
 // Play the playlist
 #[get("/playlist/{playlist_file_name}")]
-async fn play(playlist_file_name: web::Path<String>, audio_files: web::Data<AudioFiles>, query: web::Query<TimeQuery>) -> HttpResponse {
+async fn play(req: HttpRequest, playlist_file_name: web::Path<String>, audio_files: web::Data<AudioFiles>, query: web::Query<TimeQuery>, playback_opt: web::Query<PlaylistPlaybackOptions>) -> HttpResponse {
     let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let time_monotonic = monotonic_ns();
     println!("{}: Received /playlist/{}", time_ns, playlist_file_name);
 
+    let request = RequestInfo::from_request(&req);
+    let start_at = query.start_at.clone();
+    play_off_worker(&start_at, move || play_playlist(&playlist_file_name, &audio_files, &query, &playback_opt, (time_ns, time_monotonic), None, &request)).await
+}
+
+
+// Resume a playlist after the last item of one of its logs (eg. after a break or a crash)
+// The playlist is expanded again with the settings and the seed of the log, and the remaining items are played into a new (linked) log file
+#[get("/playlist/resume/{log_file_name}")]
+async fn resume_playlist(req: HttpRequest, log_file_name: web::Path<String>, audio_files: web::Data<AudioFiles>, query: web::Query<TimeQuery>, resume_opt: web::Query<ResumeOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let time_monotonic = monotonic_ns();
+    println!("{}: Received /playlist/resume/{}", time_ns, log_file_name);
+
+    // the .csv extension is optional
+    let log_file_name = if log_file_name.ends_with(".csv") { log_file_name.to_string() } else { format!("{}.csv", log_file_name) };
+    if !log_file_name.starts_with("log_playlist_") || log_file_name.contains('/') || log_file_name.contains('\\') || log_file_name.contains("..") {
+        let message = format!("{} is not a playlist log file name (eg. log_playlist_20240131-140000.csv)", log_file_name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    flush_logs(); // the log may still be in the buffer of the log writer
+    let log = match read_playlist_log(&format!("./logs/{}", log_file_name)) {
+        Ok(log) => log,
+        Err((status, message)) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::build(status).json(ResponseMessage { message });
+        }
+    };
+
+    // Same block settings and seed as the log, so that the playlist expands (and shuffles) to the same steps
+    let playback_opt = PlaylistPlaybackOptions {
+        iti: log.settings.get("iti").and_then(|iti| iti.parse().ok()),
+        block_rest: log.settings.get("block_rest").and_then(|block_rest| block_rest.parse().ok()),
+        shuffle: log.settings.get("shuffle").cloned(),
+        max_duration: resume_opt.max_duration,
+        seed: log.settings.get("seed").and_then(|seed| seed.parse().ok()),
+    };
+    if playback_opt.seed.is_none() {
+        let message = format!("{} has no seed in its request row, the playback order can't be restored", log_file_name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    println!("\x1b[2m    \x1b[38;5;8mResuming {} after {} logged item(s) (from {})\x1b[0m", log.playlist, log.played_items.len(), log_file_name);
+    let request = RequestInfo::from_request(&req);
+    let start_at = query.start_at.clone();
+    play_off_worker(&start_at, move || play_playlist(&log.playlist.clone(), &audio_files, &query, &playback_opt, (time_ns, time_monotonic), Some((&log_file_name, &log)), &request)).await
+}
+
+// Preview a playlist without playing it: durations and onsets of the items, the audio files and tones used, and the total pause time
+// The playlist is expanded as for the live playback, so the query parameters (iti, block_rest, shuffle, seed, max_duration) are taken into account
+#[get("/playlist/preview/{playlist_file_name}")]
+async fn preview_playlist(playlist_file_name: web::Path<String>, audio_files: web::Data<AudioFiles>, playback_opt: web::Query<PlaylistPlaybackOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /playlist/preview/{}", time_ns, playlist_file_name);
+
+    let PreparedPlaylist { iti, block_rest, shuffle_name, seed, steps, .. } = match prepare_playlist(&playlist_file_name, &audio_files, &playback_opt) {
+        Ok(prepared) => prepared,
+        Err((status, message)) => return HttpResponse::build(status).json(ResponseMessage { message }),
+    };
+
+    let mut items: Vec<PlaylistPreviewItem> = Vec::new();
+    let mut file_counts: HashMap<String, u32> = HashMap::new();
+    let mut tone_counts: HashMap<String, u32> = HashMap::new();
+    let mut total_duration: u128 = 0;
+    let mut total_pause: u128 = 0;
+
+    for step in &steps {
+        let duration = playlist_item_duration(&step.item, &audio_files.files);
+
+        // same stop rule as the live playback
+        if playback_opt.max_duration > 0 && total_duration + duration > playback_opt.max_duration as u128 {
+            break;
+        }
+
+        let (name, item_type) = match &step.item {
+            PlaylistTypes::AudioFiles(audio_file_name, gain) => {
+                *file_counts.entry(audio_file_name.clone()).or_insert(0) += 1;
+                (audio_file_line(audio_file_name, *gain), "file")
+            },
+            PlaylistTypes::Tone(tone) => {
+                *tone_counts.entry(tone_spec(tone)).or_insert(0) += 1;
+                (tone_spec(tone), "tone")
+            },
+            PlaylistTypes::Pause(pause_duration) => {
+                total_pause += duration;
+                (format!("pause_{}ms", pause_duration), "pause")
+            },
+            PlaylistTypes::Branch(branch) => (branch.clone(), "branch"),
+            PlaylistTypes::ResponseWindow(_, _) => continue, // part of the previous stimulus
+            PlaylistTypes::Label(_) | PlaylistTypes::Jump(_, _) => continue, // the playlist variables are only set during live playback, so the jumps are not taken
+            PlaylistTypes::Block(_, _) | PlaylistTypes::Include(_, _, _) | PlaylistTypes::Random(_) => continue, // expanded by expand_playlist
+        };
+
+        items.push(PlaylistPreviewItem {
+            index: items.len() + 1,
+            name,
+            item_type: item_type.to_string(),
+            onset_ms: total_duration,
+            duration_ms: duration,
+            block: step.block.as_ref().map(|(number, _)| *number),
+            block_name: step.block.as_ref().map(|(_, name)| name.clone()),
+            trial: step.trial,
+            source_playlist: step.source.clone(),
+        });
+        total_duration += duration;
+    }
+
+    // sorted by name
+    let sorted_counts = |counts: HashMap<String, u32>| {
+        let mut counts: Vec<StimulusCount> = counts.into_iter().map(|(name, count)| StimulusCount { name, count }).collect();
+        counts.sort_by(|a, b| a.name.cmp(&b.name));
+        counts
+    };
+
+    println!("\x1b[2m    \x1b[38;5;8m{} items, {} ms in total ({} ms of pauses)\x1b[0m", items.len(), total_duration, total_pause);
+
+    HttpResponse::Ok().json(PlaylistPreview {
+        playlist: playlist_file_name.to_string(),
+        seed,
+        iti,
+        block_rest,
+        shuffle: shuffle_name,
+        item_count: items.len(),
+        total_duration_ms: total_duration,
+        total_pause_ms: total_pause,
+        files: sorted_counts(file_counts),
+        tones: sorted_counts(tone_counts),
+        items,
+    })
+}
+
+
+// Render a playlist offline, pauses included, into one audio file. Returns a .zip with the audio file and a CSV of the item onsets (in samples)
+// The playlist is expanded as for the live playback: same query parameters (iti, block_rest, shuffle, seed, max_duration) and playlist header
+#[get("/playlist/render/{playlist_file_name}")]
+async fn render_playlist(playlist_file_name: web::Path<String>, audio_files: web::Data<AudioFiles>, playback_opt: web::Query<PlaylistPlaybackOptions>, render_opt: web::Query<RenderOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /playlist/render/{}", time_ns, playlist_file_name);
+
+    let format = match RenderFormat::parse(&render_opt.format) {
+        Some(format) => format,
+        None => {
+            let message = format!("Unknown format \"{}\". Use wav or flac", render_opt.format);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    if render_opt.sample_rate != 0 && !(1000..=384_000).contains(&render_opt.sample_rate) {
+        let message = format!("sample_rate must be between 1000 and 384000 Hz (got {})", render_opt.sample_rate);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+    if render_opt.channels > 8 {
+        let message = format!("channels must be between 1 and 8 (got {})", render_opt.channels);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    let PreparedPlaylist { seed, steps, .. } = match prepare_playlist(&playlist_file_name, &audio_files, &playback_opt) {
+        Ok(prepared) => prepared,
+        Err((status, message)) => return HttpResponse::build(status).json(ResponseMessage { message }),
+    };
+
+    let (default_sample_rate, default_channels) = default_render_format(&steps, &audio_files.files);
+    let sample_rate = if render_opt.sample_rate == 0 { default_sample_rate.min(format.max_sample_rate()) } else { render_opt.sample_rate };
+    if sample_rate > format.max_sample_rate() {
+        let message = format!("{} renders are limited to {} Hz, use wav for higher sample rates", format.extension(), format.max_sample_rate());
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+    let channels = if render_opt.channels == 0 { default_channels } else { render_opt.channels };
+
+    let render = match render_steps(&steps, &audio_files.files, channels, sample_rate, playback_opt.max_duration) {
+        Ok(render) => render,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::PayloadTooLarge().json(ResponseMessage { message });
+        }
+    };
+    let audio_file = match encode_render(&render, &format) {
+        Ok(audio_file) => audio_file,
+        Err(e) => {
+            let message = format!("Couldn't encode the render: {}", e);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::InternalServerError().json(ResponseMessage { message });
+        }
+    };
+    let onsets = render_onsets_csv(&render);
+
+    // playlist_1.txt --> playlist_1_seed42_48000Hz
+    let render_name = format!("{}_seed{}_{}Hz", playlist_file_name.trim_end_matches(".txt"), seed, sample_rate);
+
+    let mut zip_file = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    zip_file.start_file(format!("{}.{}", render_name, format.extension()), options).unwrap();
+    zip_file.write_all(&audio_file).unwrap();
+    zip_file.start_file(format!("{}_onsets.csv", render_name), options).unwrap();
+    zip_file.write_all(onsets.as_bytes()).unwrap();
+    let zip_file = zip_file.finish().unwrap().into_inner();
+
+    let frames = render.samples.len() / channels as usize;
+    println!("\x1b[2m    \x1b[38;5;8mRendered {}/{} items (ended by {}) to {}.{}: {} Hz, {} channel(s), {:.3} seconds ({} samples)\x1b[0m", render.items.len(), steps.len(), render.ended_by, render_name, format.extension(), sample_rate, channels, frames as f64 / sample_rate as f64, frames);
+
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .append_header(("Content-Disposition", format!("attachment; filename=\"{}.zip\"", render_name)))
+        .body(zip_file)
+}
+
+
+// Look up a loaded playlist and expand it with the playback settings (query parameters, then playlist header)
+// Shared by the live playback and the offline render, so that both resolve the same items in the same order
+// Errors are the status and message of the response (the routes build the JSON response)
+pub fn prepare_playlist(playlist_file_name: &str, audio_files: &AudioFiles, playback_opt: &PlaylistPlaybackOptions) -> Result<PreparedPlaylist, (StatusCode, String)> {
     // If no audio files are found, return 404
     if audio_files.files.len() == 0 {
         println!("\x1b[2m    \x1b[31mNo audio files found\x1b[0m");
         let message = format!("No audio files found");
-        return HttpResponse::NotFound().json(ResponseMessage { message });
+        return Err((StatusCode::NOT_FOUND, message));
     }
 
     let available_playlists = PLAYLISTS.lock().unwrap();
 
     // If the playlist file name is not found in the available_playlists HashMap, return 404
-    if !available_playlists.playlists.contains_key(&playlist_file_name.to_string()) {
+    if !available_playlists.playlists.contains_key(playlist_file_name) {
         println!("\x1b[2m    \x1b[31mPlaylist file name not found\x1b[0m");
         let message = format!("Playlist file name not found");
-        return HttpResponse::NotFound().json(ResponseMessage { message });
+        return Err((StatusCode::NOT_FOUND, message));
     }
 
     // If the playlist file name is found in the available_playlists HashMap, but the playlist is empty, return 404
-    if available_playlists.playlists.get(&playlist_file_name.to_string()).unwrap().len() == 0 {
+    if available_playlists.playlists.get(playlist_file_name).unwrap().items.len() == 0 {
         println!("\x1b[2m    \x1b[31mPlaylist is empty\x1b[0m");
         let message = format!("Playlist is empty");
-        return HttpResponse::NotFound().json(ResponseMessage { message });
+        return Err((StatusCode::NOT_FOUND, message));
     }
 
+    // Get the playlist from the available_playlists HashMap
+    let playlist = available_playlists.playlists.get(playlist_file_name).unwrap().clone();
+
+    drop(available_playlists); // release the lock on global PLAYLISTS
+
+    // Block settings: the query parameters override the playlist header ("# iti: 500", ...), which were validated when loading the playlist
+    let iti = playback_opt.iti.unwrap_or_else(|| playlist.header_value("iti").map_or(0, |v| v.parse().unwrap()));
+    let block_rest = playback_opt.block_rest.unwrap_or_else(|| playlist.header_value("block_rest").map_or(0, |v| v.parse().unwrap()));
+    let shuffle_name = playback_opt.shuffle.clone().unwrap_or_else(|| playlist.header_value("shuffle").unwrap_or("none").to_string());
+    let shuffle = match BlockShuffle::parse(&shuffle_name) {
+        Some(shuffle) => shuffle,
+        None => {
+            let message = format!("Unknown shuffle \"{}\". Use none, blocks, trials or both", shuffle_name);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return Err((StatusCode::BAD_REQUEST, message));
+        }
+    };
+
+    // Expand the blocks (if any) into the list of steps to play
+    let seed = resolve_seed(playback_opt.seed);
+    let mut rng = seeded_rng(seed);
+    let steps = expand_playlist(&playlist, iti, block_rest, shuffle, &mut rng);
+
+    Ok(PreparedPlaylist { playlist, iti, block_rest, shuffle_name, seed, steps })
+}
+
+// Play a validated playlist and write its playlist log, shared by the routes above and the scheduler
+// With resume (log file name and its content), only the items after the last logged item are played, into a new log linked to the resumed one
+// time_ns is the time of the request (also the job id of the rows), with the monotonic time read with it
+pub fn play_playlist(playlist_file_name: &str, audio_files: &AudioFiles, query: &TimeQuery, playback_opt: &PlaylistPlaybackOptions, (time_ns, time_monotonic): (u128, u128), resume: Option<(&str, &PlaylistLog)>, request: &RequestInfo) -> HttpResponse {
+    let PreparedPlaylist { playlist, iti, block_rest, shuffle_name, seed, steps } = match prepare_playlist(playlist_file_name, audio_files, playback_opt) {
+        Ok(prepared) => prepared,
+        Err((status, message)) => return HttpResponse::build(status).json(ResponseMessage { message }),
+    };
+    let max_duration = playback_opt.max_duration;
+
+    // When resuming, the logged items must be the same as the expanded steps (the playlist file may have changed since)
+    let mut first_step = 0;
+    if let Some((resumed_log_name, resumed_log)) = resume {
+        // the jumps depend on the playlist variables at playback time, so the logged items can't be matched to the steps
+        if steps.iter().any(|step| matches!(step.item, PlaylistTypes::Jump(_, _))) {
+            let message = format!("Playlist {} has jumps, and can't be resumed", playlist_file_name);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+        // the response windows and the labels have no rows: the logged items are matched to the other steps (logged_steps are their indices)
+        let logged_steps: Vec<usize> = (0..steps.len()).filter(|i| !matches!(steps[*i].item, PlaylistTypes::ResponseWindow(_, _) | PlaylistTypes::Label(_))).collect();
+        let step_names = playlist_item_lines(&logged_steps.iter().map(|i| steps[*i].item.clone()).collect::<Vec<PlaylistTypes>>());
+        let first_logged = logged_steps.iter().position(|i| *i >= resumed_log.first_item).unwrap_or(logged_steps.len());
+        let end_logged = first_logged + resumed_log.played_items.len();
+        if end_logged > logged_steps.len() || step_names[first_logged..end_logged] != resumed_log.played_items[..] {
+            let message = format!("The items of {} don't match playlist {} (was the playlist edited?)", resumed_log_name, playlist_file_name);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::Conflict().json(ResponseMessage { message });
+        }
+        // resume right after the last logged item (its response window, if any, was closed with the resumed playback)
+        first_step = if end_logged > first_logged { logged_steps[end_logged - 1] + 1 } else { resumed_log.first_item };
+        if end_logged == logged_steps.len() {
+            let message = format!("Playlist {} was already played to the end in {}", playlist_file_name, resumed_log_name);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    }
+
+    let start_at = match parse_start_at(&query.start_at) {
+        Ok(start_at) => start_at,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
     // In case audio device error --> handle this first
     // Linux with ALSA will panic here if there is no audio output device available
     let output_stream_result = std::panic::catch_unwind(|| OutputStream::try_default());
 
     if output_stream_result.is_err() {
-        return handle_audio_error("/play/random", "/play/random", "OutputStream NoDevice", &LOG_FILE_NAME);
+        return handle_audio_error(playlist_file_name, &query.time, "OutputStream NoDevice", &LOG_FILE_NAME, request);
     }
 
     let output_stream_result = output_stream_result.unwrap();
 
     // Windows somehow panics when unwraping the output_stream_result for the same reason (no audio output device available)
     if let Err(e) = output_stream_result {
-        return handle_audio_error("/play/random", "/play/random", &e.to_string(), &LOG_FILE_NAME);
+        return handle_audio_error(playlist_file_name, &query.time, &e.to_string(), &LOG_FILE_NAME, request);
     }
 
     // now safe to unwrap
@@ -168,99 +716,246 @@ async fn play(playlist_file_name: web::Path<String>, audio_files: web::Data<Audi
 
     // Each play request will have its own log file, and the first line of the log file will be the request start time.
     // Need to start the log file here first.
-    fs::create_dir_all("./logs").unwrap();
-
     // init a new log file name with the current date time, specific for playlist, though
     let log_file_name_process = chrono::Utc::now().format("logs/log_playlist_%Y%m%d-%H%M%S").to_string();
 
     // create new log file
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("{}.csv", &log_file_name_process))
-        .unwrap();
-
-    if let Err(e) = writeln!(file, "timestamp_audio,audio_filename,status,timestamp_client") {
-        eprintln!("Couldn't create new file: {}", e);
-    } else {
-        println!("\x1b[1m    Started new log file: ./{}.csv\x1b[0m", &log_file_name_process);
+    // the playlist logs have 4 extra columns: block (number, in playback order), block_name, trial (number within the block, in playback order)
+    // and source_playlist (the included playlist the item comes from, see include_ in the playlist files)
+    start_log(&log_file_name_process, "timestamp_audio,audio_filename,status,timestamp_client,block,block_name,trial,source_playlist");
+    println!("\x1b[1m    Started new log file: {}\x1b[0m", log_file_names(&log_file_name_process));
+    register_log_file(&log_file_name_process); // session metadata sidecar
+
+    // Counterbalanced playlists (see /playlist/create/latin_square) also log the participant and the Latin square row
+    let mut counterbalancing = String::new();
+    if let (Some(participant), Some(row)) = (playlist.header_value("participant"), playlist.header_value("row")) {
+        counterbalancing = format!(" | participant: {} | row: {}", participant, row);
     }
 
-    // Append to the log file the request start time
-    let text = format!("{},Received /playlist/{},{},{}", time_ns, playlist_file_name, "success", &query.time);
+    // Continuation logs name the playlist, the resumed log and the first item played (starting at 1)
+    let (request_path, resume_info) = match resume {
+        Some((resumed_log_name, _)) => (format!("resume/{}", resumed_log_name), format!(" | playlist: {} | resumes: {} | first_item: {}", playlist_file_name, resumed_log_name, first_step + 1)),
+        None => (playlist_file_name.to_string(), String::new()),
+    };
 
-    if let Err(e) = writeln!(file, "{}", text) {
-        eprintln!("Couldn't write to file: {}", e);
-    } else {
-        println!("\x1b[2m    \x1b[38;5;8mAppended request info to log file: {}\x1b[0m\n", &log_file_name_process);
+    // Append to the log file the request start time
+    let request_text = format!("Received /playlist/{} (iti: {} ms | block_rest: {} ms | shuffle: {} | max_duration: {} ms | seed: {} | rng: {}{}{})", request_path, iti, block_rest, shuffle_name, max_duration, seed, RNG_NAME, counterbalancing, resume_info);
+    let text = format!("{},{},{},{},,,,", time_ns, csv_field(&request_text), "success", csv_field(&query.time));
+    let mut options = serde_json::json!({
+        "iti_ms": iti,
+        "block_rest_ms": block_rest,
+        "shuffle": shuffle_name,
+        "max_duration_ms": max_duration,
+        "seed": seed,
+        "rng": RNG_NAME,
+    });
+    if let (Some(participant), Some(row)) = (playlist.header_value("participant"), playlist.header_value("row")) {
+        options["participant"] = participant.into();
+        options["row"] = row.into();
     }
+    if let Some((resumed_log_name, _)) = resume {
+        options["playlist"] = playlist_file_name.into();
+        options["resumes"] = resumed_log_name.into();
+        options["first_item"] = (first_step + 1).into();
+    }
+    log_event(&log_file_name_process, &text, &Event::new(time_ns, EventKind::Request { route: format!("/playlist/{}", request_path), options }, "success", &query.time).monotonic(time_monotonic).in_job(time_ns).for_request(request));
 
-    
-    // Get the playlist from the available_playlists HashMap
-    let playlist = available_playlists.playlists.get(&playlist_file_name.to_string()).unwrap().clone();
-
-    drop(available_playlists); // release the lock on global PLAYLISTS
+    // Link the resumed log to its continuation
+    if let Some((resumed_log_name, _)) = resume {
+        let kind = EventKind::Continued { continued_in: format!("./{}.csv", &log_file_name_process), from_item: first_step + 1, item_count: steps.len() };
+        log_event(&format!("./logs/{}", resumed_log_name), &format!("{},{},success,N/A,,,,", time_ns, csv_field(&format!("Continued in ./{}.csv (from item: {}/{})", &log_file_name_process, first_step + 1, steps.len()))), &Event::new(time_ns, kind, "success", "N/A").monotonic(time_monotonic).for_request(request));
+    }
 
     // One option here is to just append all of the audio files in the playlist to the sink
     // However, the trade off is that, we don't really know when which audio file is playing --> not as verbose
     // So? Append and play each audio file one by one, and log the start time of each audio file (or pause, of course)
     // Will have a bit of delay between each audio file, but that should be alright
-    let time_ns_playback = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
-    for (i, audio_file_name) in playlist.iter().enumerate() {
-        // See if this is a pause or an audio file by checking its PlayListTypes (AudioFiles or Pause)
-        match audio_file_name {
-            PlaylistTypes::AudioFiles(audio_file_name) => {
-                // Append the audio file to the sink
-                let audio_file = audio_files.files.get(audio_file_name).unwrap();
-                sink.append(audio_file.clone());
+    let mut played_steps = 0;
+    let mut ended_by = "completion"; // "completion" (the whole playlist was played) or "duration" (max_duration reached)
+
+    // With start_at, the playback (and max_duration) starts at start_at
+    let mut scheduled_start: Option<(u128, u128)> = None; // actual start time of the first item (wall clock and monotonic)
+    let time_ns_playback = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos().max(start_at.unwrap_or(0));
+    // Response windows ("respond_" lines): the responses (/response) to this playlist are scored as hits, misses and false alarms
+    let scored = steps.iter().any(|step| matches!(step.item, PlaylistTypes::ResponseWindow(_, _)));
+    let log_file = format!("{}.csv", &log_file_name_process);
+    // Timing diagnostics: the first item is planned at start_at, and the next ones at the end of the previous item
+    let mut received_ns = request.received_ns.filter(|_| start_at.is_none());
+    let mut planned_ns = start_at;
+
+    let mut next_step = first_step;
+    let mut jumps_in_a_row = 0; // jumps taken without playing anything in between, to stop on a jump loop
+    while next_step < steps.len() {
+        let i = next_step;
+        let step = &steps[i];
+        next_step += 1;
+
+        // stop before an item that would not finish within max_duration
+        if max_duration > 0 && elapsed_ms(time_ns_playback) + playlist_item_duration(&step.item, &audio_files.files) > max_duration as u128 {
+            ended_by = "duration";
+            break;
+        }
+        played_steps += 1;
 
-                let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
-                println!("\x1b[32m    [{}/{}] {}: Playing {}...\x1b[0m", i+1, playlist.len(), time_start_nano, audio_file_name);
+        // block, block_name, trial and source_playlist columns (empty outside of blocks and includes)
+        let log_columns = step.log_columns();
+
+        // See if this is a pause or an audio file by checking its PlayListTypes (AudioFiles, Tone or Pause)
+        match &step.item {
+            PlaylistTypes::AudioFiles(audio_file_name, gain) => {
+                // Append the audio file to the sink, with its gain ("1000.wav gain_-6dB" in the playlist)
+                let audio_file = audio_files.files.get(audio_file_name).unwrap();
+                let (nominal_duration, sample_rate) = (audio_file.total_duration(), audio_file.sample_rate());
+                sink.append(audio_file.clone().amplify(db_to_linear(*gain)));
+                let file_name = audio_file_name;
+                let audio_file_name = audio_file_line(audio_file_name, *gain); // logged as in the playlist file
+
+                let first_scheduled = start_at.filter(|_| scheduled_start.is_none());
+                if let Some(start_at) = first_scheduled {
+                    wait_until(start_at); // the first item is already buffered in the paused sink
+                }
                 sink.play(); // play the audio file
+                // the onset is read once the sink is started (see diagnostics.rs)
+                let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                let time_start_monotonic = monotonic_ns();
+                if first_scheduled.is_some() {
+                    scheduled_start = Some((time_start_nano, time_start_monotonic));
+                }
+                println!("\x1b[32m    [{}/{}] {}: Playing {}...\x1b[0m", i+1, steps.len(), time_start_nano, audio_file_name);
+                record_stimulus_onset(StimulusOnset { time_ns: time_start_nano, monotonic_ns: time_start_monotonic, name: audio_file_name.clone(), log_file: log_file.clone(), log_columns: format!(",{}", log_columns), window: response_window(&steps, i), scored, hit: false, event_log: true, position: Some(step.position(i)) });
+                record_onset_timing(received_ns.take(), planned_ns, time_start_nano);
                 sink.sleep_until_end(); // play the audio file synchronously. this thread will be blocked until the audio file has finished playing.
+                let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                planned_ns = Some(time_end_nano);
                 
                 println!("\x1b[2m    \x1b[38;5;8mFinished (job at {})\x1b[0m", time_start_nano);
 
-                let mut file = OpenOptions::new()
-                    .write(true)
-                    .append(true)
-                    .create(true)
-                    .open(format!("{}.csv", &log_file_name_process))
-                    .unwrap();
-
-                if let Err(e) = writeln!(file, "{},{},{},{}", time_start_nano, audio_file_name, "success", "N/A") {
-                    eprintln!("Couldn't write to file: {}", e);
-                } else {
-                    println!("\x1b[2m    \x1b[38;5;8mAppended to log file: {}\x1b[0m\n", &log_file_name_process);
+                let kind = EventKind::AudioFile { file: file_name.clone(), gain_db: *gain };
+                let event = Event::new(time_start_nano, kind, "success", "N/A").in_job(time_ns).for_request(request).at(Some(step.position(i)))
+                    .played(time_end_nano, nominal_duration, Some(sample_rate)).monotonic(time_start_monotonic);
+                log_event(&log_file_name_process, &format!("{},{},{},{},{}", time_start_nano, csv_field(&audio_file_name), "success", "N/A", log_columns), &event);
+            },
+
+            PlaylistTypes::Tone(tone) => {
+                // Generate the tone and append it to the sink
+                let tone_name = tone_spec(tone);
+                sink.append(tone_to_source(&tone.freq, &tone.duration, &tone.amplitude, &tone.sample_rate));
+
+                let first_scheduled = start_at.filter(|_| scheduled_start.is_none());
+                if let Some(start_at) = first_scheduled {
+                    wait_until(start_at); // the first item is already buffered in the paused sink
                 }
+                sink.play(); // play the tone
+                // the onset is read once the sink is started (see diagnostics.rs)
+                let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                let time_start_monotonic = monotonic_ns();
+                if first_scheduled.is_some() {
+                    scheduled_start = Some((time_start_nano, time_start_monotonic));
+                }
+                println!("\x1b[32m    [{}/{}] {}: Playing {}...\x1b[0m", i+1, steps.len(), time_start_nano, tone_name);
+                record_stimulus_onset(StimulusOnset { time_ns: time_start_nano, monotonic_ns: time_start_monotonic, name: tone_name.clone(), log_file: log_file.clone(), log_columns: format!(",{}", log_columns), window: response_window(&steps, i), scored, hit: false, event_log: true, position: Some(step.position(i)) });
+                record_onset_timing(received_ns.take(), planned_ns, time_start_nano);
+                sink.sleep_until_end(); // play the tone synchronously. this thread will be blocked until the tone has finished playing.
+                let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                planned_ns = Some(time_end_nano);
+
+                println!("\x1b[2m    \x1b[38;5;8mFinished (job at {})\x1b[0m", time_start_nano);
+
+                let kind = EventKind::Tone { frequency_hz: tone.freq, duration_ms: tone.duration, amplitude_db: tone.amplitude, sample_rate_hz: tone.sample_rate };
+                let event = Event::new(time_start_nano, kind, "success", "N/A").in_job(time_ns).for_request(request).at(Some(step.position(i)))
+                    .played(time_end_nano, Some(Duration::from_millis(tone.duration as u64)), Some(tone.sample_rate)).monotonic(time_start_monotonic);
+                log_event(&log_file_name_process, &format!("{},{},{},{},{}", time_start_nano, tone_name, "success", "N/A", log_columns), &event);
             },
 
             PlaylistTypes::Pause(pause_duration) => {
                 // If this is a pause, pause the sink for the specified duration
-                let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
-                println!("\x1b[34m    [{}/{}] {}: Pausing for {} milliseconds...\x1b[0m", i+1, playlist.len(), time_start_nano, pause_duration);
-                pause_sink_duration(&sink, &pause_duration);
-
-                let mut file = OpenOptions::new()
-                    .write(true)
-                    .append(true)
-                    .create(true)
-                    .open(format!("{}.csv", &log_file_name_process))
-                    .unwrap();
-
-                if let Err(e) = writeln!(file, "{},{},{},{}", time_start_nano, format!("pause_{}ms", pause_duration), "success", "N/A") {
-                    eprintln!("Couldn't write to file: {}", e);
-                } else {
-                    println!("\x1b[2m    \x1b[38;5;8mAppended to log file: {}\x1b[0m\n", &log_file_name_process);
+                let mut time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                let mut time_start_monotonic = monotonic_ns();
+                if let (Some(start_at), true) = (start_at, scheduled_start.is_none()) {
+                    time_start_nano = wait_until(start_at);
+                    time_start_monotonic = monotonic_ns();
+                    scheduled_start = Some((time_start_nano, time_start_monotonic));
                 }
-            }
+                println!("\x1b[34m    [{}/{}] {}: Pausing for {} milliseconds...\x1b[0m", i+1, steps.len(), time_start_nano, pause_duration);
+                pause_sink_duration(&sink, pause_duration);
+                let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                received_ns = None; // the receipt-to-onset latency is only measured for a playlist starting with a stimulus
+                planned_ns = Some(time_end_nano);
+
+                let kind = EventKind::Pause { duration_ms: *pause_duration };
+                log_event(&log_file_name_process, &format!("{},{},{},{},{}", time_start_nano, format!("pause_{}ms", pause_duration), "success", "N/A", log_columns), &Event::new(time_start_nano, kind, "success", "N/A").in_job(time_ns).for_request(request).at(Some(step.position(i))).played(time_end_nano, Some(Duration::from_millis(*pause_duration as u64)), None).monotonic(time_start_monotonic));
+            },
+
+            PlaylistTypes::Branch(branch) => {
+                // the random option was chosen by expand_playlist (with the seed), log it before its items
+                let time_branch_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                println!("\x1b[35m    [{}/{}] {}: Taking {}\x1b[0m", i+1, steps.len(), time_branch_nano, branch);
+
+                let kind = EventKind::Branch { option: branch.clone() };
+                log_event(&log_file_name_process, &format!("{},{},success,N/A,{}", time_branch_nano, branch, log_columns), &Event::new(time_branch_nano, kind, "success", "N/A").in_job(time_ns).for_request(request).at(Some(step.position(i))));
+                continue; // not played, so not the end of a jump loop
+            },
+
+            PlaylistTypes::Label(_) | PlaylistTypes::ResponseWindow(_, _) => continue, // the response windows are set with their stimulus
+
+            PlaylistTypes::Jump(label, condition) => {
+                // the condition is checked now, with the playlist variables set over HTTP (/variables/set)
+                let variables = PLAYLIST_VARIABLES.lock().unwrap();
+                let taken = condition.as_ref().is_none_or(|condition| condition.is_met(&variables));
+                let variable_value = condition.as_ref().map(|condition| variables.get(&condition.variable).cloned().unwrap_or_default());
+                let variable_text = condition.as_ref().map_or(String::new(), |condition| format!(" ({}: {})", condition.variable, variable_value.as_deref().unwrap_or("")));
+                drop(variables); // release the lock on PLAYLIST_VARIABLES global
+                if !taken {
+                    continue;
+                }
+
+                jumps_in_a_row += 1;
+                if jumps_in_a_row > steps.len() {
+                    println!("\x1b[2m    \x1b[31mJump loop without any item to play, stopping the playlist\x1b[0m");
+                    ended_by = "jump loop";
+                    break;
+                }
+
+                // the labels were validated when loading the playlist, but the label may be in a random option that was not taken
+                let jump_text = format!("{}{}", jump_line(label, condition), variable_text);
+                let time_jump_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+                let (status, target) = match steps.iter().position(|target| matches!(&target.item, PlaylistTypes::Label(target_label) if target_label == label)) {
+                    Some(target) => ("success", target),
+                    None => ("error", i + 1),
+                };
+                println!("\x1b[35m    [{}/{}] {}: {} {} --> [{}/{}]\x1b[0m", i+1, steps.len(), time_jump_nano, if status == "success" { "Jumping" } else { "Label not in this playback, ignoring" }, jump_text, target+1, steps.len());
+
+                let kind = EventKind::Jump {
+                    line: jump_line(label, condition),
+                    label: label.clone(),
+                    variable: condition.as_ref().map(|condition| condition.variable.clone()),
+                    value: variable_value,
+                    target_item: (status == "success").then_some(target + 1),
+                };
+                log_event(&log_file_name_process, &format!("{},{},{},N/A,{}", time_jump_nano, csv_field(&jump_text), status, log_columns), &Event::new(time_jump_nano, kind, status, "N/A").in_job(time_ns).for_request(request).at(Some(step.position(i))));
+                next_step = target;
+                continue;
+            },
+
+            PlaylistTypes::Block(_, _) | PlaylistTypes::Include(_, _, _) | PlaylistTypes::Random(_) => {} // expanded by expand_playlist
         }
+        jumps_in_a_row = 0;
+    }
+
+    // The responses to the last stimulus are still scored until the end of its response window
+    close_response_window(&log_file);
+
+    // Append to the log file how the playback ended
+    let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    if let (Some(start_at), Some((scheduled_start, scheduled_start_monotonic))) = (start_at, scheduled_start) {
+        log_event(&log_file_name_process, &format!("{},{},success,N/A,,,,", scheduled_start, scheduled_start_text(start_at, scheduled_start)), &scheduled_start_event(None, start_at, scheduled_start, scheduled_start_monotonic, "N/A").in_job(time_ns).for_request(request));
     }
+    let kind = EventKind::End { route: format!("/playlist/{}", request_path), ended_by: ended_by.to_string(), played: first_step + played_steps, item_count: Some(steps.len()), elapsed_ms: elapsed_ms(time_ns_playback) };
+    log_event(&log_file_name_process, &format!("{},{},success,N/A,,,,", time_end_nano, csv_field(&format!("End of /playlist/{} (ended by: {} | played: {}/{} items | elapsed: {} ms)", request_path, ended_by, first_step + played_steps, steps.len(), elapsed_ms(time_ns_playback)))), &Event::new(time_end_nano, kind, "success", "N/A").in_job(time_ns).for_request(request));
 
     let request_duration = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos() - time_ns;
     let playback_duration = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos() - time_ns_playback;
-    let message = format!("At {} started playlist {} ({} audio files). Playback took {} seconds. Total time since request: {} seconds.", time_ns, playlist_file_name, playlist.len(), playback_duration as f64 / 1_000_000_000.0, request_duration as f64 / 1_000_000_000.0);
+    let message = format!("At {} started playlist {} ({}/{} items, ended by {}). Playback took {} seconds. Total time since request: {} seconds.", time_ns, playlist_file_name, first_step + played_steps, steps.len(), ended_by, playback_duration as f64 / 1_000_000_000.0, request_duration as f64 / 1_000_000_000.0);
 
     println!("\x1b[1m    \x1b[38;5;8m{}\x1b[0m", message);
 
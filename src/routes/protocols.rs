@@ -0,0 +1,139 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::structs::{ResponseMessage, TimeQuery, AudioFiles, ProtocolOptions, RequestInfo};
+use crate::protocol::{ProtocolRun, load_protocol, create_protocol_log, run_protocol, now_ns};
+use crate::logging::{log_row, csv_field};
+use crate::clock::monotonic_ns;
+use crate::PROTOCOL;
+
+
+// Start a protocol (./protocols/{name}.txt) in the background: its steps run one after the other, and the wait steps wait for /protocol/continue
+// Only one protocol can run at a time
+#[get("/protocol/start/{name}")]
+async fn start_protocol(req: HttpRequest, audio_files: web::Data<AudioFiles>, name: web::Path<String>, protocol_opt: web::Query<ProtocolOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /protocol/start/{}", time_ns, name);
+
+    let steps = match load_protocol(&name, &audio_files.files) {
+        Ok(steps) => steps,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    let from_step = protocol_opt.from_step.max(1);
+    if from_step > steps.len() {
+        let message = format!("from_step {} is out of range (protocol {} has {} steps)", from_step, name, steps.len());
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    let mut protocol = PROTOCOL.lock().unwrap();
+    if let Some(run) = protocol.as_ref().filter(|run| run.is_active()) {
+        let message = format!("Protocol {} is already {} (step {}), stop it first with /protocol/stop", run.name, run.state, run.step + 1);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::Conflict().json(ResponseMessage { message });
+    }
+
+    let log_file = create_protocol_log(&name, from_step, steps.len(), time_ns, &protocol_opt.time, &RequestInfo::from_request(&req));
+    *protocol = Some(ProtocolRun {
+        name: name.to_string(),
+        steps,
+        step: from_step - 1,
+        state: "running".to_string(),
+        continue_requested: false,
+        stop_requested: false,
+        error: None,
+        log_file,
+        started_ns: time_ns,
+        step_started_ns: time_ns,
+        ended_ns: None,
+    });
+    let status = protocol.as_ref().unwrap().status();
+    drop(protocol); // release the lock on PROTOCOL global
+
+    let audio_files = audio_files.clone();
+    std::thread::spawn(move || {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_protocol(audio_files)));
+        if result.is_err() {
+            println!("\x1b[2m    \x1b[31mProtocol panicked\x1b[0m");
+            let mut protocol = PROTOCOL.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(run) = protocol.as_mut().filter(|run| run.is_active()) {
+                let (failed_ns, failed_monotonic) = (now_ns(), monotonic_ns());
+                let step_line = run.steps.get(run.step).map(|step| step.line()).unwrap_or_default();
+                run.state = "failed".to_string();
+                run.ended_ns = Some(failed_ns);
+                run.error = Some(format!("Step {} ({}): panicked", run.step + 1, step_line));
+                log_row(&run.log_file, &format!("{},{},error,N/A,{},{},", failed_ns, csv_field(&format!("Step {} failed ({} | panicked)", run.step + 1, step_line)), run.step + 1, csv_field(&step_line)), failed_monotonic, None);
+            }
+            PROTOCOL.clear_poison(); // if it panicked while holding the lock, so that the protocol routes keep working
+        }
+    });
+
+    println!("\x1b[36m    Started protocol {} ({} steps, from step {})\x1b[0m", name, status.step_count, from_step);
+    HttpResponse::Ok().json(status)
+}
+
+
+// State of the current (or last) protocol: running, waiting, finished, stopped or failed, with the current step
+#[get("/protocol/status")]
+async fn protocol_status() -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /protocol/status", time_ns);
+
+    match PROTOCOL.lock().unwrap().as_ref() {
+        Some(run) => HttpResponse::Ok().json(run.status()),
+        None => {
+            let message = "No protocol has been started".to_string();
+            HttpResponse::NotFound().json(ResponseMessage { message })
+        }
+    }
+}
+
+
+// Open the current wait step of the protocol, and continue with the next step
+#[get("/protocol/continue")]
+async fn continue_protocol(req: HttpRequest, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /protocol/continue", time_ns);
+
+    let mut protocol = PROTOCOL.lock().unwrap();
+    let run = match protocol.as_mut().filter(|run| run.state == "waiting" && !run.continue_requested) {
+        Some(run) => run,
+        None => {
+            let message = "The protocol is not waiting for /protocol/continue".to_string();
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::Conflict().json(ResponseMessage { message });
+        }
+    };
+    run.continue_requested = true;
+    log_row(&run.log_file, &format!("{},Received /protocol/continue (step {}),success,{},{},,", time_ns, run.step + 1, csv_field(&query.time), run.step + 1), monotonic_ns(), Some(&RequestInfo::from_request(&req)));
+
+    let message = format!("Continued protocol {} at step {}", run.name, run.step + 1);
+    println!("\x1b[36m    {}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+// Stop the protocol: at the end of the current playback step, or right away during a rest or a wait
+#[get("/protocol/stop")]
+async fn stop_protocol(req: HttpRequest, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /protocol/stop", time_ns);
+
+    let mut protocol = PROTOCOL.lock().unwrap();
+    let run = match protocol.as_mut().filter(|run| run.is_active()) {
+        Some(run) => run,
+        None => {
+            let message = "No protocol is running".to_string();
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::Conflict().json(ResponseMessage { message });
+        }
+    };
+    run.stop_requested = true;
+    log_row(&run.log_file, &format!("{},Received /protocol/stop (step {}),success,{},{},,", time_ns, run.step + 1, csv_field(&query.time), run.step + 1), monotonic_ns(), Some(&RequestInfo::from_request(&req)));
+
+    let message = format!("Stopping protocol {} (step {})", run.name, run.step + 1);
+    println!("\x1b[36m    {}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
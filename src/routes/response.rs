@@ -0,0 +1,31 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::structs::{ResponseMessage, TimeQuery, RequestInfo};
+use crate::responses::record_response;
+use crate::LOG_FILE_NAME;
+
+
+// Log a participant response (eg. a button press) with its latency from the onset of the most recent stimulus
+// In a playlist with response windows ("respond_{start}-{end}ms" lines), the response is also scored as a hit or a false alarm
+#[get("/response/{label}")]
+async fn response(req: HttpRequest, label: web::Path<String>, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /response/{}", time_ns, label);
+
+    // the label is written to the CSV logs
+    if label.is_empty() || label.contains([',', '\n', '(', ')']) {
+        let message = "Invalid response label: it can't contain , ( or )".to_string();
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    let main_log_file = format!("{}.csv", *LOG_FILE_NAME.lock().unwrap());
+    let summary = record_response(&label, time_ns, &query.time, &main_log_file, &RequestInfo::from_request(&req));
+
+    match (&summary.stimulus, summary.latency_ms) {
+        (Some(stimulus), Some(latency_ms)) => println!("\x1b[35m    Response {} to {} after {:.3} ms{}\x1b[0m", label, stimulus, latency_ms, summary.outcome.as_ref().map_or(String::new(), |outcome| format!(" ({})", outcome))),
+        _ => println!("\x1b[35m    Response {} (no stimulus)\x1b[0m", label),
+    }
+
+    HttpResponse::Ok().json(summary)
+}
@@ -0,0 +1,166 @@
+use actix_web::{get, web, HttpResponse};
+
+use crate::structs::{ResponseMessage, AudioFiles, Schedule, ScheduleOptions, ScheduleSummary};
+use crate::scheduler::{load_schedules, save_schedules, validate_schedule, next_run_text, SCHEDULES_FILE};
+use crate::{PLAYLISTS, SCHEDULES};
+
+
+// List all the schedules, with their next run time
+#[get("/schedules")]
+async fn list_schedules() -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /schedules", time_ns);
+
+    let schedules = SCHEDULES.lock().unwrap();
+    let summaries: Vec<ScheduleSummary> = schedules.schedules.iter().map(|schedule| ScheduleSummary {
+        name: schedule.name.clone(),
+        cron: schedule.cron.clone(),
+        target_type: schedule.target_type.clone(),
+        target: schedule.target.clone(),
+        enabled: schedule.enabled,
+        running: schedules.running.contains(&schedule.name),
+        next_run: next_run_text(schedule),
+    }).collect();
+    drop(schedules); // release the lock on SCHEDULES global
+
+    HttpResponse::Ok().json(summaries)
+}
+
+
+// Add a new schedule (or replace the schedule with the same name)
+#[get("/schedules/add")]
+async fn add_schedule(audio_files: web::Data<AudioFiles>, schedule_opt: web::Query<ScheduleOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /schedules/add ({})", time_ns, schedule_opt.name);
+
+    // Exactly one of playlist or file
+    let (target_type, target) = match (schedule_opt.playlist.is_empty(), schedule_opt.file.is_empty()) {
+        (false, true) => ("playlist", schedule_opt.playlist.clone()),
+        (true, false) => ("file", schedule_opt.file.clone()),
+        _ => {
+            let message = "Specify either playlist or file (not both)".to_string();
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    let name = schedule_opt.name.trim().to_string();
+    let cron = schedule_opt.cron.trim().to_string();
+    if let Err(message) = validate_schedule(&name, &cron, target_type, &target) {
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    // The target must be loaded on the server
+    let target_found = if target_type == "playlist" {
+        PLAYLISTS.lock().unwrap().playlists.contains_key(&target)
+    } else {
+        audio_files.files.contains_key(&target)
+    };
+    if !target_found {
+        let message = format!("{} {} not found", if target_type == "playlist" { "Playlist" } else { "Audio file" }, target);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::NotFound().json(ResponseMessage { message });
+    }
+
+    let schedule = Schedule {
+        name: name.clone(),
+        cron: cron.clone(),
+        target_type: target_type.to_string(),
+        target: target.clone(),
+        enabled: schedule_opt.enabled,
+    };
+
+    let mut schedules = SCHEDULES.lock().unwrap();
+    let replaced = match schedules.schedules.iter().position(|s| s.name == name) {
+        Some(index) => {
+            schedules.schedules[index] = schedule;
+            true
+        },
+        None => {
+            schedules.schedules.push(schedule);
+            false
+        },
+    };
+    save_schedules(SCHEDULES_FILE, &schedules.schedules);
+    drop(schedules); // release the lock on SCHEDULES global
+
+    let message = format!("{} schedule {}: {} {} at \"{}\" ({})", if replaced { "Replaced" } else { "Added" }, name, target_type, target, cron, if schedule_opt.enabled { "enabled" } else { "disabled" });
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+#[get("/schedules/enable/{name}")]
+async fn enable_schedule(name: web::Path<String>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /schedules/enable/{}", time_ns, name);
+
+    set_schedule_enabled(&name, true)
+}
+
+#[get("/schedules/disable/{name}")]
+async fn disable_schedule(name: web::Path<String>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /schedules/disable/{}", time_ns, name);
+
+    set_schedule_enabled(&name, false)
+}
+
+// Enable or disable a schedule and save the schedules file. A run already in progress is not stopped
+fn set_schedule_enabled(name: &str, enabled: bool) -> HttpResponse {
+    let mut schedules = SCHEDULES.lock().unwrap();
+    let schedule = match schedules.schedules.iter_mut().find(|s| s.name == name) {
+        Some(schedule) => schedule,
+        None => {
+            let message = format!("Schedule {} not found", name);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    };
+    schedule.enabled = enabled;
+    save_schedules(SCHEDULES_FILE, &schedules.schedules);
+    drop(schedules); // release the lock on SCHEDULES global
+
+    let message = format!("Schedule {} {}", name, if enabled { "enabled" } else { "disabled" });
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+#[get("/schedules/remove/{name}")]
+async fn remove_schedule(name: web::Path<String>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /schedules/remove/{}", time_ns, name);
+
+    let mut schedules = SCHEDULES.lock().unwrap();
+    let schedule_count = schedules.schedules.len();
+    schedules.schedules.retain(|s| s.name != *name);
+    if schedules.schedules.len() == schedule_count {
+        let message = format!("Schedule {} not found", name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::NotFound().json(ResponseMessage { message });
+    }
+    save_schedules(SCHEDULES_FILE, &schedules.schedules);
+    drop(schedules); // release the lock on SCHEDULES global
+
+    let message = format!("Schedule {} removed", name);
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+// Reload the schedules file, after editing it by hand
+#[get("/schedules/reload")]
+async fn reload_schedules() -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /schedules/reload", time_ns);
+
+    let mut schedules = SCHEDULES.lock().unwrap();
+    schedules.schedules = load_schedules(SCHEDULES_FILE);
+    let message = format!("Loaded {} schedule(s) from {} ({} enabled)", schedules.schedules.len(), SCHEDULES_FILE, schedules.schedules.iter().filter(|s| s.enabled).count());
+    drop(schedules); // release the lock on SCHEDULES global
+
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
@@ -0,0 +1,125 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::structs::{ResponseMessage, TimeQuery, Session, SessionOptions, Event, EventKind, RequestInfo};
+use crate::logging::{log_event, csv_field, flush_logs};
+use crate::routes::startnewlog::start_new_log_file;
+use crate::session::write_sidecar;
+use crate::{CLIENT_CLOCKS, LOG_FILE_NAME, SESSION};
+
+
+// Start a session with its participant, experiment, experimenter and free-form key/values, and a new main log file
+// Every log file written until /session/end gets a sidecar JSON with this metadata ({log}_session.json)
+#[get("/session/start")]
+async fn start_session(req: HttpRequest, session_opt: web::Query<SessionOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /session/start (participant: {})", time_ns, session_opt.participant);
+
+    // the participant is part of the session ID, and the participant, experiment and experimenter are written to the CSV logs
+    let participant = session_opt.participant.trim();
+    let experiment = session_opt.experiment.trim();
+    let experimenter = session_opt.experimenter.trim();
+    let invalid = participant.is_empty()
+        || participant.contains(['/', '\\', ' '])
+        || [participant, experiment, experimenter].iter().any(|value| value.contains([',', '\n', '|']));
+    if invalid {
+        let message = "Invalid session: the participant is required, and can't contain spaces, / or \\. The participant, experiment and experimenter can't contain , or |".to_string();
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    let mut session = SESSION.lock().unwrap();
+    if let Some(session) = session.as_ref() {
+        let message = format!("Session {} is already active, end it first with /session/end", session.session_id);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::Conflict().json(ResponseMessage { message });
+    }
+
+    let now = chrono::Local::now();
+    *session = Some(Session {
+        session_id: format!("{}_{}", participant, now.format("%Y%m%d-%H%M%S")),
+        participant: participant.to_string(),
+        experiment: experiment.to_string(),
+        experimenter: experimenter.to_string(),
+        fields: session_opt.fields.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+        started_at: now.to_rfc3339(),
+        ended_at: None,
+        log_files: Vec::new(),
+        client_clocks: CLIENT_CLOCKS.lock().unwrap().clone().into_iter().collect(), // registered before the session started
+    });
+    drop(session); // release the lock on SESSION global, the new log file is registered to the session
+
+    // each session has its own main log file
+    start_new_log_file();
+
+    let session = SESSION.lock().unwrap().clone().unwrap();
+    write_main_log_row(&format!(
+        "{},Session started (session_id: {} | participant: {} | experiment: {} | experimenter: {}),success,{}",
+        time_ns, session.session_id, session.participant, session.experiment, session.experimenter, csv_field(&session_opt.time)
+    ), &Event::new(time_ns, session_event("started", &session), "success", &session_opt.time).for_request(&RequestInfo::from_request(&req)));
+
+    println!("\x1b[36m    Started session {}\x1b[0m", session.session_id);
+    HttpResponse::Ok().json(session)
+}
+
+
+// The current session, with the log files written so far (JSON)
+#[get("/session")]
+async fn current_session() -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /session", time_ns);
+
+    match SESSION.lock().unwrap().as_ref() {
+        Some(session) => HttpResponse::Ok().json(session),
+        None => {
+            let message = "No active session. Start one with /session/start".to_string();
+            HttpResponse::NotFound().json(ResponseMessage { message })
+        }
+    }
+}
+
+
+// End the current session: the sidecar JSON of all its log files are updated with the end time and the full list of log files
+// The client clock offsets (/clock/offset) are cleared
+#[get("/session/end")]
+async fn end_session(req: HttpRequest, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /session/end", time_ns);
+
+    let mut session = match SESSION.lock().unwrap().take() {
+        Some(session) => session,
+        None => {
+            let message = "No active session".to_string();
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::Conflict().json(ResponseMessage { message });
+        }
+    };
+
+    write_main_log_row(&format!("{},Session ended (session_id: {} | log files: {}),success,{}", time_ns, session.session_id, session.log_files.len(), csv_field(&query.time)), &Event::new(time_ns, session_event("ended", &session), "success", &query.time).for_request(&RequestInfo::from_request(&req)));
+
+    session.ended_at = Some(chrono::Local::now().to_rfc3339());
+    CLIENT_CLOCKS.lock().unwrap().clear(); // the client clock offsets are registered for the session
+    for log_file in &session.log_files {
+        write_sidecar(log_file, &session);
+    }
+    flush_logs(); // the logs of the session are complete on disk
+
+    println!("\x1b[36m    Ended session {} ({} log files)\x1b[0m", session.session_id, session.log_files.len());
+    HttpResponse::Ok().json(session)
+}
+
+
+fn session_event(action: &str, session: &Session) -> EventKind {
+    EventKind::Session {
+        action: action.to_string(),
+        session_id: session.session_id.clone(),
+        participant: session.participant.clone(),
+        experiment: session.experiment.clone(),
+        experimenter: session.experimenter.clone(),
+        log_file_count: session.log_files.len(),
+    }
+}
+
+fn write_main_log_row(row: &str, event: &Event) {
+    let log_file_name = LOG_FILE_NAME.lock().unwrap().clone();
+    log_event(&log_file_name, row, event);
+}
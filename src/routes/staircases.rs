@@ -0,0 +1,202 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use rodio::Source;
+
+use crate::structs::{ResponseMessage, TimeQuery, AudioFiles, StaircaseOptions, StimulusOnset, RequestInfo};
+use crate::staircase::{Staircase, StaircaseStimulus, create_staircase_log};
+use crate::responses::record_stimulus_onset;
+use crate::diagnostics::record_onset_timing;
+use crate::logging::{log_row, csv_field};
+use crate::clock::monotonic_ns;
+use crate::audio::{tone_to_source, db_to_linear, play_source_blocking, handle_audio_error};
+use crate::{LOG_FILE_NAME, STAIRCASES};
+
+
+// Start a new adaptive staircase (replaces any staircase with the same name), with its own log file
+// The trials are played with /staircase/play/{name}, and answered with /staircase/answer/{name}/{answer}
+#[get("/staircase/start")]
+async fn start_staircase(req: HttpRequest, audio_files: web::Data<AudioFiles>, staircase_opt: web::Query<StaircaseOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /staircase/start (name: {})", time_ns, staircase_opt.name);
+
+    let mut staircase = match Staircase::from_options(&staircase_opt) {
+        Ok(staircase) => staircase,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+    if let StaircaseStimulus::File(audio_file_name) = &staircase.stimulus {
+        if !audio_files.files.contains_key(audio_file_name) {
+            println!("\x1b[2m    \x1b[31mAudio file Not Found\x1b[0m");
+            let message = format!("Audio file {} not found", audio_file_name);
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    }
+
+    create_staircase_log(&mut staircase, time_ns, &staircase_opt.time, &RequestInfo::from_request(&req));
+    let summary = staircase.summary();
+
+    let mut staircases = STAIRCASES.lock().unwrap();
+    if staircases.insert(staircase.name.clone(), staircase).is_some() {
+        println!("\x1b[2m    \x1b[33mReplaced the previous staircase {}\x1b[0m", summary.name);
+    }
+    drop(staircases); // release the lock on STAIRCASES global
+
+    println!("\x1b[36m    Started staircase {} ({} | {} at {} dB)\x1b[0m", summary.name, summary.rule, summary.stimulus, summary.level);
+    HttpResponse::Ok().json(summary)
+}
+
+
+// Play the next trial of a staircase at its current level (synchronously, like /play and /tone)
+// The previous trial must have been answered first
+#[get("/staircase/play/{name}")]
+async fn play_staircase(req: HttpRequest, audio_files: web::Data<AudioFiles>, name: web::Path<String>, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /staircase/play/{}", time_ns, name);
+
+    // prepare the trial, and release the lock on STAIRCASES during playback (so that the other staircases can be used)
+    let mut staircases = STAIRCASES.lock().unwrap();
+    let staircase = match staircases.get_mut(name.as_str()) {
+        Some(staircase) => staircase,
+        None => {
+            println!("\x1b[2m    \x1b[31mStaircase Not Found\x1b[0m");
+            let message = format!("Staircase {} not found. Start it with /staircase/start", name);
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    };
+    let conflict = if let Some(stopped_by) = &staircase.stopped_by {
+        Some(format!("Staircase {} is finished (stopped by {}), see /staircase/summary/{}", name, stopped_by, name))
+    } else if staircase.awaiting_answer {
+        Some(format!("Trial {} of staircase {} has not been answered yet", staircase.trial, name))
+    } else {
+        None
+    };
+    if let Some(message) = conflict {
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::Conflict().json(ResponseMessage { message });
+    }
+
+    staircase.trial += 1;
+    staircase.awaiting_answer = true; // the answer can be sent during playback
+    let trial = staircase.trial;
+    let level = staircase.level;
+    let stimulus_name = staircase.stimulus_name();
+    let log_file = staircase.log_file.clone();
+    let reversal_count = staircase.reversals.len();
+    let tone = match &staircase.stimulus {
+        StaircaseStimulus::Tone(tone) => Some(tone.clone()),
+        StaircaseStimulus::File(_) => None,
+    };
+    let audio_file_name = match &staircase.stimulus {
+        StaircaseStimulus::File(audio_file_name) => audio_file_name.clone(),
+        StaircaseStimulus::Tone(_) => String::new(),
+    };
+    drop(staircases); // release the lock on STAIRCASES global
+
+    // responses (/response) during the trial are written to the staircase log
+    let on_start = |time_start_nano: u128, time_start_monotonic: u128| {
+        println!("\x1b[2m    \x1b[38;5;8m{}: Started trial {} of staircase {}: {}...\x1b[0m", time_start_nano, trial, name, stimulus_name);
+        record_stimulus_onset(StimulusOnset { time_ns: time_start_nano, monotonic_ns: time_start_monotonic, name: stimulus_name.clone(), log_file: log_file.clone(), log_columns: format!(",{},{},,,{},", trial, level, reversal_count), window: None, scored: false, hit: false, event_log: false, position: None });
+    };
+    let result = match tone {
+        Some(tone) => play_source_blocking(tone_to_source(&tone.freq, &tone.duration, &level, &tone.sample_rate), on_start),
+        None => match audio_files.files.get(&audio_file_name) {
+            Some(source) => play_source_blocking(source.clone().amplify(db_to_linear(level)), on_start),
+            None => Err(format!("Audio file {} not found", audio_file_name)), // removed since the start of the staircase
+        },
+    };
+
+    let (time_start_nano, time_start_monotonic) = match result {
+        Ok((time_start_nano, time_start_monotonic)) => {
+            record_onset_timing(RequestInfo::from_request(&req).received_ns, None, time_start_nano);
+            (time_start_nano, time_start_monotonic)
+        },
+        Err(e) => {
+            // the trial was not played: it can be played again (unless the staircase was restarted under the same name during playback)
+            if let Some(staircase) = STAIRCASES.lock().unwrap().get_mut(name.as_str()).filter(|staircase| staircase.log_file == log_file) {
+                staircase.trial = staircase.trial.saturating_sub(1);
+                staircase.awaiting_answer = false;
+            }
+            let time_error_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+            log_row(&log_file, &format!("{},{},error,{},{},{},,,{},", time_error_nano, csv_field(&stimulus_name), csv_field(&query.time), trial, level, reversal_count), monotonic_ns(), Some(&RequestInfo::from_request(&req)));
+            return handle_audio_error(&stimulus_name, &query.time, &e, &LOG_FILE_NAME, &RequestInfo::from_request(&req));
+        }
+    };
+    println!("\x1b[2m    \x1b[38;5;8mFinished (job at {})\x1b[0m", time_start_nano);
+
+    log_row(&log_file, &format!("{},{},success,{},{},{},,,{},", time_start_nano, csv_field(&stimulus_name), csv_field(&query.time), trial, level, reversal_count), time_start_monotonic, Some(&RequestInfo::from_request(&req)));
+
+    let message = format!("At {} played trial {} of staircase {}: {}", time_start_nano, trial, name, stimulus_name);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+// Answer the last trial of a staircase: correct (also yes or 1) or incorrect (also no or 0)
+// Returns the summary of the staircase, with the level of the next trial, the reversals and the threshold estimate once finished
+#[get("/staircase/answer/{name}/{answer}")]
+async fn answer_staircase(req: HttpRequest, path: web::Path<(String, String)>, query: web::Query<TimeQuery>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let (name, answer) = path.into_inner();
+    println!("{}: Received /staircase/answer/{}/{}", time_ns, name, answer);
+
+    let correct = match answer.to_lowercase().as_str() {
+        "correct" | "yes" | "1" => true,
+        "incorrect" | "no" | "0" => false,
+        _ => {
+            let message = format!("Invalid answer \"{}\". Use correct or incorrect (also yes/no or 1/0)", answer);
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
+
+    let mut staircases = STAIRCASES.lock().unwrap();
+    let staircase = match staircases.get_mut(&name) {
+        Some(staircase) => staircase,
+        None => {
+            println!("\x1b[2m    \x1b[31mStaircase Not Found\x1b[0m");
+            let message = format!("Staircase {} not found. Start it with /staircase/start", name);
+            return HttpResponse::NotFound().json(ResponseMessage { message });
+        }
+    };
+    if !staircase.awaiting_answer {
+        let message = format!("Staircase {} has no trial to answer, play one with /staircase/play/{}", name, name);
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::Conflict().json(ResponseMessage { message });
+    }
+
+    let step = staircase.answer(correct);
+    let answer_text = if correct { "correct" } else { "incorrect" };
+    log_row(&staircase.log_file, &format!(
+        "{},Answer {} (trial {}),success,{},{},{},{},{},{},{}",
+        time_ns, answer_text, staircase.trial, csv_field(&query.time), staircase.trial, step.level, answer_text, step.reversal, staircase.reversals.len(), step.next_level
+    ), monotonic_ns(), Some(&RequestInfo::from_request(&req)));
+    println!("\x1b[36m    Trial {} at {} dB: {}{} --> next level {} dB\x1b[0m", staircase.trial, step.level, answer_text, if step.reversal { " (reversal)" } else { "" }, step.next_level);
+
+    if let Some(stopped_by) = &staircase.stopped_by {
+        let threshold_text = staircase.threshold().map_or("N/A".to_string(), |threshold| format!("{} dB", threshold));
+        log_row(&staircase.log_file, &format!(
+            "{},End (stopped by: {} | threshold: {} | reversals averaged: {}),success,N/A,{},,,,{},",
+            time_ns, stopped_by, threshold_text, (staircase.threshold_reversals as usize).min(staircase.reversals.len()), staircase.trial, staircase.reversals.len()
+        ), monotonic_ns(), None);
+        println!("\x1b[1m    Staircase {} finished after {} trials ({} reversals): threshold {}\x1b[0m", name, staircase.trial, staircase.reversals.len(), threshold_text);
+    }
+
+    HttpResponse::Ok().json(staircase.summary())
+}
+
+
+// Current state of a staircase: level, trials, reversal levels and threshold estimate (mean level of the last reversals)
+#[get("/staircase/summary/{name}")]
+async fn staircase_summary(name: web::Path<String>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /staircase/summary/{}", time_ns, name);
+
+    match STAIRCASES.lock().unwrap().get(name.as_str()) {
+        Some(staircase) => HttpResponse::Ok().json(staircase.summary()),
+        None => {
+            println!("\x1b[2m    \x1b[31mStaircase Not Found\x1b[0m");
+            let message = format!("Staircase {} not found", name);
+            HttpResponse::NotFound().json(ResponseMessage { message })
+        }
+    }
+}
@@ -1,13 +1,10 @@
-use std::{
-    fs,
-    fs::OpenOptions,
-    io::Write,
-};
-
 use actix_web::{get, HttpResponse, Responder};
 use chrono::Utc;
 
 use crate::structs::ResponseMessage;
+use crate::logging::{start_log, log_file_names};
+use crate::clock::log_clock_sync;
+use crate::session::register_log_file;
 use crate::LOG_FILE_NAME;
 
 
@@ -17,30 +14,24 @@ async fn start_new_log() -> impl Responder {
     let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
     println!("{}: Received /startnewlog", time_ns);
 
-    // make sure the log folder exists
-    fs::create_dir_all("./logs").unwrap();
+    let message = start_new_log_file();
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
 
+// Start a new main log file, named after the current date time. Shared by the route above, the sessions and the protocol runner
+pub fn start_new_log_file() -> String {
     let mut log_file_name = LOG_FILE_NAME.lock().unwrap();
     *log_file_name = Utc::now().format("logs/log_%Y%m%d-%H%M%S").to_string(); // update log file name
 
     // create new log file
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("{}.csv", *log_file_name))
-        .unwrap();
-    let mut message = format!("Started new log file: ./logs/{}.csv", *log_file_name);
+    let message = format!("Started new log file: {}", log_file_names(&log_file_name));
+    let message_log_file = log_file_name.clone();
     drop(log_file_name);
 
-    if let Err(e) = writeln!(file, "timestamp_audio,audio_filename,status,timestamp_client") {
-        eprintln!("Couldn't create new file: {}", e);
-
-        message = format!("Error: Couldn't create new file: {}", e);
-    }
-
-    drop(file);
+    start_log(&message_log_file, "timestamp_audio,audio_filename,status,timestamp_client");
+    log_clock_sync(&message_log_file);
+    register_log_file(&message_log_file); // session metadata sidecar
 
     println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
-    HttpResponse::Ok().json(ResponseMessage { message })
-}
\ No newline at end of file
+    message
+}
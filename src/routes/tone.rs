@@ -1,24 +1,41 @@
-use std::{
-    fs,
-    fs::OpenOptions,
-    io::Write,
-};
+use std::time::Duration;
 
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use rodio::{OutputStream, Sink, Source};
 
-use crate::structs::{ResponseMessage, TimeQuery, Tone};
-use crate::audio::{tone_to_source, tone_to_wav_file, handle_audio_error};
+use crate::structs::{ResponseMessage, TimeQuery, Tone, StimulusOnset, Event, EventKind, RequestInfo};
+use crate::responses::record_stimulus_onset;
+use crate::diagnostics::record_onset_timing;
+use crate::audio::{tone_to_source, tone_to_wav_file, tone_spec, handle_audio_error, parse_start_at, wait_until, play_off_worker, scheduled_start_text};
+use crate::logging::{log_event, csv_field, scheduled_start_event};
+use crate::clock::monotonic_ns;
 use crate::LOG_FILE_NAME;
 
 #[get("/tone/{freq}/{duration}/{amplitude}/{sample_rate}")]
-async fn play_tone(tone: web::Path<Tone>, query: web::Query<TimeQuery>) -> HttpResponse {
+async fn play_tone(req: HttpRequest, tone: web::Path<Tone>, query: web::Query<TimeQuery>) -> HttpResponse {
     let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
 
+    let tone = tone.into_inner();
+    println!("{}: Received /tone/{}/{}/{}/{}", time_ns, tone.freq, tone.duration, tone.amplitude, tone.sample_rate);
+
+    let request = RequestInfo::from_request(&req);
+    let start_at = query.start_at.clone();
+    play_off_worker(&start_at, move || play_tone_and_log(tone, &query, &request)).await
+}
+
+// Play a pure tone and append it to the main log, shared by the route above and the protocol runner
+pub fn play_tone_and_log(tone: Tone, query: &TimeQuery, request: &RequestInfo) -> HttpResponse {
     // destructure the Tone struct to get the values
-    let Tone { freq, duration, amplitude, sample_rate } = tone.into_inner();
+    let audio_file_name = tone_spec(&tone);
+    let Tone { freq, duration, amplitude, sample_rate } = tone;
 
-    println!("{}: Received /tone/{}/{}/{}/{}", time_ns, freq, duration, amplitude, sample_rate);
+    let start_at = match parse_start_at(&query.start_at) {
+        Ok(start_at) => start_at,
+        Err(message) => {
+            println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+            return HttpResponse::BadRequest().json(ResponseMessage { message });
+        }
+    };
 
     // create a pure sine tone
     let source = tone_to_source(&freq, &duration, &amplitude, &sample_rate);
@@ -27,14 +44,14 @@ async fn play_tone(tone: web::Path<Tone>, query: web::Query<TimeQuery>) -> HttpR
     let output_stream_result = std::panic::catch_unwind(|| OutputStream::try_default());
 
     if output_stream_result.is_err() {
-        return handle_audio_error(&freq.to_string(), &duration.to_string(), "OutputStream NoDevice", &LOG_FILE_NAME);
+        return handle_audio_error(&audio_file_name, &query.time, "OutputStream NoDevice", &LOG_FILE_NAME, request);
     }
 
     let output_stream_result = output_stream_result.unwrap();
 
     // Windows somehow panics when unwraping the output_stream_result for the same reason (no audio output device available)
     if let Err(e) = output_stream_result {
-        return handle_audio_error(&freq.to_string(), &duration.to_string(), &e.to_string(), &LOG_FILE_NAME);
+        return handle_audio_error(&audio_file_name, &query.time, &e.to_string(), &LOG_FILE_NAME, request);
     }
 
     // now safe to unwrap
@@ -44,14 +61,24 @@ async fn play_tone(tone: web::Path<Tone>, query: web::Query<TimeQuery>) -> HttpR
     println!("\x1b[2m    \x1b[38;5;8mSource's Sample Rate: {} Hz\x1b[0m", source.sample_rate());
 
     let sink = Sink::try_new(&stream_handle).unwrap();
+    sink.pause(); // buffer the tone in the paused sink, then start it right away (or at start_at)
     sink.append(source); // init the sink with the audio file
 
-    let audio_file_name = format!("tone_{}Hz_{}ms_{}dB_@{}Hz", freq, duration, amplitude, sample_rate);
-
+    if let Some(start_at) = start_at {
+        println!("\x1b[2m    \x1b[38;5;8mWaiting for start_at {}...\x1b[0m", start_at);
+        wait_until(start_at);
+    }
+    sink.play();
+    // the onset is read once the sink is started (see diagnostics.rs)
     let time_start_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    let time_start_monotonic = monotonic_ns();
+    let log_file = format!("{}.csv", *LOG_FILE_NAME.lock().unwrap());
+    record_stimulus_onset(StimulusOnset { time_ns: time_start_nano, monotonic_ns: time_start_monotonic, name: audio_file_name.clone(), log_file, log_columns: String::new(), window: None, scored: false, hit: false, event_log: true, position: None });
+    record_onset_timing(request.received_ns.filter(|_| start_at.is_none()), start_at, time_start_nano); // the wait for start_at is not a latency
     
     println!("\x1b[2m    \x1b[38;5;8m{}: Started {}...\x1b[0m", time_start_nano, audio_file_name);
     sink.sleep_until_end(); // play the audio file synchronously. this thread will be blocked until the audio file has finished playing.
+    let time_end_nano = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
     println!("\x1b[2m    \x1b[38;5;8mFinished (job at {})\x1b[0m", time_start_nano);
 
     let message = format!("At {} played {}", time_start_nano, audio_file_name);
@@ -60,24 +87,17 @@ async fn play_tone(tone: web::Path<Tone>, query: web::Query<TimeQuery>) -> HttpR
     drop(_stream);
     
     // Append to the log file
-    fs::create_dir_all("./logs").unwrap(); // make sure the logs/ folder exists first
-
-    let log_file_name = LOG_FILE_NAME.lock().unwrap();
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(format!("{}.csv", *log_file_name))
-        .unwrap();
-    if let Err(e) = writeln!(file, "{},{},{},{}", time_start_nano, audio_file_name, "success", &query.time) {
-        eprintln!("Couldn't write to file: {}", e);
-    } else {
-        println!("\x1b[2m    \x1b[38;5;8mAppended to log file: {}\x1b[0m", *log_file_name);
+    let log_file_name = LOG_FILE_NAME.lock().unwrap().clone();
+    let kind = EventKind::Tone { frequency_hz: freq, duration_ms: duration, amplitude_db: amplitude, sample_rate_hz: sample_rate };
+    let event = Event::new(time_start_nano, kind, "success", &query.time)
+        .played(time_end_nano, Some(Duration::from_millis(duration as u64)), Some(sample_rate))
+        .monotonic(time_start_monotonic)
+        .for_request(request);
+    log_event(&log_file_name, &format!("{},{},{},{}", time_start_nano, audio_file_name, "success", csv_field(&query.time)), &event);
+    if let Some(start_at) = start_at {
+        log_event(&log_file_name, &format!("{},{} of {},success,{}", time_start_nano, scheduled_start_text(start_at, time_start_nano), audio_file_name, csv_field(&query.time)), &scheduled_start_event(Some(&audio_file_name), start_at, time_start_nano, time_start_monotonic, &query.time).for_request(request));
     }
 
-    drop(log_file_name); // release the lock
-    drop(file); // release the lock
-
     HttpResponse::Ok().json(ResponseMessage { message })
 }
 
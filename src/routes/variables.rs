@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::structs::{ResponseMessage, VariableOptions, Event, EventKind, RequestInfo};
+use crate::logging::{log_event, csv_field};
+use crate::{LOG_FILE_NAME, PLAYLIST_VARIABLES};
+
+
+// List the playlist variables and their values (JSON object, sorted by name)
+#[get("/variables")]
+async fn list_variables() -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /variables", time_ns);
+
+    let variables: BTreeMap<String, String> = PLAYLIST_VARIABLES.lock().unwrap().clone().into_iter().collect();
+    HttpResponse::Ok().json(variables)
+}
+
+
+// Set a playlist variable, checked by the conditional jumps of the playlists ("jump_{label} if_{variable}={value}")
+// Can be sent while a playlist is playing: the next jump line uses the new value
+#[get("/variables/set")]
+async fn set_variable(req: HttpRequest, variable_opt: web::Query<VariableOptions>) -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /variables/set ({}={})", time_ns, variable_opt.name, variable_opt.value);
+
+    // the variable names and values are written to the CSV logs
+    let name = variable_opt.name.trim();
+    let value = variable_opt.value.trim();
+    if name.is_empty() || name.contains(['=', '!', ' ', ',', '\n']) || value.contains([',', '\n']) {
+        let message = "Invalid variable: the name can't be empty or contain = ! , or spaces, and the value can't contain ,".to_string();
+        println!("\x1b[2m    \x1b[31m{}\x1b[0m", message);
+        return HttpResponse::BadRequest().json(ResponseMessage { message });
+    }
+
+    PLAYLIST_VARIABLES.lock().unwrap().insert(name.to_string(), value.to_string());
+
+    // Append to the log file, so that the jumps can be related to the responses
+    let log_file_name = LOG_FILE_NAME.lock().unwrap().clone();
+    let kind = EventKind::Variable { name: name.to_string(), value: value.to_string() };
+    log_event(&log_file_name, &format!("{},Set variable {}={},success,{}", time_ns, name, value, csv_field(&variable_opt.time)), &Event::new(time_ns, kind, "success", &variable_opt.time).for_request(&RequestInfo::from_request(&req)));
+
+    let message = format!("Set variable {}={}", name, value);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
+
+
+// Unset all the playlist variables
+#[get("/variables/clear")]
+async fn clear_variables() -> HttpResponse {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    println!("{}: Received /variables/clear", time_ns);
+
+    let mut variables = PLAYLIST_VARIABLES.lock().unwrap();
+    let count = variables.len();
+    variables.clear();
+    drop(variables); // release the lock on PLAYLIST_VARIABLES global
+
+    let message = format!("Cleared {} variables", count);
+    println!("\x1b[2m    \x1b[38;5;8m{}\x1b[0m", message);
+    HttpResponse::Ok().json(ResponseMessage { message })
+}
@@ -0,0 +1,408 @@
+use std::{
+    fs,
+    fs::OpenOptions,
+    io::Write,
+};
+
+use actix_web::web;
+use chrono::{DateTime, Datelike, Duration, Local, Timelike};
+
+use crate::structs::{AudioFiles, Schedule, TimeQuery, PlaylistPlaybackOptions, RequestInfo};
+use crate::routes::{play::play_audio_file, playlists::play_playlist};
+use crate::clock::monotonic_ns;
+use crate::{PLAYLISTS, SCHEDULES};
+
+
+// Schedules are saved in this file, one per line: name | cron | playlist or file | target | enabled or disabled
+pub static SCHEDULES_FILE: &str = "./schedules.txt";
+
+
+// A parsed cron expression. Each field is a bit mask of the allowed values
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl CronSchedule {
+    // Parse a 5-field cron expression (minute hour day-of-month month day-of-week)
+    // Each field accepts *, single values, lists (1,15), ranges (1-5) and steps (*/15, 0-30/10). Day-of-week 0 and 7 are both Sunday
+    // The @hourly, @daily (@midnight), @weekly, @monthly and @yearly (@annually) shortcuts are also accepted
+    pub fn parse(expression: &str) -> Result<CronSchedule, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Invalid cron expression \"{}\": expected 5 fields (minute hour day-of-month month day-of-week)", expression));
+        }
+
+        let mut days_of_week = parse_cron_field(fields[4], 0, 7, "day-of-week")?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1; // 7 is also Sunday
+        }
+
+        Ok(CronSchedule {
+            minutes: parse_cron_field(fields[0], 0, 59, "minute")?,
+            hours: parse_cron_field(fields[1], 0, 23, "hour")?,
+            days_of_month: parse_cron_field(fields[2], 1, 31, "day-of-month")?,
+            months: parse_cron_field(fields[3], 1, 12, "month")?,
+            days_of_week,
+            // as with cron, a field starting with * (eg. */2) doesn't restrict the day, even if it skips some days
+            any_day_of_month: fields[2].starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*'),
+        })
+    }
+
+    // Does the given (local) time fall on this schedule? Seconds are ignored
+    pub fn matches(&self, time: &DateTime<Local>) -> bool {
+        let day_of_month = self.days_of_month & (1 << time.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << time.weekday().num_days_from_sunday()) != 0;
+
+        // Same as cron: if both day fields are restricted, either one matching is enough
+        let day = match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        };
+
+        day
+            && self.minutes & (1 << time.minute()) != 0
+            && self.hours & (1 << time.hour()) != 0
+            && self.months & (1 << time.month()) != 0
+    }
+
+    // Next matching minute strictly after the given time, looking ahead at most one year (and a day, for Feb 29)
+    pub fn next_after(&self, time: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut next = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        for _ in 0..(367 * 24 * 60) {
+            if self.matches(&next) {
+                return Some(next);
+            }
+            next += Duration::minutes(1);
+        }
+        None
+    }
+}
+
+// Parse one cron field into a bit mask of the allowed values
+fn parse_cron_field(field: &str, min: u32, max: u32, field_name: &str) -> Result<u64, String> {
+    let mut mask: u64 = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid step \"{}\" in the {} field", step, field_name)),
+            },
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            match (start.parse::<u32>(), end.parse::<u32>()) {
+                (Ok(start), Ok(end)) => (start, end),
+                _ => return Err(format!("Invalid range \"{}\" in the {} field", range, field_name)),
+            }
+        } else {
+            match range.parse::<u32>() {
+                // "5/10" means from 5 to the end, every 10
+                Ok(value) if part.contains('/') => (value, max),
+                Ok(value) => (value, value),
+                Err(_) => return Err(format!("Invalid value \"{}\" in the {} field", range, field_name)),
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!("\"{}\" is out of range in the {} field ({}-{})", range, field_name, min, max));
+        }
+
+        let mut value = start;
+        while value <= end {
+            mask |= 1 << value;
+            value += step;
+        }
+    }
+
+    Ok(mask)
+}
+
+
+// Parse one line of the schedules file
+fn parse_schedule_line(line: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = line.split('|').map(|field| field.trim()).collect();
+    if fields.len() != 5 {
+        return Err("expected 5 fields: name | cron | playlist or file | target | enabled or disabled".to_string());
+    }
+
+    let enabled = match fields[4] {
+        "enabled" | "true" => true,
+        "disabled" | "false" => false,
+        other => return Err(format!("unknown state \"{}\", use enabled or disabled", other)),
+    };
+
+    validate_schedule(fields[0], fields[1], fields[2], fields[3])?;
+
+    Ok(Schedule {
+        name: fields[0].to_string(),
+        cron: fields[1].to_string(),
+        target_type: fields[2].to_string(),
+        target: fields[3].to_string(),
+        enabled,
+    })
+}
+
+// Check the parts of a schedule that do not depend on the loaded audio files and playlists
+// (| and line breaks would break the line of the schedule in the schedules file)
+pub fn validate_schedule(name: &str, cron: &str, target_type: &str, target: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains(['|', '/', '\n', '\r']) {
+        return Err(format!("Invalid schedule name \"{}\" (must not be empty or contain | or /)", name));
+    }
+    if cron.contains(['|', '\n', '\r']) {
+        return Err(format!("Invalid cron expression \"{}\" (must not contain | or line breaks)", cron));
+    }
+    if target_type != "playlist" && target_type != "file" {
+        return Err(format!("Unknown target type \"{}\", use playlist or file", target_type));
+    }
+    if target.is_empty() || target.contains(['|', '\n', '\r']) {
+        return Err(format!("Invalid target \"{}\" (must not be empty or contain | or line breaks)", target));
+    }
+    CronSchedule::parse(cron)?;
+    Ok(())
+}
+
+// Load the schedules from the schedules file. Invalid lines are skipped with a warning
+// The targets are only checked when the schedule runs, since playlists can be added (hot reloaded) after the server starts
+pub fn load_schedules(path: &str) -> Vec<Schedule> {
+    let mut schedules: Vec<Schedule> = Vec::new();
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return schedules, // no schedules file yet
+    };
+
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_schedule_line(line) {
+            Ok(schedule) => {
+                if schedules.iter().any(|s| s.name == schedule.name) {
+                    println!("\x1b[2m    \x1b[31mSkipped line {} of {}: duplicate schedule name \"{}\"\x1b[0m", line_number + 1, path, schedule.name);
+                    continue;
+                }
+                schedules.push(schedule);
+            },
+            Err(e) => println!("\x1b[2m    \x1b[31mSkipped line {} of {}: {}\x1b[0m", line_number + 1, path, e),
+        }
+    }
+
+    schedules
+}
+
+// Overwrite the schedules file with the given schedules
+pub fn save_schedules(path: &str, schedules: &[Schedule]) {
+    let mut content = String::from("# name | cron (minute hour day-of-month month day-of-week, local time) | playlist or file | target | enabled or disabled\n");
+    for schedule in schedules {
+        content.push_str(&format!("{} | {} | {} | {} | {}\n", schedule.name, schedule.cron, schedule.target_type, schedule.target, if schedule.enabled { "enabled" } else { "disabled" }));
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+    if let Err(e) = write!(file, "{}", content) {
+        eprintln!("Couldn't write to file: {}", e);
+    }
+}
+
+// Next run of a schedule, as local time text (empty if disabled)
+pub fn next_run_text(schedule: &Schedule) -> String {
+    if !schedule.enabled {
+        return String::new();
+    }
+    match CronSchedule::parse(&schedule.cron).ok().and_then(|cron| cron.next_after(&Local::now())) {
+        Some(next) => next.format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+        None => String::new(),
+    }
+}
+
+
+// Start the scheduler thread. Every minute, all the enabled schedules matching the current (local) minute are started in their own thread
+pub fn start_scheduler(audio_files: web::Data<AudioFiles>) {
+    std::thread::spawn(move || {
+        let mut last_checked_minute: Option<DateTime<Local>> = None;
+
+        loop {
+            // sleep until the start of the next minute
+            let now = Local::now();
+            let to_next_minute = 60_000_000_000 - (now.second() as u64 * 1_000_000_000 + now.nanosecond() as u64 % 1_000_000_000);
+            std::thread::sleep(std::time::Duration::from_nanos(to_next_minute));
+
+            let now = Local::now();
+            let minute = match now.with_second(0).and_then(|now| now.with_nanosecond(0)) {
+                Some(minute) => minute,
+                None => continue,
+            };
+            if last_checked_minute == Some(minute) {
+                continue; // woke up early, this minute was already checked
+            }
+            last_checked_minute = Some(minute);
+
+            let mut schedules = SCHEDULES.lock().unwrap();
+            let due: Vec<Schedule> = schedules.schedules.iter()
+                .filter(|schedule| schedule.enabled)
+                .filter(|schedule| CronSchedule::parse(&schedule.cron).is_ok_and(|cron| cron.matches(&minute)))
+                .cloned()
+                .collect();
+
+            for schedule in due {
+                if schedules.running.contains(&schedule.name) {
+                    println!("\x1b[2m    \x1b[31mSchedule {} is still running, skipped the run at {}\x1b[0m", schedule.name, minute.format("%Y-%m-%d %H:%M"));
+                    continue;
+                }
+                schedules.running.insert(schedule.name.clone());
+
+                let audio_files = audio_files.clone();
+                std::thread::spawn(move || run_schedule(schedule, audio_files));
+            }
+            drop(schedules); // release the lock on SCHEDULES global
+        }
+    });
+}
+
+// Play the target of a schedule, the same way (and with the same logs) as the /playlist/{name} or /play/{name} route would
+fn run_schedule(schedule: Schedule, audio_files: web::Data<AudioFiles>) {
+    let time_ns = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    let time_monotonic = monotonic_ns();
+    println!("{}: Scheduled run of {} (/{}/{})", time_ns, schedule.name, if schedule.target_type == "playlist" { "playlist" } else { "play" }, schedule.target);
+
+    // the schedule name is logged as the client timestamp, so that scheduled runs can be told apart from manual requests
+    let query = TimeQuery {
+        time: format!("schedule:{}", schedule.name),
+        ..Default::default()
+    };
+    let request = RequestInfo::internal(&query.time); // no HTTP request: the request path is the schedule
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        if schedule.target_type == "playlist" {
+            if !PLAYLISTS.lock().unwrap().playlists.contains_key(&schedule.target) {
+                println!("\x1b[2m    \x1b[31mSchedule {}: playlist {} not found\x1b[0m", schedule.name, schedule.target);
+                return;
+            }
+            let response = play_playlist(&schedule.target, &audio_files, &query, &PlaylistPlaybackOptions::default(), (time_ns, time_monotonic), None, &request);
+            if !response.status().is_success() {
+                println!("\x1b[2m    \x1b[31mSchedule {} failed ({})\x1b[0m", schedule.name, response.status());
+            }
+        } else {
+            let response = play_audio_file(&schedule.target, &audio_files, &query, &request);
+            if !response.status().is_success() {
+                println!("\x1b[2m    \x1b[31mSchedule {} failed ({})\x1b[0m", schedule.name, response.status());
+            }
+        }
+    }));
+    if result.is_err() {
+        println!("\x1b[2m    \x1b[31mSchedule {} panicked\x1b[0m", schedule.name);
+    }
+
+    SCHEDULES.lock().unwrap().running.remove(&schedule.name);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn cron_fields() {
+        assert_eq!(parse_cron_field("*", 0, 5, "minute").unwrap(), 0b111111);
+        assert_eq!(parse_cron_field("1,3-4", 0, 59, "minute").unwrap(), 0b11010);
+        assert_eq!(parse_cron_field("*/15", 0, 59, "minute").unwrap(), (1 << 0) | (1 << 15) | (1 << 30) | (1 << 45));
+        assert_eq!(parse_cron_field("0-30/10", 0, 59, "minute").unwrap(), (1 << 0) | (1 << 10) | (1 << 20) | (1 << 30));
+        assert_eq!(parse_cron_field("50/5", 0, 59, "minute").unwrap(), (1 << 50) | (1 << 55));
+
+        for field in ["60", "5-2", "*/0", "a", "1-", "", "0"] {
+            assert!(parse_cron_field(field, 1, 59, "minute").is_err(), "{}", field);
+        }
+    }
+
+    #[test]
+    fn cron_expressions() {
+        assert!(CronSchedule::parse("0 9 * * 1-5").is_ok());
+        assert!(CronSchedule::parse(" @daily ").is_ok());
+        assert!(CronSchedule::parse("0 9 * *").is_err());
+        assert!(CronSchedule::parse("0 9 * * * *").is_err());
+        assert!(CronSchedule::parse("0 24 * * *").is_err());
+        assert!(CronSchedule::parse("@fortnightly").is_err());
+    }
+
+    #[test]
+    fn cron_matches() {
+        let weekdays = CronSchedule::parse("30 9 * * 1-5").unwrap();
+        assert!(weekdays.matches(&local(2024, 1, 31, 9, 30))); // Wednesday
+        assert!(!weekdays.matches(&local(2024, 1, 31, 9, 31)));
+        assert!(!weekdays.matches(&local(2024, 2, 3, 9, 30))); // Saturday
+
+        let sunday = CronSchedule::parse("0 0 * * 7").unwrap();
+        assert!(sunday.matches(&local(2024, 2, 4, 0, 0)));
+    }
+
+    #[test]
+    fn cron_day_fields() {
+        // both day fields restricted: either one matching is enough
+        let either = CronSchedule::parse("0 12 1 * 1").unwrap();
+        assert!(either.matches(&local(2024, 2, 1, 12, 0))); // the 1st, a Thursday
+        assert!(either.matches(&local(2024, 2, 5, 12, 0))); // a Monday
+        assert!(!either.matches(&local(2024, 2, 6, 12, 0)));
+
+        // a day field starting with * doesn't restrict the day, so only the other one counts
+        let every_other_day = CronSchedule::parse("0 12 */2 * 1").unwrap();
+        assert!(every_other_day.matches(&local(2024, 2, 5, 12, 0))); // Monday the 5th
+        assert!(!every_other_day.matches(&local(2024, 2, 7, 12, 0))); // Wednesday the 7th
+    }
+
+    #[test]
+    fn cron_next_after() {
+        let schedule = CronSchedule::parse("15 10 29 2 *").unwrap();
+        assert_eq!(schedule.next_after(&local(2024, 1, 31, 14, 0)), Some(local(2024, 2, 29, 10, 15)));
+        assert_eq!(schedule.next_after(&local(2024, 2, 29, 10, 15)), None); // the next Feb 29 is more than a year ahead
+
+        let hourly = CronSchedule::parse("@hourly").unwrap();
+        assert_eq!(hourly.next_after(&local(2024, 1, 31, 14, 0)), Some(local(2024, 1, 31, 15, 0)));
+        assert_eq!(hourly.next_after(&local(2024, 1, 31, 14, 59)), Some(local(2024, 1, 31, 15, 0)));
+    }
+
+    #[test]
+    fn schedule_lines() {
+        let schedule = parse_schedule_line("morning | 0 9 * * 1-5 | playlist | session.txt | enabled").unwrap();
+        assert_eq!((schedule.name.as_str(), schedule.cron.as_str(), schedule.target_type.as_str(), schedule.target.as_str(), schedule.enabled), ("morning", "0 9 * * 1-5", "playlist", "session.txt", true));
+        assert!(!parse_schedule_line("night | @daily | file | 1.wav | disabled").unwrap().enabled);
+
+        assert!(parse_schedule_line("morning | 0 9 * * * | playlist | session.txt").is_err());
+        assert!(parse_schedule_line("morning | 0 9 * * * | playlist | session.txt | paused").is_err());
+        assert!(parse_schedule_line("a/b | 0 9 * * * | playlist | session.txt | enabled").is_err());
+        assert!(parse_schedule_line("morning | 0 9 * * * | stream | session.txt | enabled").is_err());
+        assert!(parse_schedule_line("morning | 0 9 * * * | file |  | enabled").is_err());
+    }
+}
@@ -0,0 +1,34 @@
+use crate::structs::{Session, SessionSidecar};
+use crate::logging::write_log_file;
+use crate::SESSION;
+
+
+// Sessions: participant, experiment, experimenter and free-form key/values (/session/start), until /session/end
+// Every log file written during a session gets a sidecar JSON with the session metadata, next to it:
+// logs/log_playlist_20240131-140000.csv --> logs/log_playlist_20240131-140000_session.json
+
+// Sidecar JSON file of a log file (with or without the .csv extension)
+pub fn sidecar_file_name(log_file: &str) -> String {
+    let log_file = log_file.strip_suffix(".csv").unwrap_or(log_file);
+    format!("{}_session.json", log_file)
+}
+
+// Register a new log file: if a session is active, the log file is added to it and gets a sidecar JSON
+pub fn register_log_file(log_file: &str) {
+    let mut session = SESSION.lock().unwrap();
+    if let Some(session) = session.as_mut() {
+        let log_file = if log_file.ends_with(".csv") { log_file.to_string() } else { format!("{}.csv", log_file) };
+        if !session.log_files.contains(&log_file) {
+            session.log_files.push(log_file.clone());
+        }
+        write_sidecar(&log_file, session);
+    }
+}
+
+// Write (or update) the sidecar JSON of a log file
+pub fn write_sidecar(log_file: &str, session: &Session) {
+    let sidecar_file = sidecar_file_name(log_file);
+    let content = serde_json::to_string_pretty(&SessionSidecar { log_file, session }).unwrap();
+    write_log_file(&sidecar_file, content);
+    println!("\x1b[2m    \x1b[38;5;8mWrote session metadata: {}\x1b[0m", sidecar_file);
+}
@@ -0,0 +1,263 @@
+use crate::structs::{StaircaseOptions, StaircaseSummary, Tone, RequestInfo};
+use crate::audio::{parse_tone_spec, tone_spec};
+use crate::logging::{start_csv_log, log_row, csv_field};
+use crate::clock::monotonic_ns;
+use crate::session::register_log_file;
+
+
+// Adaptive staircases (transformed up-down, Levitt 1971): the level goes up after each incorrect answer, and down after
+// 2 (1-up/2-down, converges to 70.7% correct) or 3 (1-up/3-down, 79.4% correct) correct answers in a row.
+// The level is a gain in dB: the amplitude of a tone, or the gain of an audio file.
+// The step size can change after each reversal (step-size schedule), and the staircase stops after a number of reversals or trials.
+// The threshold estimate is the mean level of the last reversals.
+
+pub enum StaircaseStimulus {
+    Tone(Tone), // the amplitude of the tone is replaced by the level
+    File(String), // the audio file is played with the level as gain
+}
+
+pub struct Staircase {
+    pub name: String,
+    pub stimulus: StaircaseStimulus,
+    pub down: u32, // correct answers in a row to go down: 2 (1-up/2-down) or 3 (1-up/3-down)
+    pub step_sizes: Vec<f32>, // step size (dB) after 0, 1, 2, ... reversals, the last one is kept
+    pub min_level: f32,
+    pub max_level: f32,
+    pub max_reversals: u32,
+    pub max_trials: u32,
+    pub threshold_reversals: u32, // number of last reversals averaged for the threshold
+    pub log_file: String,
+
+    pub level: f32,
+    pub trial: u32, // trials played
+    pub correct_in_a_row: u32,
+    pub last_direction: i8, // -1 (down), 1 (up), or 0 before the first level change
+    pub reversals: Vec<f32>, // levels at the reversals
+    pub awaiting_answer: bool, // a trial was played, and not answered yet
+    pub stopped_by: Option<String>, // "reversals" or "trials", once the staircase is finished
+}
+
+// Result of an answer
+pub struct StaircaseStep {
+    pub level: f32, // level of the answered trial
+    pub next_level: f32,
+    pub reversal: bool,
+}
+
+impl Staircase {
+    pub fn from_options(options: &StaircaseOptions) -> Result<Staircase, String> {
+        let name = options.name.trim().to_string();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("Invalid staircase name (use letters, digits, - and _)".to_string());
+        }
+
+        let (stimulus, default_level) = match (options.tone.is_empty(), options.file.is_empty()) {
+            (false, true) => match parse_tone_spec(&options.tone) {
+                Some(tone) => {
+                    let amplitude = tone.amplitude;
+                    (StaircaseStimulus::Tone(tone), amplitude)
+                },
+                None => return Err(format!("Invalid tone \"{}\" (expected tone_{{freq}}Hz_{{duration}}ms_{{amplitude}}dB_@{{sample_rate}}Hz)", options.tone)),
+            },
+            (true, false) => (StaircaseStimulus::File(options.file.clone()), 0.0),
+            _ => return Err("Specify either tone or file (not both)".to_string()),
+        };
+
+        let down = match options.rule.as_str() {
+            "" | "1up2down" => 2,
+            "1up3down" => 3,
+            _ => return Err(format!("Unknown rule \"{}\". Use 1up2down or 1up3down", options.rule)),
+        };
+
+        let step_sizes: Vec<f32> = if options.step_sizes.is_empty() {
+            vec![8.0, 4.0, 2.0] // large steps to approach the threshold quickly, then smaller steps around it
+        } else {
+            match options.step_sizes.split(',').map(|step| step.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>() {
+                Ok(step_sizes) if step_sizes.iter().all(|step| step.is_finite() && *step > 0.0) => step_sizes,
+                _ => return Err(format!("Invalid step_sizes \"{}\" (expected comma-separated positive numbers, in dB)", options.step_sizes)),
+            }
+        };
+
+        let level = options.start_level.unwrap_or(default_level);
+        if !options.min_level.is_finite() || !options.max_level.is_finite() || options.min_level >= options.max_level || !(options.min_level..=options.max_level).contains(&level) {
+            return Err(format!("start_level ({} dB) must be between min_level ({} dB) and max_level ({} dB)", level, options.min_level, options.max_level));
+        }
+        if options.max_reversals == 0 && options.max_trials == 0 {
+            return Err("Set max_reversals or max_trials (or both), so that the staircase stops".to_string());
+        }
+
+        Ok(Staircase {
+            name,
+            stimulus,
+            down,
+            step_sizes,
+            min_level: options.min_level,
+            max_level: options.max_level,
+            max_reversals: options.max_reversals,
+            max_trials: options.max_trials,
+            threshold_reversals: options.threshold_reversals.max(1),
+            log_file: String::new(),
+            level,
+            trial: 0,
+            correct_in_a_row: 0,
+            last_direction: 0,
+            reversals: Vec::new(),
+            awaiting_answer: false,
+            stopped_by: None,
+        })
+    }
+
+    pub fn rule(&self) -> String {
+        format!("1up{}down", self.down)
+    }
+
+    // Name of the stimulus at the current level, as in the logs (eg. tone_1000Hz_200ms_-30dB_@48000Hz or 1000.wav gain_-30dB)
+    pub fn stimulus_name(&self) -> String {
+        match &self.stimulus {
+            StaircaseStimulus::Tone(tone) => tone_spec(&Tone { amplitude: self.level, ..*tone }),
+            StaircaseStimulus::File(audio_file_name) => crate::audio::audio_file_line(audio_file_name, self.level),
+        }
+    }
+
+    fn step_size(&self) -> f32 {
+        self.step_sizes[self.reversals.len().min(self.step_sizes.len() - 1)]
+    }
+
+    // Apply the answer of the last trial: update the level, count the reversals, and check the stop criteria
+    pub fn answer(&mut self, correct: bool) -> StaircaseStep {
+        let level = self.level;
+        self.awaiting_answer = false;
+
+        let direction: i8 = if correct {
+            self.correct_in_a_row += 1;
+            if self.correct_in_a_row >= self.down {
+                self.correct_in_a_row = 0;
+                -1
+            } else {
+                0
+            }
+        } else {
+            self.correct_in_a_row = 0;
+            1
+        };
+
+        let reversal = direction != 0 && self.last_direction != 0 && direction != self.last_direction;
+        if reversal {
+            self.reversals.push(level);
+        }
+        if direction != 0 {
+            // the step size of the schedule is chosen after counting the reversal
+            self.level = (level + direction as f32 * self.step_size()).clamp(self.min_level, self.max_level);
+            self.last_direction = direction;
+        }
+
+        if self.max_reversals > 0 && self.reversals.len() as u32 >= self.max_reversals {
+            self.stopped_by = Some("reversals".to_string());
+        } else if self.max_trials > 0 && self.trial >= self.max_trials {
+            self.stopped_by = Some("trials".to_string());
+        }
+
+        StaircaseStep { level, next_level: self.level, reversal }
+    }
+
+    // Mean level of the last threshold_reversals reversals (or of all the reversals if there are fewer)
+    pub fn threshold(&self) -> Option<f32> {
+        if self.reversals.is_empty() {
+            return None;
+        }
+        let count = (self.threshold_reversals as usize).min(self.reversals.len());
+        let last = &self.reversals[self.reversals.len() - count..];
+        Some(last.iter().sum::<f32>() / count as f32)
+    }
+
+    pub fn summary(&self) -> StaircaseSummary {
+        let count = (self.threshold_reversals as usize).min(self.reversals.len());
+        StaircaseSummary {
+            name: self.name.clone(),
+            rule: self.rule(),
+            stimulus: self.stimulus_name(),
+            level: self.level,
+            trials: self.trial,
+            reversals: self.reversals.clone(),
+            finished: self.stopped_by.is_some(),
+            stopped_by: self.stopped_by.clone(),
+            threshold: self.threshold(),
+            threshold_reversals: count as u32,
+            log_file: self.log_file.clone(),
+        }
+    }
+}
+
+
+// Staircase log: one row per trial played and per answer, with the level, the answer and the reversals
+pub fn create_staircase_log(staircase: &mut Staircase, time_ns: u128, client_time: &str, request: &RequestInfo) {
+    staircase.log_file = chrono::Utc::now().format(&format!("logs/log_staircase_{}_%Y%m%d-%H%M%S.csv", staircase.name)).to_string();
+
+    let step_sizes: Vec<String> = staircase.step_sizes.iter().map(|step| step.to_string()).collect();
+    start_csv_log(&staircase.log_file, "timestamp_audio,audio_filename,status,timestamp_client,trial,level_db,answer,reversal,reversal_count,next_level_db");
+    let request_text = format!(
+        "Received /staircase/start (name: {} | rule: {} | start_level: {} dB | step_sizes: {} dB | min_level: {} dB | max_level: {} dB | max_reversals: {} | max_trials: {} | threshold_reversals: {})",
+        staircase.name, staircase.rule(), staircase.level, step_sizes.join(" "), staircase.min_level, staircase.max_level, staircase.max_reversals, staircase.max_trials, staircase.threshold_reversals
+    );
+    log_row(&staircase.log_file, &format!("{},{},success,{},,{},,,,", time_ns, csv_field(&request_text), csv_field(client_time), staircase.level), monotonic_ns(), Some(request));
+    println!("\x1b[1m    Started new log file: ./{}\x1b[0m", staircase.log_file);
+    register_log_file(&staircase.log_file); // session metadata sidecar
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staircase(options: serde_json::Value) -> Staircase {
+        Staircase::from_options(&serde_json::from_value(options).unwrap()).unwrap()
+    }
+
+    // Play and answer one trial, as /staircase/play and /staircase/answer do
+    fn trial(staircase: &mut Staircase, correct: bool) -> StaircaseStep {
+        staircase.trial += 1;
+        staircase.answer(correct)
+    }
+
+    #[test]
+    fn reversals_and_threshold() {
+        let mut staircase = staircase(serde_json::json!({ "name": "test", "tone": "tone_1000Hz_200ms_-20dB_@48000Hz", "max_reversals": 4, "threshold_reversals": 2 }));
+        assert_eq!(staircase.threshold(), None);
+
+        // 1up2down with steps of 8, 4 and 2 dB: (level, next level, reversal) of each trial
+        let answers = [true, true, false, false, true, true, false, true, true];
+        let expected = [(-20.0, -20.0, false), (-20.0, -28.0, false), (-28.0, -24.0, true), (-24.0, -20.0, false), (-20.0, -20.0, false), (-20.0, -22.0, true), (-22.0, -20.0, true), (-20.0, -20.0, false), (-20.0, -22.0, true)];
+        for (answer, (level, next_level, reversal)) in answers.iter().zip(expected) {
+            assert!(staircase.stopped_by.is_none());
+            let step = trial(&mut staircase, *answer);
+            assert_eq!((step.level, step.next_level, step.reversal), (level, next_level, reversal), "trial {}", staircase.trial);
+        }
+
+        assert_eq!(staircase.reversals, vec![-28.0, -20.0, -22.0, -20.0]);
+        assert_eq!(staircase.stopped_by.as_deref(), Some("reversals"));
+        assert_eq!(staircase.threshold(), Some(-21.0)); // mean of the last 2 reversals
+    }
+
+    #[test]
+    fn levels_are_clamped_and_trials_stop() {
+        let mut staircase = staircase(serde_json::json!({ "name": "test", "file": "1000.wav", "rule": "1up3down", "start_level": -3.0, "max_reversals": 0, "max_trials": 3 }));
+        assert_eq!(trial(&mut staircase, false).next_level, 0.0); // max_level
+        assert_eq!(trial(&mut staircase, false).next_level, 0.0);
+        assert!(staircase.stopped_by.is_none());
+        assert_eq!(trial(&mut staircase, true).next_level, 0.0); // 1 of the 3 correct answers in a row
+        assert_eq!(staircase.stopped_by.as_deref(), Some("trials"));
+        assert_eq!(staircase.threshold(), None);
+    }
+
+    #[test]
+    fn invalid_options() {
+        let options = |options: serde_json::Value| Staircase::from_options(&serde_json::from_value(options).unwrap());
+        assert!(options(serde_json::json!({ "name": "test" })).is_err());
+        assert!(options(serde_json::json!({ "name": "a b", "file": "1000.wav" })).is_err());
+        assert!(options(serde_json::json!({ "name": "test", "file": "1000.wav", "rule": "2up1down" })).is_err());
+        assert!(options(serde_json::json!({ "name": "test", "file": "1000.wav", "step_sizes": "4,0" })).is_err());
+        assert!(options(serde_json::json!({ "name": "test", "file": "1000.wav", "start_level": 6.0 })).is_err());
+        assert!(options(serde_json::json!({ "name": "test", "file": "1000.wav", "max_reversals": 0 })).is_err());
+    }
+}
@@ -1,9 +1,9 @@
-use std::{collections::HashMap, io::BufReader};
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::BufReader};
 
 use rodio::{source::Buffered, Decoder};
 use serde::{Serialize, Deserialize};
 
-use crate::audio::PlaylistTypes;
+use crate::audio::Playlist;
 
 
 #[derive(Serialize)]
@@ -11,11 +11,13 @@ pub struct ResponseMessage {
     pub message: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct TimeQuery {
     // optional parameters
     #[serde(default)]
     pub time: String,
+    #[serde(default)]
+    pub start_at: String, // start playback at this time: UNIX nanoseconds or ISO-8601 (eg. 2024-01-31T14:00:00.000Z)
 }
 
 pub struct AudioFiles {
@@ -23,10 +25,24 @@ pub struct AudioFiles {
 }
 
 pub struct Playlists {
-    pub playlists: HashMap<String, Vec<PlaylistTypes>>
+    pub playlists: HashMap<String, Playlist>
 }
 
-#[derive(Deserialize)]
+pub struct Schedules {
+    pub schedules: Vec<Schedule>,
+    pub running: HashSet<String>, // names of the schedules currently playing, a schedule is not started again until its last run is done
+}
+
+#[derive(Serialize, Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub cron: String, // 5-field cron expression in local time (minute hour day-of-month month day-of-week), or @hourly, @daily, ...
+    pub target_type: String, // "playlist" or "file"
+    pub target: String, // playlist file name or audio file name
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Tone {
     pub freq: f32,
     pub duration: u32,
@@ -40,7 +56,19 @@ pub struct RandomAudioQueueOptions {
     #[serde(default)]
     pub break_between_files: u32, // in milliseconds
     #[serde(default)]
-    pub file_count: u32, // number of files to play before stopping, overrides max_duration
+    pub file_count: u32, // number of files to play before stopping. If max_duration is also set, stop at whichever comes first
+    #[serde(default)]
+    pub max_duration: u32, // in milliseconds, no file is started if it would not finish within max_duration (0 = no limit)
+    #[serde(default)]
+    pub seed: Option<u64>, // seed for the random draws, a random seed is picked (and logged) if not specified
+    #[serde(default)]
+    pub sampling: String, // "replacement" (default), "without_replacement" or "balanced"
+    #[serde(default)]
+    pub max_repeats: u32, // maximum number of times the same file can play in a row, 0 = no limit
+    #[serde(default)]
+    pub min_distance: u32, // minimum number of other files between two plays of the same file, 0 = no limit
+    #[serde(default)]
+    pub weights: String, // per-file weights, eg. "a.wav:2,b.wav:0.5". Files not listed have a weight of 1, a weight of 0 excludes the file
 }
 
 #[derive(Deserialize)]
@@ -49,8 +77,587 @@ pub struct PlaylistOptions {
     #[serde(default)]
     pub break_between_files: u32, // in milliseconds
     #[serde(default)]
-    pub file_count: u32, // number of files to play before stopping, overrides max_duration
+    pub file_count: u32, // number of files in the playlist. If max_duration is also set, stop at whichever comes first
+    #[serde(default)]
+    pub max_duration: u32, // in milliseconds, fill the playlist up to this duration (0 = no limit)
+    #[serde(default)]
+    pub seed: Option<u64>, // seed for the random draws, a random seed is picked (and written to the playlist) if not specified
+    #[serde(default)]
+    pub sampling: String, // "replacement" (default), "without_replacement" or "balanced"
+    #[serde(default)]
+    pub max_repeats: u32, // maximum number of times the same file can play in a row, 0 = no limit
+    #[serde(default)]
+    pub min_distance: u32, // minimum number of other files between two plays of the same file, 0 = no limit
+    #[serde(default)]
+    pub weights: String, // per-file weights, eg. "a.wav:2,b.wav:0.5". Files not listed have a weight of 1, a weight of 0 excludes the file
 
     #[serde(default)] // this default to false --> download the file
     pub no_download: bool, // don't download the file, only create the playlist server-side
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize, Default)]
+pub struct PlaylistPlaybackOptions {
+    // optional parameters, override the "# iti: ...", "# block_rest: ..." and "# shuffle: ..." playlist header
+    #[serde(default)]
+    pub iti: Option<u32>, // in milliseconds, between two trials of the same block
+    #[serde(default)]
+    pub block_rest: Option<u32>, // in milliseconds, between two blocks
+    #[serde(default)]
+    pub shuffle: Option<String>, // "none", "blocks", "trials" or "both"
+    #[serde(default)]
+    pub max_duration: u32, // in milliseconds, no item is started if it would not finish within max_duration (0 = no limit)
+    #[serde(default)]
+    pub seed: Option<u64>, // seed for the shuffles, a random seed is picked (and logged) if not specified
+}
+
+#[derive(Deserialize)]
+pub struct RenderOptions {
+    // optional parameters
+    #[serde(default)]
+    pub format: String, // "wav" (default, 32-bit float) or "flac" (24-bit)
+    #[serde(default)]
+    pub sample_rate: u32, // in Hz, 0 = the highest sample rate of the items
+    #[serde(default)]
+    pub channels: u16, // 0 = the highest channel count of the items
+}
+
+#[derive(Deserialize)]
+pub struct ResumeOptions {
+    // optional parameters
+    #[serde(default)]
+    pub max_duration: u32, // in milliseconds, for the continuation only (0 = no limit)
+}
+
+// A playlist log read back from ./logs/, to resume the playlist after its last logged item
+pub struct PlaylistLog {
+    pub playlist: String, // playlist file name
+    pub settings: HashMap<String, String>, // "key: value" pairs of the request row (iti, block_rest, shuffle, seed, ...)
+    pub first_item: usize, // index of the first item of this log in the playlist (0, unless the log is itself a continuation)
+    pub played_items: Vec<String>, // audio_filename column of the items played in this log, in playback order
+}
+
+#[derive(Deserialize)]
+pub struct OddballOptions {
+    pub standard: String, // audio file name or tone (tone_{freq}Hz_{duration}ms_{amplitude}dB_@{sample_rate}Hz)
+    pub deviants: String, // comma-separated audio file names or tones
+    pub deviant_probabilities: String, // comma-separated probabilities, one per deviant
+
+    // optional parameters
+    #[serde(default)]
+    pub trial_count: u32, // number of trials (standards + deviants), default 200
+    #[serde(default)]
+    pub isi: u32, // in milliseconds, silence between two trials
+    #[serde(default)]
+    pub min_standards: u32, // minimum number of standards between two deviants
+    #[serde(default)]
+    pub initial_standards: u32, // number of standards at the start, before any deviant
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct OddballDeviantSummary {
+    pub stimulus: String,
+    pub probability: f64, // requested probability
+    pub count: u32, // actual number of trials
+    pub positions: Vec<u32>, // trial numbers (starting at 1)
+}
+
+#[derive(Serialize)]
+pub struct OddballSummary {
+    pub message: String,
+    pub playlist: String,
+    pub seed: u64,
+    pub trial_count: u32,
+    pub standard: String,
+    pub standard_count: u32,
+    pub deviants: Vec<OddballDeviantSummary>,
+    pub total_duration_ms: u128,
+}
+
+// The most recent stimulus (audio file or tone), for the latency of the responses (see responses.rs)
+pub struct StimulusOnset {
+    pub time_ns: u128,
+    pub monotonic_ns: u128, // monotonic time read with time_ns
+    pub name: String, // as in the audio_filename column of the logs
+    pub log_file: String, // log file of the stimulus (eg. logs/log_playlist_20240131-140000.csv), the responses are written to it
+    pub log_columns: String, // extra columns of the playlist logs, with a leading comma ("" in the main log)
+    pub window: Option<(u32, u32)>, // response window, in milliseconds after the onset
+    pub scored: bool, // part of a playlist with response windows: the responses outside of a window are false alarms
+    pub hit: bool,
+    pub event_log: bool, // the log file has a JSON Lines event log (main, /play/random and playlist logs, see logging.rs)
+    pub position: Option<EventPosition>, // position of the stimulus in its playlist, for the JSON Lines event log
+}
+
+#[derive(Serialize)]
+pub struct ResponseSummary {
+    pub label: String,
+    pub stimulus: Option<String>, // the most recent stimulus, if any
+    pub latency_ms: Option<f64>, // from the onset of the stimulus to the receipt of the response
+    pub outcome: Option<String>, // "hit", "false_alarm" or "repeat" in playlists with response windows
+    pub log_file: String,
+}
+
+#[derive(Deserialize)]
+pub struct VariableOptions {
+    pub name: String,
+    // optional parameters
+    #[serde(default)]
+    pub value: String, // default = "" (same as an unset variable)
+    #[serde(default)]
+    pub time: String, // client timestamp, logged like the time of the other requests
+}
+
+#[derive(Deserialize)]
+pub struct ImportOptions {
+    // optional parameters
+    #[serde(default)]
+    pub overwrite: bool, // replace the playlist if it already exists
+    #[serde(default)]
+    pub file_column: String, // column names of CSV and PsychoPy files, default = see playlist_formats.rs
+    #[serde(default)]
+    pub isi_column: String,
+    #[serde(default)]
+    pub gain_column: String,
+}
+
+#[derive(Deserialize)]
+pub struct LatinSquareOptions {
+    pub playlists: String, // comma-separated names of the condition playlists (already loaded on the server)
+    pub participant: u32, // participant number, starting at 1
+
+    // optional parameters
+    #[serde(default)]
+    pub balanced: bool, // balanced Latin square (Williams design) instead of a plain Latin square
+    #[serde(default)]
+    pub block_rest: u32, // in milliseconds, rest between two conditions
+}
+
+#[derive(Serialize)]
+pub struct LatinSquareSummary {
+    pub message: String,
+    pub playlist: String,
+    pub participant: u32,
+    pub row: usize, // row of the Latin square, starting at 1
+    pub row_count: usize,
+    pub design: String,
+    pub order: Vec<String>, // condition playlists, in the order they are played
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleOptions {
+    pub name: String,
+    pub cron: String, // eg. "30 9 * * 1-5" (9:30 on weekdays), spaces can be written as "+" or "%20" in the URL
+
+    // one of playlist or file is required
+    #[serde(default)]
+    pub playlist: String,
+    #[serde(default)]
+    pub file: String,
+
+    // optional parameters
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+pub struct ScheduleSummary {
+    pub name: String,
+    pub cron: String,
+    pub target_type: String,
+    pub target: String,
+    pub enabled: bool,
+    pub running: bool,
+    pub next_run: String, // local time of the next run, empty if disabled or not within a year
+}
+
+#[derive(Serialize)]
+pub struct PlaylistPreviewItem {
+    pub index: usize, // position in playback order, starting at 1
+    pub name: String, // same as the audio_filename column of the playlist logs
+    pub item_type: String, // "file", "tone" or "pause"
+    pub onset_ms: u128, // cumulative, from the start of the playlist
+    pub duration_ms: u128,
+    pub block: Option<usize>,
+    pub block_name: Option<String>,
+    pub trial: Option<usize>,
+    pub source_playlist: Option<String>, // included playlist the item comes from
+}
+
+#[derive(Serialize)]
+pub struct StimulusCount {
+    pub name: String,
+    pub count: u32,
+}
+
+#[derive(Serialize)]
+pub struct PlaylistPreview {
+    pub playlist: String,
+    pub seed: u64,
+    pub iti: u32,
+    pub block_rest: u32,
+    pub shuffle: String,
+    pub item_count: usize,
+    pub total_duration_ms: u128,
+    pub total_pause_ms: u128, // pauses, inter-trial intervals and block rests
+    pub files: Vec<StimulusCount>, // distinct audio files, sorted by name
+    pub tones: Vec<StimulusCount>, // distinct tones, sorted by name
+    pub items: Vec<PlaylistPreviewItem>,
+}
+
+#[derive(Deserialize)]
+pub struct StaircaseOptions {
+    pub name: String,
+
+    // one of tone or file is required
+    #[serde(default)]
+    pub tone: String, // eg. tone_1000Hz_200ms_-20dB_@48000Hz, the amplitude is the default start level
+    #[serde(default)]
+    pub file: String, // audio file name, played with the level as gain (default start level = 0 dB)
+
+    // optional parameters
+    #[serde(default)]
+    pub rule: String, // "1up2down" (default) or "1up3down"
+    #[serde(default)]
+    pub start_level: Option<f32>, // in dB
+    #[serde(default)]
+    pub step_sizes: String, // comma-separated step sizes in dB, used after 0, 1, 2, ... reversals, default = "8,4,2"
+    #[serde(default = "default_staircase_min_level")]
+    pub min_level: f32, // in dB, default = -100
+    #[serde(default)]
+    pub max_level: f32, // in dB, default = 0 (full scale)
+    #[serde(default = "default_staircase_max_reversals")]
+    pub max_reversals: u32, // stop after this many reversals, default = 8 (0 = no limit)
+    #[serde(default)]
+    pub max_trials: u32, // stop after this many trials, default = 0 (no limit)
+    #[serde(default = "default_staircase_threshold_reversals")]
+    pub threshold_reversals: u32, // the threshold is the mean level of the last reversals, default = 6
+    #[serde(default)]
+    pub time: String, // client timestamp, logged like the time of the other requests
+}
+
+fn default_staircase_min_level() -> f32 {
+    -100.0
+}
+
+fn default_staircase_max_reversals() -> u32 {
+    8
+}
+
+fn default_staircase_threshold_reversals() -> u32 {
+    6
+}
+
+#[derive(Serialize)]
+pub struct StaircaseSummary {
+    pub name: String,
+    pub rule: String,
+    pub stimulus: String, // the stimulus at the current level
+    pub level: f32, // level of the next trial, in dB
+    pub trials: u32,
+    pub reversals: Vec<f32>, // levels at the reversals, in dB
+    pub finished: bool,
+    pub stopped_by: Option<String>, // "reversals" or "trials"
+    pub threshold: Option<f32>, // mean level of the last threshold_reversals reversals, in dB
+    pub threshold_reversals: u32, // number of reversals averaged for the threshold
+    pub log_file: String,
+}
+
+#[derive(Deserialize)]
+pub struct AudiometryOptions {
+    pub name: String,
+
+    // optional parameters
+    #[serde(default)]
+    pub frequencies: String, // comma-separated frequencies in Hz, in test order, default = "1000,2000,4000,8000,500,250"
+    #[serde(default)]
+    pub ears: String, // comma-separated ears in test order, default = "right,left"
+    #[serde(default = "default_audiometry_start_level")]
+    pub start_level: i32, // in dB HL, default = 30
+    #[serde(default = "default_audiometry_min_level")]
+    pub min_level: i32, // in dB HL, default = -10
+    #[serde(default = "default_audiometry_max_level")]
+    pub max_level: i32, // in dB HL, default = 90 (lower if the calibration gain would clip)
+    #[serde(default = "default_audiometry_duration")]
+    pub duration: u32, // tone duration in milliseconds, default = 1000
+    #[serde(default = "default_audiometry_sample_rate")]
+    pub sample_rate: u32, // in Hz, default = 48000
+    #[serde(default = "default_audiometry_max_presentations")]
+    pub max_presentations: u32, // per ear and frequency, default = 30
+    #[serde(default)]
+    pub time: String, // client timestamp, logged like the time of the other requests
+}
+
+fn default_audiometry_start_level() -> i32 {
+    30
+}
+
+fn default_audiometry_min_level() -> i32 {
+    -10
+}
+
+fn default_audiometry_max_level() -> i32 {
+    90
+}
+
+fn default_audiometry_duration() -> u32 {
+    1000
+}
+
+fn default_audiometry_sample_rate() -> u32 {
+    48000
+}
+
+fn default_audiometry_max_presentations() -> u32 {
+    30
+}
+
+#[derive(Serialize, Clone)]
+pub struct AudiogramThreshold {
+    pub ear: String,
+    pub frequency: f32, // in Hz
+    pub threshold: Option<i32>, // in dB HL, None if there is no threshold
+    pub status: String, // "threshold", "no_response" (not heard at the max level) or "not_established" (too many presentations)
+    pub presentations: u32,
+    pub calibration_gain: f32, // amplitude (dB re full scale) of a 0 dB HL tone
+}
+
+#[derive(Serialize)]
+pub struct AudiometrySummary {
+    pub name: String,
+    pub calibrated: bool, // false if ./calibration.txt was not found when the audiogram was started
+    pub finished: bool,
+    pub ear: Option<String>, // ear, frequency and level (dB HL) of the next presentation
+    pub frequency: Option<f32>,
+    pub level: Option<i32>,
+    pub presentations: u32, // total number of presentations
+    pub thresholds: Vec<AudiogramThreshold>, // in test order
+    pub log_file: String,
+    pub results_file: String, // CSV file with the thresholds, written when the audiogram is finished
+}
+
+#[derive(Deserialize)]
+pub struct ProtocolOptions {
+    // optional parameters
+    #[serde(default)]
+    pub from_step: usize, // start at this step (1 = first step, default), eg. to restart a failed protocol
+    #[serde(default)]
+    pub time: String, // client timestamp, logged like the time of the other requests
+}
+
+#[derive(Serialize)]
+pub struct ProtocolStatus {
+    pub protocol: String,
+    pub state: String, // "running", "waiting" (for /protocol/continue), "finished", "stopped" or "failed"
+    pub step: Option<usize>, // current step (1 = first step), while running or waiting
+    pub step_count: usize,
+    pub current_step: Option<String>, // protocol line of the current step
+    pub message: Option<String>, // message of the current wait step
+    pub step_elapsed_ms: Option<u128>,
+    pub elapsed_ms: u128, // since /protocol/start
+    pub error: Option<String>, // step and error, if the protocol failed
+    pub steps: Vec<String>, // protocol lines of all the steps
+    pub log_file: String,
+}
+
+#[derive(Deserialize)]
+pub struct SessionOptions {
+    pub participant: String,
+    // optional parameters
+    #[serde(default)]
+    pub experiment: String,
+    #[serde(default)]
+    pub experimenter: String,
+    #[serde(default)]
+    pub time: String, // client timestamp, logged like the time of the other requests
+    #[serde(flatten)]
+    pub fields: HashMap<String, String>, // any other parameter is a free-form key/value (eg. age=25&handedness=right)
+}
+
+#[derive(Serialize, Clone)]
+pub struct Session {
+    pub session_id: String, // {participant}_{%Y%m%d-%H%M%S}
+    pub participant: String,
+    pub experiment: String,
+    pub experimenter: String,
+    pub fields: BTreeMap<String, String>,
+    pub started_at: String, // local time, RFC 3339
+    pub ended_at: Option<String>,
+    pub log_files: Vec<String>, // all the logs written during the session, each with a sidecar {log}_session.json
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub client_clocks: BTreeMap<String, ClientClock>, // the last client clock offset registered by each client IP (/clock/offset)
+}
+
+#[derive(Deserialize)]
+pub struct ClockSyncQuery {
+    // optional parameters
+    #[serde(default)]
+    pub t0: String, // client transmit time, echoed back
+}
+
+// Reply of /clock/sync (NTP-style exchange): with the client transmit (t0) and receive (t3) times,
+// offset = ((server_receive_ns - t0) + (server_transmit_ns - t3)) / 2 and round trip = (t3 - t0) - (server_transmit_ns - server_receive_ns)
+#[derive(Serialize)]
+pub struct ClockSyncReply {
+    pub t0: String,
+    pub server_receive_ns: u128,
+    pub server_transmit_ns: u128,
+}
+
+#[derive(Deserialize)]
+pub struct ClockOffsetOptions {
+    pub offset_ns: i64, // server clock - client clock
+    // optional parameters
+    pub rtt_ns: Option<u64>, // round trip time of the exchange the offset was estimated from
+    #[serde(default)]
+    pub time: String, // client timestamp, logged like the time of the other requests
+}
+
+// Rolling statistics of a latency, in milliseconds (see diagnostics.rs). The percentiles are nearest-rank, null without any sample
+#[derive(Serialize)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub mean_ms: Option<f64>,
+    pub sd_ms: Option<f64>,
+    pub min_ms: Option<f64>,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct TimingReport {
+    pub enabled: bool,
+    pub window: usize, // number of playbacks kept for the statistics
+    pub receipt_to_onset: LatencyStats,
+    pub planned_to_actual: LatencyStats,
+}
+
+#[derive(Deserialize)]
+pub struct SelfTestOptions {
+    // optional parameters
+    #[serde(default = "default_click_count")]
+    pub count: u32, // default = 20
+    #[serde(default = "default_click_interval")]
+    pub interval: u32, // milliseconds between the planned onsets of the clicks, default = 250
+}
+
+fn default_click_count() -> u32 {
+    20
+}
+
+fn default_click_interval() -> u32 {
+    250
+}
+
+#[derive(Serialize)]
+pub struct SelfTestReport {
+    pub count: u32,
+    pub interval_ms: u32,
+    pub click: String,
+    pub planned_to_actual: LatencyStats,
+    pub onset_errors_ms: Vec<f64>, // actual - planned onset of each click, in playback order
+}
+
+// Clock offset of the client, registered with /clock/offset: the client timestamps of the logs are corrected to server time with it
+#[derive(Serialize, Clone)]
+pub struct ClientClock {
+    pub offset_ns: i64, // server clock - client clock
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ns: Option<u64>,
+    pub client_ip: String,
+    pub registered_at_ns: u128,
+}
+
+// Content of the sidecar JSON of a log file written during a session
+#[derive(Serialize)]
+pub struct SessionSidecar<'a> {
+    pub log_file: &'a str,
+    #[serde(flatten)]
+    pub session: &'a Session,
+}
+
+// One event of the JSON Lines event log (see logging.rs): the common fields, the fields of its event kind, and its position in a playlist
+#[derive(Serialize)]
+pub struct Event {
+    pub timestamp_ns: u128,
+    pub timestamp_monotonic_ns: u128, // nanoseconds since the server started, see clock.rs
+    #[serde(flatten)]
+    pub kind: EventKind,
+    pub status: String, // "success" or "error"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_client: Option<String>, // the time query parameter of the request, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_client_corrected_ns: Option<u128>, // timestamp_client + the clock offset registered by the client of the request (see /clock/offset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<u128>, // /play/random and playlist playbacks: the receipt time of the request, the same for all of its events
+    #[serde(flatten)]
+    pub position: Option<EventPosition>,
+    // played items (audio files, tones and pauses): end time, measured and nominal durations, and sample rate of the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_end_ns: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measured_duration_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nominal_duration_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_sample_rate_hz: Option<u32>,
+    #[serde(flatten)]
+    pub request: Option<RequestInfo>,
+}
+
+// The HTTP request behind a log row (the request that started the playback for the rows of /play/random and playlists)
+#[derive(Serialize, Clone, Default)]
+pub struct RequestInfo {
+    pub client_ip: String, // empty for the schedules and the protocols
+    pub request_path: String, // path and query, eg. /play/1.wav?time=1706709600000000000
+    pub request_id: String, // the X-Request-ID header of the request, or a random 16-digit hex ID
+    #[serde(skip)]
+    pub received_ns: Option<u128>, // receipt time of the HTTP request, for the timing diagnostics (None for the schedules and the protocols)
+}
+
+// Receipt time of an HTTP request (UNIX nanoseconds), stamped in the request extensions by the middleware of main.rs
+pub struct ReceivedAt(pub u128);
+
+// Position of an event in a playlist playback (the block, block_name, trial and source_playlist columns of the CSV log)
+#[derive(Serialize, Clone, Default)]
+pub struct EventPosition {
+    pub item: usize, // item number in the expanded playlist, starting at 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trial: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_playlist: Option<String>,
+}
+
+// Event kinds of the JSON Lines event log, in the "event" field. Documented in /info
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    Request { route: String, options: serde_json::Value },
+    AudioFile { file: String, gain_db: f32 },
+    Tone { frequency_hz: f32, duration_ms: u32, amplitude_db: f32, sample_rate_hz: u32 },
+    Pause { duration_ms: u32 },
+    ScheduledStart { stimulus: Option<String>, requested_ns: u128, actual_ns: u128, error_ns: i128 },
+    Branch { option: String },
+    Jump { line: String, label: String, variable: Option<String>, value: Option<String>, target_item: Option<usize> },
+    Continued { continued_in: String, from_item: usize, item_count: usize },
+    End { route: String, ended_by: String, played: usize, item_count: Option<usize>, elapsed_ms: u128 },
+    Response { label: String, stimulus: Option<String>, latency_ms: Option<f64>, outcome: Option<String> },
+    Variable { name: String, value: String },
+    Miss { stimulus: String, window_start_ms: u32, window_end_ms: u32 },
+    Session { action: String, session_id: String, participant: String, experiment: String, experimenter: String, log_file_count: usize },
+    Error { stimulus: String, message: String },
+    ClockSync { offset_ns: i128 },
+    ClockOffset { offset_ns: i64, rtt_ns: Option<u64> },
+    TimingSelfTest { click_count: u32, interval_ms: u32, mean_ms: Option<f64>, sd_ms: Option<f64>, p95_ms: Option<f64>, max_ms: Option<f64> },
+}